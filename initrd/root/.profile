@@ -0,0 +1 @@
+# Read by the shell of root when it starts
@@ -0,0 +1,381 @@
+//!
+//! ACPI static tables
+//!
+//! `init` finds the RSDP in the BIOS areas, walks the RSDT (or the XSDT when there is
+//! one) and parses the tables other subsystems care about: the MADT for the APICs, the
+//! FADT for power management, the HPET and the MCFG for PCI ECAM. Every table is
+//! checked against its checksum; broken ones are skipped. The tables are read through
+//! the physical memory mapping of the bootloader.
+//!
+pub mod aml;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::PhysAddr;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+pub use mcfg::Mcfg;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+
+/// Segment of the EBDA is stored at this address of the BIOS data area
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+pub const HEADER_LENGTH: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// Physical memory isn't mapped yet
+    NoMemoryMap,
+    NoRsdp,
+    /// The table with that signature doesn't add up to 0
+    BadChecksum([u8; 4]),
+    BadTable([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoMemoryMap => write!(f, "physical memory is not mapped"),
+            AcpiError::NoRsdp => write!(f, "no RSDP found"),
+            AcpiError::BadChecksum(signature) => {
+                write!(f, "bad checksum in {}", signature_str(signature))
+            }
+            AcpiError::BadTable(signature) => write!(f, "malformed {}", signature_str(signature)),
+        }
+    }
+}
+
+pub fn signature_str(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+///
+/// Whether the bytes add up to 0, like every ACPI structure has to
+///
+pub fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+///
+/// `length` bytes of physical memory at `address`
+///
+fn physical_bytes(address: PhysAddr, length: usize) -> Result<&'static [u8], AcpiError> {
+    let offset = crate::memory::physical_memory_offset().ok_or(AcpiError::NoMemoryMap)?;
+    let virtual_address = offset + address.as_u64();
+    Ok(unsafe { core::slice::from_raw_parts(virtual_address.as_ptr(), length) })
+}
+
+///
+/// Register location used by the newer tables, in memory or in I/O space
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+pub const GENERIC_ADDRESS_LENGTH: usize = 12;
+
+impl GenericAddress {
+    pub(crate) fn read(bytes: &[u8], offset: usize) -> Option<Self> {
+        let space = match bytes.read_u8(offset)? {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        Some(Self {
+            space,
+            bit_width: bytes.read_u8(offset + 1)?,
+            bit_offset: bytes.read_u8(offset + 2)?,
+            access_size: bytes.read_u8(offset + 3)?,
+            address: bytes.read_u64(offset + 4)?,
+        })
+    }
+}
+
+///
+/// A system description table, header included
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub address: PhysAddr,
+    pub signature: [u8; 4],
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    bytes: &'static [u8],
+}
+
+impl Table {
+    ///
+    /// Maps the table at `address` and checks its checksum
+    ///
+    pub fn read(address: PhysAddr) -> Result<Self, AcpiError> {
+        let header = physical_bytes(address, HEADER_LENGTH)?;
+        let mut signature = [0; 4];
+        signature.copy_from_slice(&header[0..4]);
+        let length = header.read_u32(4).unwrap_or(0) as usize;
+        if length < HEADER_LENGTH {
+            return Err(AcpiError::BadTable(signature));
+        }
+        let bytes = physical_bytes(address, length)?;
+        if !checksum_is_valid(bytes) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[10..16]);
+        Ok(Self {
+            address,
+            signature,
+            revision: bytes[8],
+            oem_id,
+            bytes,
+        })
+    }
+
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    ///
+    /// What comes after the header, AML code for the DSDT and the SSDTs
+    ///
+    pub fn body(&self) -> &'static [u8] {
+        &self.bytes[HEADER_LENGTH..]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub address: PhysAddr,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt: PhysAddr,
+    /// Only from ACPI 2.0 on
+    pub xsdt: Option<PhysAddr>,
+}
+
+impl Rsdp {
+    fn read(address: PhysAddr) -> Option<Self> {
+        let bytes = physical_bytes(address, RSDP_V1_LENGTH).ok()?;
+        if &bytes[0..8] != RSDP_SIGNATURE || !checksum_is_valid(bytes) {
+            return None;
+        }
+        let revision = bytes[15];
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        let xsdt = if revision >= 2 {
+            let bytes = physical_bytes(address, RSDP_V2_LENGTH).ok()?;
+            if !checksum_is_valid(bytes) {
+                return None;
+            }
+            bytes
+                .read_u64(24)
+                .filter(|&xsdt| xsdt != 0)
+                .map(PhysAddr::new)
+        } else {
+            None
+        };
+        Some(Self {
+            address,
+            revision,
+            oem_id,
+            rsdt: PhysAddr::new(bytes.read_u32(16)? as u64),
+            xsdt,
+        })
+    }
+
+    ///
+    /// Looks in the first KiB of the EBDA and then in the BIOS area below 1 MiB,
+    /// the RSDP is always 16 byte aligned
+    ///
+    pub fn find() -> Option<Self> {
+        let ebda = physical_bytes(PhysAddr::new(EBDA_POINTER), 2)
+            .ok()?
+            .read_u16(0)
+            .map(|segment| (segment as u64) << 4)
+            .filter(|&ebda| ebda != 0);
+        let ebda_range = ebda.map(|start| start..start + 1024);
+        ebda_range
+            .into_iter()
+            .chain(core::iter::once(BIOS_AREA_START..BIOS_AREA_END))
+            .flat_map(|range| range.step_by(16))
+            .find_map(|address| Self::read(PhysAddr::new(address)))
+    }
+}
+
+///
+/// Everything `init` found
+///
+#[derive(Debug)]
+pub struct Acpi {
+    pub rsdp: Rsdp,
+    /// Every table listed by the RSDT or XSDT that had a good checksum
+    pub tables: Vec<Table>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<&Table> {
+        self.tables
+            .iter()
+            .find(|table| &table.signature == signature)
+    }
+
+    ///
+    /// The DSDT isn't listed in the RSDT, the FADT points to it
+    ///
+    pub fn dsdt(&self) -> Option<Table> {
+        let address = self.fadt.as_ref()?.dsdt?;
+        Table::read(address).ok()
+    }
+
+    ///
+    /// SLP_TYP values of sleep state `state`, 5 being soft off. They're in the DSDT or,
+    /// on some machines, in an SSDT
+    ///
+    pub fn sleep_type(&self, state: u8) -> Option<aml::SleepType> {
+        let name = [b'_', b'S', b'0' + state, b'_'];
+        let dsdt = self.dsdt();
+        dsdt.iter()
+            .chain(
+                self.tables
+                    .iter()
+                    .filter(|table| &table.signature == b"SSDT"),
+            )
+            .find_map(|table| aml::find_sleep_type(table.body(), &name))
+    }
+}
+
+static ACPI: OnceCell<Acpi> = OnceCell::uninit();
+
+///
+/// Addresses of the tables listed by the RSDT (32 bit entries) or XSDT (64 bit ones)
+///
+fn table_addresses(rsdp: &Rsdp) -> Result<Vec<PhysAddr>, AcpiError> {
+    let (root, entry_size) = match rsdp.xsdt {
+        Some(xsdt) => (Table::read(xsdt)?, 8),
+        None => (Table::read(rsdp.rsdt)?, 4),
+    };
+    let entries = &root.bytes()[HEADER_LENGTH..];
+    Ok(entries
+        .chunks_exact(entry_size)
+        .filter_map(|entry| match entry_size {
+            8 => entry.read_u64(0),
+            _ => entry.read_u32(0).map(|address| address as u64),
+        })
+        .filter(|&address| address != 0)
+        .map(PhysAddr::new)
+        .collect())
+}
+
+fn parse() -> Result<Acpi, AcpiError> {
+    let rsdp = Rsdp::find().ok_or(AcpiError::NoRsdp)?;
+    let mut tables = Vec::new();
+    for address in table_addresses(&rsdp)? {
+        match Table::read(address) {
+            Ok(table) => tables.push(table),
+            Err(error) => crate::warn!("acpi: {}", error),
+        }
+    }
+    let find = |signature: &[u8; 4]| tables.iter().find(|table| &table.signature == signature);
+    let madt = find(madt::SIGNATURE).and_then(Madt::parse);
+    let fadt = find(fadt::SIGNATURE).and_then(Fadt::parse);
+    let hpet = find(hpet::SIGNATURE).and_then(Hpet::parse);
+    let mcfg = find(mcfg::SIGNATURE).and_then(Mcfg::parse);
+    Ok(Acpi {
+        rsdp,
+        tables,
+        madt,
+        fadt,
+        hpet,
+        mcfg,
+    })
+}
+
+///
+/// Finds and parses the tables. Needs the physical memory mapping from `memory::init`
+/// and the heap
+///
+pub fn init() -> Result<&'static Acpi, AcpiError> {
+    if let Some(acpi) = get() {
+        return Ok(acpi);
+    }
+    let acpi = parse()?;
+    let oem = core::str::from_utf8(&acpi.rsdp.oem_id).unwrap_or("?");
+    crate::info!(
+        "acpi: revision {} from {}, {} tables",
+        acpi.rsdp.revision,
+        oem.trim_end(),
+        acpi.tables.len()
+    );
+    let _ = ACPI.try_init_once(|| acpi);
+    crate::shell::register(crate::shell::Command {
+        name: "acpi",
+        help: "lists the ACPI tables",
+        handler: list_tables,
+    });
+    get().ok_or(AcpiError::NoRsdp)
+}
+
+///
+/// The tables, if `init` found them
+///
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.try_get().ok()
+}
+
+fn list_tables(out: &mut dyn fmt::Write, _arguments: &[&str]) -> crate::shell::CommandResult {
+    let acpi = get().ok_or_else(|| crate::shell::CommandError::Failed("no ACPI".into()))?;
+    for table in &acpi.tables {
+        writeln!(
+            out,
+            "{} rev {} at {:#x}, {} bytes",
+            signature_str(&table.signature),
+            table.revision,
+            table.address.as_u64(),
+            table.len()
+        )?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_checksum() {
+    assert!(checksum_is_valid(&[0x10, 0xf0, 0x00]));
+    assert!(!checksum_is_valid(&[0x10, 0xf1]));
+}
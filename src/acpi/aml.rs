@@ -0,0 +1,99 @@
+//!
+//! Just enough AML to read the sleep state packages (`\_S5` and friends) of the DSDT
+//!
+//! They're declared as `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })`, which every
+//! firmware compiles the same way, so there's no need for an interpreter.
+//!
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const ROOT_PREFIX: u8 = b'\\';
+
+///
+/// Values to write to the SLP_TYP fields of the PM1a and PM1b control registers
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+///
+/// Reads an integer that fits in a byte, returns it and how many bytes it took
+///
+fn read_byte_integer(aml: &[u8]) -> Option<(u8, usize)> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        BYTE_PREFIX => Some((*aml.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+///
+/// Finds `Name (<name>, Package () {...})` in `aml` and returns its first two elements.
+/// `name` is the 4 character AML name, like `_S5_`
+///
+pub fn find_sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, window)| window == name)
+        .find_map(|(position, _)| {
+            let named = match position {
+                0 => false,
+                _ if aml[position - 1] == NAME_OP => true,
+                1 => false,
+                _ => aml[position - 1] == ROOT_PREFIX && aml[position - 2] == NAME_OP,
+            };
+            if !named {
+                return None;
+            }
+            let package = aml.get(position + 4..)?;
+            if *package.first()? != PACKAGE_OP {
+                return None;
+            }
+            // The top 2 bits of the first byte of PkgLength say how many bytes follow
+            let length_bytes = 1 + (*package.get(1)? >> 6) as usize;
+            // Then comes NumElements
+            let elements = package.get(1 + length_bytes + 1..)?;
+            let (a, used) = read_byte_integer(elements)?;
+            let (b, _) = read_byte_integer(elements.get(used..)?)?;
+            Some(SleepType { a, b })
+        })
+}
+
+#[test_case]
+fn test_find_sleep_type() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) as QEMU has it
+    let aml = [
+        0x10,
+        0x08,
+        b'_',
+        b'S',
+        b'5',
+        b'_',
+        0xff,
+        NAME_OP,
+        ROOT_PREFIX,
+        b'_',
+        b'S',
+        b'5',
+        b'_',
+        PACKAGE_OP,
+        0x0a,
+        0x04,
+        BYTE_PREFIX,
+        0x05,
+        ZERO_OP,
+        ZERO_OP,
+        ZERO_OP,
+    ];
+    assert_eq!(
+        find_sleep_type(&aml, b"_S5_"),
+        Some(SleepType { a: 5, b: 0 })
+    );
+    assert_eq!(find_sleep_type(&aml, b"_S4_"), None);
+}
@@ -0,0 +1,91 @@
+//!
+//! Fixed ACPI Description Table: power management registers, the reset register and
+//! where the DSDT is
+//!
+use super::{GenericAddress, Table};
+use crate::bytes::ReadBytes;
+use x86_64::PhysAddr;
+
+pub const SIGNATURE: &[u8; 4] = b"FACP";
+
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVENT_BLOCK: usize = 56;
+const PM1B_EVENT_BLOCK: usize = 60;
+const PM1A_CONTROL_BLOCK: usize = 64;
+const PM1B_CONTROL_BLOCK: usize = 68;
+const PM_TIMER_BLOCK: usize = 76;
+const PM1_EVENT_LENGTH: usize = 88;
+const CENTURY: usize = 108;
+const BOOT_ARCHITECTURE: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+
+const BOOT_ARCHITECTURE_8042: u16 = 0x2;
+const FLAG_RESET_REGISTER: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: Option<PhysAddr>,
+    /// ISA line of the ACPI system control interrupt
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to in order to switch to ACPI mode, 0 if it's always on
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u16,
+    pub pm1b_event_block: u16,
+    pub pm1_event_length: u8,
+    pub pm1a_control_block: u16,
+    pub pm1b_control_block: u16,
+    pub pm_timer_block: u16,
+    /// CMOS register with the century, 0 if there's none
+    pub century_register: u8,
+    /// There's an 8042 keyboard controller. Only meaningful from revision 2 on
+    pub has_8042: bool,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub(crate) fn parse(table: &Table) -> Option<Self> {
+        let bytes = table.bytes();
+        let port = |offset| bytes.read_u32(offset).unwrap_or(0) as u16;
+        let flags = bytes.read_u32(FLAGS).unwrap_or(0);
+        let dsdt = bytes
+            .read_u64(X_DSDT)
+            .filter(|&address| address != 0)
+            .or_else(|| bytes.read_u32(DSDT).map(|address| address as u64))
+            .filter(|&address| address != 0)
+            .map(PhysAddr::new);
+        let boot_architecture = bytes.read_u16(BOOT_ARCHITECTURE).unwrap_or(0);
+        Some(Self {
+            revision: table.revision,
+            dsdt,
+            sci_interrupt: bytes.read_u16(SCI_INTERRUPT)?,
+            smi_command: port(SMI_COMMAND),
+            acpi_enable: bytes.read_u8(ACPI_ENABLE)?,
+            acpi_disable: bytes.read_u8(ACPI_DISABLE)?,
+            pm1a_event_block: port(PM1A_EVENT_BLOCK),
+            pm1b_event_block: port(PM1B_EVENT_BLOCK),
+            pm1_event_length: bytes.read_u8(PM1_EVENT_LENGTH).unwrap_or(0),
+            pm1a_control_block: port(PM1A_CONTROL_BLOCK),
+            pm1b_control_block: port(PM1B_CONTROL_BLOCK),
+            pm_timer_block: port(PM_TIMER_BLOCK),
+            century_register: bytes.read_u8(CENTURY).unwrap_or(0),
+            has_8042: table.revision < 2 || boot_architecture & BOOT_ARCHITECTURE_8042 != 0,
+            reset_register: if flags & FLAG_RESET_REGISTER != 0 {
+                GenericAddress::read(bytes, RESET_REGISTER)
+            } else {
+                None
+            },
+            reset_value: bytes.read_u8(RESET_VALUE).unwrap_or(0),
+        })
+    }
+}
@@ -0,0 +1,39 @@
+//!
+//! High Precision Event Timer description
+//!
+use super::{GenericAddress, Table, HEADER_LENGTH};
+use crate::bytes::ReadBytes;
+
+pub const SIGNATURE: &[u8; 4] = b"HPET";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// Where its registers are, always in memory
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_64_bit: bool,
+    /// It can take over the interrupts of the PIT and the RTC
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    /// Smallest period, in counter ticks, that can be programmed in periodic mode
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub(crate) fn parse(table: &Table) -> Option<Self> {
+        let bytes = table.bytes();
+        let id = bytes.read_u32(HEADER_LENGTH)?;
+        Some(Self {
+            address: GenericAddress::read(bytes, HEADER_LENGTH + 4)?,
+            hpet_number: bytes.read_u8(HEADER_LENGTH + 16)?,
+            minimum_tick: bytes.read_u16(HEADER_LENGTH + 17)?,
+            hardware_revision: id as u8,
+            comparators: (id >> 8 & 0x1f) as u8 + 1,
+            counter_64_bit: id & 1 << 13 != 0,
+            legacy_replacement: id & 1 << 15 != 0,
+            vendor_id: (id >> 16) as u16,
+        })
+    }
+}
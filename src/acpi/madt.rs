@@ -0,0 +1,128 @@
+//!
+//! Multiple APIC Description Table: the local APICs of the CPUs, the I/O APICs and how
+//! the ISA interrupts are wired to them
+//!
+use super::{Table, HEADER_LENGTH};
+use crate::apic::io::Trigger;
+use crate::apic::{InterruptOverride, IoApicInfo, Topology};
+use crate::bytes::ReadBytes;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+pub const SIGNATURE: &[u8; 4] = b"APIC";
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+const FLAG_PCAT_COMPAT: u32 = 0x1;
+const PROCESSOR_ENABLED: u32 = 0x1;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Disabled ones can't be started, unless they are online capable
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+///
+/// Local APIC input wired to the NMI, 0xff as processor means all of them
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,
+    pub lint: u8,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// There are also 8259 PICs, which have to be masked
+    pub pcat_compat: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+///
+/// Polarity and trigger mode flags, 0 means what the bus uses: edge and active high for ISA
+///
+fn trigger(flags: u16) -> Trigger {
+    Trigger {
+        active_low: flags & 0x3 == 0x3,
+        level: flags >> 2 & 0x3 == 0x3,
+    }
+}
+
+impl Madt {
+    pub(crate) fn parse(table: &Table) -> Option<Self> {
+        let bytes = table.bytes();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(bytes.read_u32(HEADER_LENGTH)? as u64),
+            pcat_compat: bytes.read_u32(HEADER_LENGTH + 4)? & FLAG_PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+        let mut offset = HEADER_LENGTH + 8;
+        while offset + 2 <= bytes.len() {
+            let kind = bytes[offset];
+            let length = bytes[offset + 1] as usize;
+            if length < 2 || offset + length > bytes.len() {
+                break;
+            }
+            let entry = &bytes[offset..offset + length];
+            match kind {
+                ENTRY_LOCAL_APIC => {
+                    let flags = entry.read_u32(4)?;
+                    madt.processors.push(Processor {
+                        processor_id: entry[2],
+                        apic_id: entry[3],
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC => madt.io_apics.push(IoApicInfo {
+                    id: entry[2],
+                    address: PhysAddr::new(entry.read_u32(4)? as u64),
+                    gsi_base: entry.read_u32(8)?,
+                }),
+                ENTRY_OVERRIDE => madt.overrides.push(InterruptOverride {
+                    source: entry[3],
+                    gsi: entry.read_u32(4)?,
+                    trigger: trigger(entry.read_u16(8)?),
+                }),
+                ENTRY_LOCAL_APIC_NMI => madt.nmis.push(LocalApicNmi {
+                    processor_id: entry[2],
+                    lint: entry.read_u8(5)?,
+                    trigger: trigger(entry.read_u16(3)?),
+                }),
+                ENTRY_LOCAL_APIC_ADDRESS => {
+                    madt.local_apic_address = PhysAddr::new(entry.read_u64(4)?);
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(madt)
+    }
+
+    ///
+    /// What `apic::init` needs to know
+    ///
+    pub fn topology(&self) -> Topology {
+        Topology {
+            local_apic: self.local_apic_address,
+            io_apics: self.io_apics.clone(),
+            overrides: self.overrides.clone(),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//!
+//! PCI Express memory mapped configuration space description
+//!
+use super::{Table, HEADER_LENGTH};
+use crate::bytes::ReadBytes;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+pub const SIGNATURE: &[u8; 4] = b"MCFG";
+
+const ENTRY_LENGTH: usize = 16;
+
+///
+/// ECAM window of buses `start_bus..=end_bus` of a segment
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    pub(crate) fn parse(table: &Table) -> Option<Self> {
+        // 8 reserved bytes after the header
+        let entries = table.bytes().get(HEADER_LENGTH + 8..)?;
+        let entries = entries
+            .chunks_exact(ENTRY_LENGTH)
+            .filter_map(|entry| {
+                Some(McfgEntry {
+                    base: PhysAddr::new(entry.read_u64(0)?),
+                    segment: entry.read_u16(8)?,
+                    start_bus: entry.read_u8(10)?,
+                    end_bus: entry.read_u8(11)?,
+                })
+            })
+            .collect();
+        Some(Self { entries })
+    }
+}
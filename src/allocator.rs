@@ -14,7 +14,13 @@ use linked_list::LinkedListAllocator;
 use linked_list_allocator::LockedHeap;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+///
+/// Each of the `vt::TERMINAL_COUNT` virtual terminals keeps an off-screen text buffer
+/// and `DEFAULT_HISTORY_LINES` of scrollback, about 24 KiB. The biggest user is the
+/// back buffer of the desktop, a whole screen of `u32` pixels: 3 MiB at the default
+/// 1024x768, with the windows next to it
+///
+pub const HEAP_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
 
 use x86_64::{
     structures::paging::{
@@ -49,6 +55,22 @@ pub fn init_heap(
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+}
+
+///
+/// How much of the kernel heap is in use
+///
+pub fn stats() -> HeapStats {
+    HeapStats {
+        size: HEAP_SIZE,
+        used: ALLOCATOR.lock().used(),
+    }
+}
+
 ///
 /// A dummy allocator that only returns null pointers
 ///
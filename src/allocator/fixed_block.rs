@@ -7,6 +7,8 @@ struct Node {
 pub struct FixedBlockAllocator {
     list_heads: [Option<&'static mut Node>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// Bytes handed out, counting whole blocks
+    used: usize,
 }
 
 impl FixedBlockAllocator {
@@ -14,6 +16,7 @@ impl FixedBlockAllocator {
         Self {
             list_heads: [None; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            used: 0,
         }
     }
 
@@ -25,6 +28,10 @@ impl FixedBlockAllocator {
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
     }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
 }
 
 use alloc::alloc::Layout;
@@ -61,7 +68,8 @@ use alloc::alloc::GlobalAlloc;
 unsafe impl GlobalAlloc for Locked<FixedBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator: spin::MutexGuard<FixedBlockAllocator> = self.lock();
-        match list_index(&layout) {
+        let size = list_index(&layout).map_or(layout.size(), |index| BLOCK_SIZES[index]);
+        let pointer = match list_index(&layout) {
             Some(index) => match allocator.list_heads[index].take() {
                 Some(node) => {
                     // go next
@@ -80,7 +88,11 @@ unsafe impl GlobalAlloc for Locked<FixedBlockAllocator> {
                 }
             },
             None => allocator.fallback_allocator(layout),
+        };
+        if !pointer.is_null() {
+            allocator.used += size;
         }
+        pointer
     }
 
     unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
@@ -88,6 +100,7 @@ unsafe impl GlobalAlloc for Locked<FixedBlockAllocator> {
         let mut allocator: spin::MutexGuard<FixedBlockAllocator> = self.lock();
         match list_index(&layout) {
             Some(index) => {
+                allocator.used -= BLOCK_SIZES[index];
                 let old_head = allocator.list_heads[index].take();
                 let mut node = Node { next: old_head };
                 assert!(mem::size_of::<Node>() <= BLOCK_SIZES[index]);
@@ -97,6 +110,7 @@ unsafe impl GlobalAlloc for Locked<FixedBlockAllocator> {
                 allocator.list_heads[index] = Some(&mut *ptr);
             }
             None => {
+                allocator.used -= layout.size();
                 allocator.fallback_dealloc(layout, pointer);
             }
         }
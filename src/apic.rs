@@ -0,0 +1,285 @@
+//!
+//! Local APIC and I/O APIC
+//!
+//! `init` takes the interrupts away from the 8259 PICs: they get masked, every I/O APIC
+//! pin is routed to the local APIC of this CPU and the local APIC timer replaces the PIT
+//! as the tick. ISA lines keep the vectors the PICs gave them, so the handlers in `irq`
+//! don't notice the switch, and lines from 16 on (the global system interrupts of PCI
+//! devices) get vectors from `GSI_VECTOR_BASE`.
+//!
+//! Where the APICs are and how the ISA lines are wired comes in a `Topology`, normally
+//! read from the ACPI MADT.
+//!
+pub mod io;
+pub mod local;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use io::{IoApic, Trigger};
+use local::LocalApic;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+///
+/// Lines from 16 on are raised as `GSI_VECTOR_BASE + line - 16`
+///
+pub(crate) const GSI_VECTOR_BASE: u8 = 64;
+
+///
+/// Lines that can be routed: the 16 ISA ones and the global system interrupts after them
+///
+pub const MAX_LINES: u8 = 64;
+
+const DEFAULT_LOCAL_APIC_ADDRESS: u64 = 0xfee0_0000;
+const DEFAULT_IO_APIC_ADDRESS: u64 = 0xfec0_0000;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+///
+/// An ISA line wired to another pin, or with another polarity, than the usual one
+///
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub local_apic: PhysAddr,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Topology {
+    ///
+    /// What a PC without a MADT looks like: one I/O APIC at the default address and the
+    /// PIT on pin 2
+    ///
+    pub fn legacy() -> Self {
+        // The MSR isn't there without an APIC, reading it faults. `init` refuses those anyway
+        let base = if is_supported() {
+            unsafe { x86_64::registers::model_specific::Msr::new(IA32_APIC_BASE).read() }
+        } else {
+            DEFAULT_LOCAL_APIC_ADDRESS
+        };
+        Topology {
+            local_apic: PhysAddr::new(base & 0x000f_ffff_ffff_f000),
+            io_apics: vec![IoApicInfo {
+                id: 0,
+                address: PhysAddr::new(DEFAULT_IO_APIC_ADDRESS),
+                gsi_base: 0,
+            }],
+            overrides: vec![InterruptOverride {
+                source: 0,
+                gsi: 2,
+                trigger: Trigger::EDGE_HIGH,
+            }],
+        }
+    }
+
+    ///
+    /// Pin and trigger of `line`, `None` if its pin is taken by an overridden ISA line
+    ///
+    pub fn gsi_of(&self, line: u8) -> Option<(u32, Trigger)> {
+        if line < 16 {
+            if let Some(entry) = self.overrides.iter().find(|o| o.source == line) {
+                return Some((entry.gsi, entry.trigger));
+            }
+        }
+        let gsi = line as u32;
+        if self.overrides.iter().any(|o| o.gsi == gsi) {
+            return None;
+        }
+        let trigger = if line < 16 {
+            Trigger::EDGE_HIGH
+        } else {
+            Trigger::LEVEL_LOW
+        };
+        Some((gsi, trigger))
+    }
+}
+
+///
+/// Virtual address of the local APIC registers, 0 while the PICs are in charge
+///
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+struct Routing {
+    topology: Topology,
+    io_apics: Vec<IoApic>,
+}
+
+impl Routing {
+    fn io_apic(&self, line: u8) -> Option<(&IoApic, u32)> {
+        let (gsi, _) = self.topology.gsi_of(line)?;
+        let io_apic = self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))?;
+        Some((io_apic, gsi))
+    }
+}
+
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+pub fn is_supported() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+fn local_apic() -> Option<LocalApic> {
+    match LOCAL_APIC.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(unsafe { LocalApic::new(VirtAddr::new(base)) }),
+    }
+}
+
+pub(crate) fn end_of_interrupt() {
+    if let Some(local_apic) = local_apic() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+///
+/// Masks or unmasks the I/O APIC pin of `line`
+///
+pub(crate) fn set_masked(line: u8, masked: bool) {
+    if let Some(routing) = ROUTING.lock().as_ref() {
+        if let Some((io_apic, gsi)) = routing.io_apic(line) {
+            io_apic.set_masked(gsi, masked);
+        }
+    }
+}
+
+///
+/// Counts how fast the local APIC timer goes during one PIT tick
+///
+fn calibrate(local_apic: &LocalApic) -> u32 {
+    let start = crate::time::ticks();
+    while crate::time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    local_apic.start_counting();
+    let start = crate::time::ticks();
+    while crate::time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    local_apic.elapsed()
+}
+
+fn mask_pics() {
+    use x86_64::instructions::port::Port;
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+fn map(
+    address: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, &'static str> {
+    crate::memory::map_mmio(address, size, mapper, frame_allocator)
+        .map_err(|_| "couldn't map the APIC registers")
+}
+
+///
+/// Switches from the PICs to the APICs. Has to run with interrupts enabled and the PIT
+/// ticking, the local APIC timer is measured against it to keep the same tick length
+///
+pub fn init(
+    topology: &Topology,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    use crate::interrupts::line_vector;
+    use crate::irq::{handler_count, Irq};
+    use x86_64::instructions::interrupts;
+
+    if !is_supported() {
+        return Err("the CPU has no APIC");
+    }
+    if !interrupts::are_enabled() {
+        return Err("interrupts are needed to calibrate the APIC timer");
+    }
+    unsafe {
+        let mut msr = x86_64::registers::model_specific::Msr::new(IA32_APIC_BASE);
+        let base = msr.read();
+        msr.write(base | APIC_GLOBAL_ENABLE);
+    }
+    let local_base = map(
+        topology.local_apic,
+        local::MMIO_SIZE,
+        mapper,
+        frame_allocator,
+    )?;
+    let local_apic = unsafe { LocalApic::new(local_base) };
+    local_apic.enable(SPURIOUS_VECTOR);
+    let timer_count = calibrate(&local_apic);
+
+    let mut io_apics = Vec::new();
+    for info in &topology.io_apics {
+        let base = map(info.address, io::MMIO_SIZE, mapper, frame_allocator)?;
+        let io_apic = unsafe { IoApic::new(base, info.gsi_base) };
+        io_apic.mask_all();
+        io_apics.push(io_apic);
+    }
+    let routing = Routing {
+        topology: topology.clone(),
+        io_apics,
+    };
+    let destination = local_apic.id();
+    // Line 2 is the cascade of the PICs, nothing is wired to it
+    for line in (0..MAX_LINES).filter(|&line| line != 2) {
+        let vector = match line {
+            0..=15 => line_vector(line),
+            _ => Some(GSI_VECTOR_BASE + line - 16),
+        };
+        if let (Some((gsi, trigger)), Some((io_apic, _)), Some(vector)) =
+            (routing.topology.gsi_of(line), routing.io_apic(line), vector)
+        {
+            io_apic.route(gsi, vector, trigger, destination);
+        }
+    }
+
+    let pins: u32 = routing.io_apics.iter().map(|io_apic| io_apic.pins).sum();
+    interrupts::without_interrupts(|| {
+        mask_pics();
+        *ROUTING.lock() = Some(routing);
+        LOCAL_APIC.store(local_base.as_u64(), Ordering::SeqCst);
+        // Line 0 stays masked, the local APIC timer takes its vector
+        for line in 1..16 {
+            if handler_count(Irq::Line(line)) > 0 {
+                set_masked(line, false);
+            }
+        }
+        if let Some(vector) = line_vector(0) {
+            local_apic.start_periodic(vector, timer_count);
+        }
+    });
+    crate::info!(
+        "apic: local APIC {}, {} I/O APIC pins, timer {} per tick",
+        destination,
+        pins,
+        timer_count
+    );
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//!
+//! I/O APIC: the redirection table that turns global system interrupts into vectors
+//!
+use x86_64::VirtAddr;
+
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_WINDOW: usize = 0x10;
+
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+pub const MMIO_SIZE: u64 = 0x20;
+
+///
+/// How an interrupt is signalled on its pin
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trigger {
+    pub active_low: bool,
+    pub level: bool,
+}
+
+impl Trigger {
+    /// What ISA devices use
+    pub const EDGE_HIGH: Trigger = Trigger {
+        active_low: false,
+        level: false,
+    };
+    /// What PCI devices use
+    pub const LEVEL_LOW: Trigger = Trigger {
+        active_low: true,
+        level: true,
+    };
+}
+
+#[derive(Debug)]
+pub struct IoApic {
+    base: VirtAddr,
+    /// First global system interrupt handled by this one
+    pub gsi_base: u32,
+    /// How many pins it has
+    pub pins: u32,
+}
+
+impl IoApic {
+    ///
+    /// # Safety
+    /// `base` has to be the mapping of the I/O APIC registers
+    ///
+    pub unsafe fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base,
+            gsi_base,
+            pins: 0,
+        };
+        io_apic.pins = (io_apic.read(VERSION) >> 16 & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            (self.base.as_mut_ptr::<u8>().add(REGISTER_SELECT) as *mut u32)
+                .write_volatile(register);
+            (self.base.as_ptr::<u8>().add(REGISTER_WINDOW) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            (self.base.as_mut_ptr::<u8>().add(REGISTER_SELECT) as *mut u32)
+                .write_volatile(register);
+            (self.base.as_mut_ptr::<u8>().add(REGISTER_WINDOW) as *mut u32).write_volatile(value);
+        }
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.pins).contains(&gsi)
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let register = REDIRECTION_TABLE + pin * 2;
+        (self.read(register + 1) as u64) << 32 | self.read(register) as u64
+    }
+
+    fn write_entry(&self, pin: u32, entry: u64) {
+        let register = REDIRECTION_TABLE + pin * 2;
+        // Write the low half, which has the mask bit, last
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    ///
+    /// Sends `gsi` as `vector` to the local APIC `destination`, masked
+    ///
+    pub fn route(&self, gsi: u32, vector: u8, trigger: Trigger, destination: u8) {
+        let mut entry = ENTRY_MASKED | vector as u64 | (destination as u64) << 56;
+        if trigger.active_low {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if trigger.level {
+            entry |= ENTRY_LEVEL_TRIGGERED;
+        }
+        self.write_entry(gsi - self.gsi_base, entry);
+    }
+
+    pub fn set_masked(&self, gsi: u32, masked: bool) {
+        let pin = gsi - self.gsi_base;
+        let entry = self.read_entry(pin);
+        let entry = if masked {
+            entry | ENTRY_MASKED
+        } else {
+            entry & !ENTRY_MASKED
+        };
+        self.write_entry(pin, entry);
+    }
+
+    pub fn mask_all(&self) {
+        for pin in 0..self.pins {
+            self.write_entry(pin, ENTRY_MASKED);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//!
+//! Local APIC of the CPU: end of interrupt, spurious vector and timer
+//!
+use x86_64::VirtAddr;
+
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS_INTERRUPT: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 0x100;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16
+const DIVIDE_BY_16: u32 = 0x3;
+
+pub const MMIO_SIZE: u64 = 0x1000;
+
+///
+/// Registers of the local APIC, mapped in memory
+///
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    ///
+    /// # Safety
+    /// `base` has to be the mapping of the local APIC registers
+    ///
+    pub unsafe fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        unsafe { (self.base.as_ptr::<u8>().add(register) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { (self.base.as_mut_ptr::<u8>().add(register) as *mut u32).write_volatile(value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    ///
+    /// Accepts interrupts of every priority and sends the spurious ones to `spurious_vector`
+    ///
+    pub fn enable(&self, spurious_vector: u8) {
+        self.write(TASK_PRIORITY, 0);
+        self.write(SPURIOUS_INTERRUPT, SOFTWARE_ENABLE | spurious_vector as u32);
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    ///
+    /// Starts counting down from the maximum without raising anything, used to measure
+    /// the speed of the timer
+    ///
+    pub fn start_counting(&self) {
+        self.write(TIMER_DIVIDE, DIVIDE_BY_16);
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(TIMER_INITIAL_COUNT, u32::MAX);
+    }
+
+    ///
+    /// How much the count went down since `start_counting`
+    ///
+    pub fn elapsed(&self) -> u32 {
+        u32::MAX - self.read(TIMER_CURRENT_COUNT)
+    }
+
+    ///
+    /// Raises `vector` every `count` timer ticks
+    ///
+    pub fn start_periodic(&self, vector: u8, count: u32) {
+        self.write(TIMER_DIVIDE, DIVIDE_BY_16);
+        self.write(LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        self.write(TIMER_INITIAL_COUNT, count);
+    }
+
+    pub fn stop_timer(&self) {
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(TIMER_INITIAL_COUNT, 0);
+    }
+}
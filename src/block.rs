@@ -0,0 +1,203 @@
+//!
+//! Block devices
+//!
+//! Disks are read and written in whole blocks through the `BlockDevice` trait. The
+//! operations are async: drivers start the transfer and the task waits for the
+//! completion interrupt instead of spinning, so filesystems can be written on top
+//! without caring which controller is below.
+//!
+//! Drivers `register` their disks here and filesystems look them up by name. Filesystems
+//! should go through `cache` rather than reading and writing the devices themselves.
+//!
+pub mod cache;
+pub mod partition;
+
+use crate::task::executor::Executor;
+use crate::task::Task;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use spin::Mutex;
+
+///
+/// What the operations of a `BlockDevice` return, boxed so the trait stays object safe
+///
+pub type BlockFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks go past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    BadBuffer,
+    ReadOnly,
+    /// The device reported an error or didn't answer
+    Io(&'static str),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "block out of range"),
+            BlockError::BadBuffer => write!(f, "buffer is not a whole number of blocks"),
+            BlockError::ReadOnly => write!(f, "device is read only"),
+            BlockError::Io(message) => write!(f, "I/O error: {}", message),
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    ///
+    /// Bytes in a block, the unit of every transfer
+    ///
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    ///
+    /// The disk this device is a part of, for partitions
+    ///
+    fn parent(&self) -> Option<&Arc<dyn BlockDevice>> {
+        None
+    }
+
+    ///
+    /// Reads the blocks from `start` on into `buffer`, as many as fit in it
+    ///
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a mut [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>>;
+
+    ///
+    /// Writes `buffer` to the blocks from `start` on
+    ///
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>>;
+
+    ///
+    /// Makes sure what was written reached the medium
+    ///
+    fn flush(&self) -> BlockFuture<'_, Result<(), BlockError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+///
+/// Checks that `len` bytes starting at block `start` are whole blocks inside `device`,
+/// and returns how many blocks that is
+///
+pub fn check_range(device: &dyn BlockDevice, start: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / block_size) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+///
+/// Makes a disk available to the rest of the kernel
+///
+pub fn register(device: Arc<dyn BlockDevice>) {
+    crate::info!(
+        "block: {}, {} blocks of {} bytes",
+        device.name(),
+        device.block_count(),
+        device.block_size()
+    );
+    DEVICES.lock().push(device);
+}
+
+///
+/// Takes a disk away, the ones already handed out keep working until dropped
+///
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(|device| device.name() == name)?;
+    Some(devices.remove(index))
+}
+
+///
+/// Takes disks away for good from a driver's `remove`, which can't wait: they're
+/// unregistered now and their cached blocks written and forgotten by a task. `stop`
+/// runs after that, to stop the device
+///
+pub fn remove(disks: Vec<Arc<dyn BlockDevice>>, stop: impl FnOnce() + 'static) {
+    for disk in &disks {
+        unregister(disk.name());
+    }
+    let release = async move {
+        for disk in &disks {
+            if let Err(error) = cache::release(disk).await {
+                crate::warn!("block: {} not written back, {}", disk.name(), error);
+            }
+        }
+        stop();
+    };
+    Executor::spawn_task()
+        .push(Task::new(release))
+        .expect("queue is full. consider increasing the number of concurrent tasks");
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+pub fn init() {
+    crate::shell::register(crate::shell::Command {
+        name: "lsblk",
+        help: "lists the block devices",
+        handler: lsblk,
+    });
+    crate::shell::register(crate::shell::Command {
+        name: "bcache",
+        help: "shows the statistics of the block cache",
+        handler: cache::bcache,
+    });
+}
+
+fn lsblk(out: &mut dyn fmt::Write, _arguments: &[&str]) -> crate::shell::CommandResult {
+    for device in devices() {
+        let bytes = device.block_count() * device.block_size() as u64;
+        write!(
+            out,
+            "{} {} MiB ({} x {})",
+            device.name(),
+            bytes / (1024 * 1024),
+            device.block_count(),
+            device.block_size()
+        )?;
+        if device.read_only() {
+            write!(out, " ro")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
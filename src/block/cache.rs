@@ -0,0 +1,391 @@
+//!
+//! Buffer cache
+//!
+//! Filesystems read and write blocks through here instead of going to the device. Blocks
+//! are kept by device and number, up to `CAPACITY` bytes of them, and the least recently
+//! used one makes room for new ones. Writes only change the cached copy and mark it
+//! dirty; it reaches the device when it's evicted, when somebody calls `flush` and every
+//! `FLUSH_INTERVAL` from `flush_periodically`.
+//!
+//! Reading a block right after the one before it on the same device counts as
+//! sequential access, and the blocks after it are read in the same request.
+//!
+use super::{BlockDevice, BlockError};
+use crate::task::sync::AsyncMutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use lazy_static::lazy_static;
+
+///
+/// Bytes of blocks the cache holds at most
+///
+pub const CAPACITY: usize = 128 * 1024;
+
+///
+/// Blocks read after the wanted one when the access is sequential
+///
+pub const READ_AHEAD: u64 = 8;
+
+///
+/// How long a dirty block waits at most before `flush_periodically` writes it
+///
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most blocks a write back puts in one request
+const MAX_WRITE_BLOCKS: u64 = 64;
+
+///
+/// Devices are told apart by the address of the device itself
+///
+type DeviceKey = usize;
+
+fn device_key(device: &Arc<dyn BlockDevice>) -> DeviceKey {
+    &**device as *const dyn BlockDevice as *const u8 as usize
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks read before anybody asked for them
+    pub read_ahead: u64,
+    pub evictions: u64,
+    /// Dirty blocks written to their devices
+    pub write_backs: u64,
+    /// Blocks cached now
+    pub blocks: usize,
+    pub dirty: usize,
+    pub bytes: usize,
+}
+
+struct Entry {
+    data: Vec<u8>,
+    dirty: bool,
+    /// When it was last used, the key in `lru`
+    stamp: u64,
+}
+
+struct Cache {
+    entries: BTreeMap<(DeviceKey, u64), Entry>,
+    /// Entries from the least to the most recently used
+    lru: BTreeMap<u64, (DeviceKey, u64)>,
+    devices: BTreeMap<DeviceKey, Arc<dyn BlockDevice>>,
+    /// Last block read from each device, to spot sequential reads
+    last_read: BTreeMap<DeviceKey, u64>,
+    clock: u64,
+    bytes: usize,
+    stats: CacheStats,
+}
+
+lazy_static! {
+    static ref CACHE: AsyncMutex<Cache> = AsyncMutex::new(Cache {
+        entries: BTreeMap::new(),
+        lru: BTreeMap::new(),
+        devices: BTreeMap::new(),
+        last_read: BTreeMap::new(),
+        clock: 0,
+        bytes: 0,
+        stats: CacheStats::default(),
+    });
+}
+
+impl Cache {
+    fn touch(&mut self, key: (DeviceKey, u64)) {
+        self.clock += 1;
+        let stamp = self.clock;
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.lru.remove(&entry.stamp);
+            entry.stamp = stamp;
+            self.lru.insert(stamp, key);
+        }
+    }
+
+    ///
+    /// Makes room for `bytes` more, writing back the dirty blocks it evicts
+    ///
+    async fn make_room(&mut self, bytes: usize) -> Result<(), BlockError> {
+        while self.bytes + bytes > CAPACITY {
+            let (&stamp, &key) = match self.lru.iter().next() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if self.entries.get(&key).map_or(false, |entry| entry.dirty) {
+                self.write_back(key.0, key.1, 1).await?;
+            }
+            self.lru.remove(&stamp);
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.data.len();
+            }
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: (DeviceKey, u64), data: Vec<u8>, dirty: bool) {
+        self.clock += 1;
+        self.bytes += data.len();
+        self.lru.insert(self.clock, key);
+        let entry = Entry {
+            data,
+            dirty,
+            stamp: self.clock,
+        };
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.bytes -= old.data.len();
+            self.lru.remove(&old.stamp);
+        }
+    }
+
+    ///
+    /// Reads `block` into the cache if it isn't there, with the blocks after it if the
+    /// access is sequential
+    ///
+    async fn load(&mut self, device: &Arc<dyn BlockDevice>, block: u64) -> Result<(), BlockError> {
+        let key = device_key(device);
+        let sequential = block > 0 && self.last_read.get(&key) == Some(&(block - 1));
+        self.last_read.insert(key, block);
+        if self.entries.contains_key(&(key, block)) {
+            self.stats.hits += 1;
+            self.touch((key, block));
+            return Ok(());
+        }
+        self.stats.misses += 1;
+        self.devices.entry(key).or_insert_with(|| device.clone());
+        // The run of blocks after it that aren't cached
+        let mut count = 1;
+        if sequential {
+            while count <= READ_AHEAD
+                && block + count < device.block_count()
+                && !self.entries.contains_key(&(key, block + count))
+            {
+                count += 1;
+            }
+        }
+        let block_size = device.block_size();
+        let mut buffer = vec![0; block_size * count as usize];
+        device.read_blocks(block, &mut buffer).await?;
+        self.make_room(buffer.len()).await?;
+        for (i, data) in buffer.chunks_exact(block_size).enumerate().rev() {
+            // The wanted block last, so it's the most recently used
+            self.insert((key, block + i as u64), data.to_vec(), false);
+        }
+        self.stats.read_ahead += count - 1;
+        Ok(())
+    }
+
+    ///
+    /// Writes up to `count` dirty blocks from `block` on in one request
+    ///
+    async fn write_back(
+        &mut self,
+        key: DeviceKey,
+        block: u64,
+        count: u64,
+    ) -> Result<(), BlockError> {
+        let device = match self.devices.get(&key) {
+            Some(device) => device.clone(),
+            None => return Ok(()),
+        };
+        let mut buffer = Vec::new();
+        let mut written = 0;
+        while written < count {
+            match self.entries.get(&(key, block + written)) {
+                Some(entry) if entry.dirty => buffer.extend_from_slice(&entry.data),
+                _ => break,
+            }
+            written += 1;
+        }
+        if written == 0 {
+            return Ok(());
+        }
+        device.write_blocks(block, &buffer).await?;
+        for i in 0..written {
+            if let Some(entry) = self.entries.get_mut(&(key, block + i)) {
+                entry.dirty = false;
+            }
+        }
+        self.stats.write_backs += written;
+        Ok(())
+    }
+
+    async fn flush(&mut self, key: DeviceKey) -> Result<(), BlockError> {
+        let dirty: Vec<u64> = self
+            .entries
+            .range((key, 0)..=(key, u64::MAX))
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&(_, block), _)| block)
+            .collect();
+        // Runs of consecutive blocks go together, the first write takes the whole run
+        for block in dirty {
+            if self
+                .entries
+                .get(&(key, block))
+                .map_or(false, |entry| entry.dirty)
+            {
+                self.write_back(key, block, MAX_WRITE_BLOCKS).await?;
+            }
+        }
+        if let Some(device) = self.devices.get(&key).cloned() {
+            device.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+fn check_access(
+    device: &dyn BlockDevice,
+    block: u64,
+    offset: usize,
+    len: usize,
+) -> Result<(), BlockError> {
+    if block >= device.block_count() {
+        return Err(BlockError::OutOfRange);
+    }
+    if offset + len > device.block_size() {
+        return Err(BlockError::BadBuffer);
+    }
+    Ok(())
+}
+
+///
+/// Copies `buffer.len()` bytes from `offset` on of `block`
+///
+pub async fn read(
+    device: &Arc<dyn BlockDevice>,
+    block: u64,
+    offset: usize,
+    buffer: &mut [u8],
+) -> Result<(), BlockError> {
+    check_access(&**device, block, offset, buffer.len())?;
+    let mut cache = CACHE.lock().await;
+    cache.load(device, block).await?;
+    let entry = &cache.entries[&(device_key(device), block)];
+    buffer.copy_from_slice(&entry.data[offset..offset + buffer.len()]);
+    Ok(())
+}
+
+///
+/// Changes the bytes of `block` from `offset` on. Only whole blocks are written without
+/// reading them first
+///
+pub async fn write(
+    device: &Arc<dyn BlockDevice>,
+    block: u64,
+    offset: usize,
+    data: &[u8],
+) -> Result<(), BlockError> {
+    if device.read_only() {
+        return Err(BlockError::ReadOnly);
+    }
+    check_access(&**device, block, offset, data.len())?;
+    let key = (device_key(device), block);
+    let mut cache = CACHE.lock().await;
+    if data.len() == device.block_size() && !cache.entries.contains_key(&key) {
+        cache.devices.entry(key.0).or_insert_with(|| device.clone());
+        cache.make_room(data.len()).await?;
+        cache.insert(key, data.to_vec(), true);
+        return Ok(());
+    }
+    cache.load(device, block).await?;
+    let entry = cache.entries.get_mut(&key).expect("just loaded");
+    entry.data[offset..offset + data.len()].copy_from_slice(data);
+    entry.dirty = true;
+    Ok(())
+}
+
+///
+/// Writes the dirty blocks of `device` and flushes it
+///
+pub async fn flush(device: &Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    CACHE.lock().await.flush(device_key(device)).await
+}
+
+///
+/// Writes every dirty block of every device
+///
+pub async fn flush_all() -> Result<(), BlockError> {
+    let mut cache = CACHE.lock().await;
+    let keys: Vec<DeviceKey> = cache.devices.keys().copied().collect();
+    for key in keys {
+        cache.flush(key).await?;
+    }
+    Ok(())
+}
+
+///
+/// Writes and forgets the blocks of `device`, for when it goes away
+///
+pub async fn release(device: &Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    let key = device_key(device);
+    let mut cache = CACHE.lock().await;
+    cache.flush(key).await?;
+    let blocks: Vec<(DeviceKey, u64)> = cache
+        .entries
+        .range((key, 0)..=(key, u64::MAX))
+        .map(|(&key, _)| key)
+        .collect();
+    for block in blocks {
+        if let Some(entry) = cache.entries.remove(&block) {
+            cache.bytes -= entry.data.len();
+            cache.lru.remove(&entry.stamp);
+        }
+    }
+    cache.devices.remove(&key);
+    cache.last_read.remove(&key);
+    Ok(())
+}
+
+///
+/// Task that writes the dirty blocks every `FLUSH_INTERVAL`
+///
+pub async fn flush_periodically() {
+    loop {
+        crate::time::sleep(FLUSH_INTERVAL).await;
+        if let Err(error) = flush_all().await {
+            crate::warn!("block cache: write back failed: {}", error);
+        }
+    }
+}
+
+///
+/// `None` while somebody is using the cache
+///
+pub fn stats() -> Option<CacheStats> {
+    let cache = CACHE.try_lock()?;
+    Some(CacheStats {
+        blocks: cache.entries.len(),
+        dirty: cache.entries.values().filter(|entry| entry.dirty).count(),
+        bytes: cache.bytes,
+        ..cache.stats
+    })
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} blocks cached ({} dirty), {} of {} KiB",
+            self.blocks,
+            self.dirty,
+            self.bytes / 1024,
+            CAPACITY / 1024
+        )?;
+        write!(
+            f,
+            "{} hits, {} misses, {} read ahead, {} evicted, {} written back",
+            self.hits, self.misses, self.read_ahead, self.evictions, self.write_backs
+        )
+    }
+}
+
+pub(super) fn bcache(out: &mut dyn fmt::Write, _arguments: &[&str]) -> crate::shell::CommandResult {
+    match stats() {
+        Some(stats) => writeln!(out, "{}", stats)?,
+        None => writeln!(out, "the cache is busy")?,
+    }
+    Ok(())
+}
@@ -0,0 +1,397 @@
+//!
+//! Partition tables
+//!
+//! `scan` reads the MBR of a disk, and the GPT if the MBR is the protective one, and
+//! gives back each partition as a block device of its own: `hda1`, `vdb2`, ... Logical
+//! partitions inside an MBR extended partition are numbered from 5 like everybody else
+//! does. A partition is a window on its disk, requests outside of it are refused.
+//!
+use super::{BlockDevice, BlockError, BlockFuture};
+use crate::bytes::ReadBytes;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+const SECTOR_SIZE: usize = 512;
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+const MBR_EMPTY: u8 = 0x00;
+const MBR_EXTENDED_CHS: u8 = 0x05;
+const MBR_EXTENDED_LBA: u8 = 0x0f;
+const MBR_EXTENDED_LINUX: u8 = 0x85;
+const MBR_PROTECTIVE: u8 = 0xee;
+
+/// A broken chain of extended boot records could loop forever
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// More than any real disk has, keeps a bad header from taking the whole heap
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+///
+/// GUID as stored in the GPT: the first three fields little endian
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// System id of the MBR entry
+    Mbr(u8),
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        name: String,
+    },
+}
+
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    /// Number, from 1
+    pub number: usize,
+    /// First block on the disk
+    pub start: u64,
+    pub blocks: u64,
+    pub kind: PartitionKind,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn parent(&self) -> Option<&Arc<dyn BlockDevice>> {
+        Some(&self.disk)
+    }
+
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a mut [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            super::check_range(self, start, buffer.len())?;
+            self.disk.read_blocks(self.start + start, buffer).await
+        })
+    }
+
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            super::check_range(self, start, buffer.len())?;
+            self.disk.write_blocks(self.start + start, buffer).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, Result<(), BlockError>> {
+        self.disk.flush()
+    }
+}
+
+///
+/// CRC-32 of the GPT (the IEEE one, reflected)
+///
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+///
+/// Where a partition is, before it's made a device
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    number: usize,
+    start: u64,
+    blocks: u64,
+    kind: PartitionKind,
+}
+
+async fn read_sectors(
+    disk: &Arc<dyn BlockDevice>,
+    sector: u64,
+    count: usize,
+) -> Result<Vec<u8>, BlockError> {
+    let mut buffer = vec![0; count * SECTOR_SIZE];
+    disk.read_blocks(sector, &mut buffer).await?;
+    Ok(buffer)
+}
+
+fn is_extended(kind: u8) -> bool {
+    kind == MBR_EXTENDED_CHS || kind == MBR_EXTENDED_LBA || kind == MBR_EXTENDED_LINUX
+}
+
+///
+/// The four entries of a boot record: system id, first sector and sector count
+///
+fn mbr_entries(sector: &[u8]) -> Option<[(u8, u64, u64); 4]> {
+    if sector.read_u16(510)? != MBR_SIGNATURE {
+        return None;
+    }
+    let mut entries = [(MBR_EMPTY, 0, 0); 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let offset = MBR_ENTRIES + i * MBR_ENTRY_SIZE;
+        *entry = (
+            sector.read_u8(offset + 4)?,
+            sector.read_u32(offset + 8)? as u64,
+            sector.read_u32(offset + 12)? as u64,
+        );
+    }
+    Some(entries)
+}
+
+///
+/// Follows the chain of extended boot records. Their first sectors are relative to the
+/// record, the links to the next record relative to the extended partition
+///
+async fn logical_partitions(
+    disk: &Arc<dyn BlockDevice>,
+    extended: u64,
+    entries: &mut Vec<Entry>,
+) -> Result<(), BlockError> {
+    let mut record = extended;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        let sector = read_sectors(disk, record, 1).await?;
+        let table = match mbr_entries(&sector) {
+            Some(table) => table,
+            None => break,
+        };
+        let (kind, start, blocks) = table[0];
+        if kind != MBR_EMPTY && blocks != 0 {
+            entries.push(Entry {
+                number,
+                start: record + start,
+                blocks,
+                kind: PartitionKind::Mbr(kind),
+            });
+        }
+        let (next_kind, next, _) = table[1];
+        if !is_extended(next_kind) || next == 0 {
+            break;
+        }
+        record = extended + next;
+    }
+    Ok(())
+}
+
+///
+/// Reads and checks the GPT header at `lba`
+///
+async fn gpt_entries(disk: &Arc<dyn BlockDevice>, lba: u64) -> Result<Vec<Entry>, &'static str> {
+    let header = read_sectors(disk, lba, 1)
+        .await
+        .map_err(|_| "can't read the GPT header")?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err("no GPT signature");
+    }
+    let size = header.read_u32(12).ok_or("bad GPT header")? as usize;
+    if !(GPT_MIN_HEADER_SIZE..=SECTOR_SIZE).contains(&size) {
+        return Err("bad GPT header size");
+    }
+    let mut copy = header[..size].to_vec();
+    copy[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&copy) != header.read_u32(16).ok_or("bad GPT header")? {
+        return Err("bad GPT header CRC");
+    }
+    let bad = "bad GPT header";
+    let entries_lba = header.read_u64(72).ok_or(bad)?;
+    let count = header.read_u32(80).ok_or(bad)?;
+    let entry_size = header.read_u32(84).ok_or(bad)? as usize;
+    let entries_crc = header.read_u32(88).ok_or(bad)?;
+    if count > GPT_MAX_ENTRIES || entry_size < GPT_MIN_ENTRY_SIZE || entry_size % 8 != 0 {
+        return Err("bad GPT entry array");
+    }
+    let bytes = count as usize * entry_size;
+    let sectors = (bytes + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let array = read_sectors(disk, entries_lba, sectors)
+        .await
+        .map_err(|_| "can't read the GPT entries")?;
+    if crc32(&array[..bytes]) != entries_crc {
+        return Err("bad GPT entries CRC");
+    }
+    let mut entries = Vec::new();
+    for (i, entry) in array[..bytes].chunks_exact(entry_size).enumerate() {
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&entry[..16]);
+        if Guid(type_guid) == Guid::UNUSED {
+            continue;
+        }
+        let mut unique_guid = [0; 16];
+        unique_guid.copy_from_slice(&entry[16..32]);
+        let first = entry.read_u64(32).ok_or("bad GPT entry")?;
+        let last = entry.read_u64(40).ok_or("bad GPT entry")?;
+        if last < first {
+            continue;
+        }
+        let name = entry[56..128]
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .take_while(|&unit| unit != 0);
+        let name = core::char::decode_utf16(name)
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+        entries.push(Entry {
+            number: i + 1,
+            start: first,
+            blocks: last - first + 1,
+            kind: PartitionKind::Gpt {
+                type_guid: Guid(type_guid),
+                unique_guid: Guid(unique_guid),
+                name,
+            },
+        });
+    }
+    Ok(entries)
+}
+
+///
+/// Reads the partition table of `disk`. A disk without one has no partitions
+///
+pub async fn scan(disk: &Arc<dyn BlockDevice>) -> Result<Vec<Arc<Partition>>, BlockError> {
+    if disk.block_size() != SECTOR_SIZE || disk.block_count() < 2 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_sectors(disk, 0, 1).await?;
+    let table = match mbr_entries(&mbr) {
+        Some(table) => table,
+        None => return Ok(Vec::new()),
+    };
+    let mut entries = Vec::new();
+    if table.iter().any(|&(kind, _, _)| kind == MBR_PROTECTIVE) {
+        // The backup header is in the last sector if the first one is damaged
+        let last = disk.block_count() - 1;
+        entries = match gpt_entries(disk, 1).await {
+            Ok(entries) => entries,
+            Err(error) => {
+                crate::warn!("partition: {}: {}, trying the backup", disk.name(), error);
+                gpt_entries(disk, last).await.unwrap_or_else(|error| {
+                    crate::warn!("partition: {}: {}", disk.name(), error);
+                    Vec::new()
+                })
+            }
+        };
+    } else {
+        for (i, &(kind, start, blocks)) in table.iter().enumerate() {
+            if kind == MBR_EMPTY || blocks == 0 {
+                continue;
+            }
+            if is_extended(kind) {
+                logical_partitions(disk, start, &mut entries).await?;
+                continue;
+            }
+            entries.push(Entry {
+                number: i + 1,
+                start,
+                blocks,
+                kind: PartitionKind::Mbr(kind),
+            });
+        }
+    }
+    let mut partitions = Vec::new();
+    for entry in entries {
+        let fits = entry
+            .start
+            .checked_add(entry.blocks)
+            .map_or(false, |end| entry.start > 0 && end <= disk.block_count());
+        if !fits {
+            crate::warn!(
+                "partition: {} {} goes past the end of the disk",
+                disk.name(),
+                entry.number
+            );
+            continue;
+        }
+        partitions.push(Arc::new(Partition {
+            name: alloc::format!("{}{}", disk.name(), entry.number),
+            disk: disk.clone(),
+            number: entry.number,
+            start: entry.start,
+            blocks: entry.blocks,
+            kind: entry.kind,
+        }));
+    }
+    Ok(partitions)
+}
+
+///
+/// Scans the disks registered so far and registers their partitions
+///
+pub async fn scan_devices() {
+    let devices = super::devices();
+    let scanned = |disk: &Arc<dyn BlockDevice>| {
+        devices
+            .iter()
+            .filter_map(|device| device.parent())
+            .any(|parent| parent.name() == disk.name())
+    };
+    // Partitions don't have partitions
+    for disk in devices.iter().filter(|disk| disk.parent().is_none()) {
+        if scanned(disk) {
+            continue;
+        }
+        match scan(disk).await {
+            Ok(partitions) => {
+                for partition in partitions {
+                    super::register(partition);
+                }
+            }
+            Err(error) => crate::warn!("partition: {}: {}", disk.name(), error),
+        }
+    }
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+}
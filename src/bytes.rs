@@ -0,0 +1,35 @@
+//!
+//! Reading fields out of byte buffers: ACPI tables, partition tables, ...
+//!
+///
+/// Little endian reads that don't go past the end of the data. Fields that aren't
+/// there, like the ones an old ACPI table doesn't have yet, read as `None`
+///
+pub(crate) trait ReadBytes {
+    fn read_u8(&self, offset: usize) -> Option<u8>;
+    fn read_u16(&self, offset: usize) -> Option<u16>;
+    fn read_u32(&self, offset: usize) -> Option<u32>;
+    fn read_u64(&self, offset: usize) -> Option<u64>;
+}
+
+impl ReadBytes for [u8] {
+    fn read_u8(&self, offset: usize) -> Option<u8> {
+        self.get(offset).copied()
+    }
+
+    fn read_u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&self, offset: usize) -> Option<u64> {
+        let low = self.read_u32(offset)? as u64;
+        let high = self.read_u32(offset + 4)? as u64;
+        Some(high << 32 | low)
+    }
+}
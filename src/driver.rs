@@ -0,0 +1,466 @@
+//!
+//! Driver model
+//!
+//! Devices live in a tree: the platform root, the buses under it and the devices found
+//! on each bus. Whoever discovers a device (the PCI scan, the legacy ISA table, ...)
+//! adds it with `add_device`, and drivers are registered with `register_driver`. When a
+//! driver `matches` a device without a driver its `probe` runs; `remove` runs when the
+//! device goes away and `shutdown` before the machine reboots or powers off.
+//!
+//! Drivers get their interrupt with `request_irq`, a thin wrapper over `irq::register`.
+//!
+pub mod ata;
+pub mod ps2;
+pub mod rtc;
+pub mod virtio;
+
+use crate::irq::{self, HandlerId, Irq, IrqError};
+use crate::pci::{Bar, PciDevice};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+pub type DeviceId = usize;
+
+#[derive(Debug, Clone)]
+pub enum Bus {
+    /// The platform itself and the buses hanging from it
+    Root,
+    /// Legacy devices at fixed ports and IRQs
+    Isa,
+    Pci(PciDevice),
+    /// Described by an ACPI table
+    Acpi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Io { base: u16, len: u16 },
+    Memory { base: u64, len: u64 },
+    Irq(u8),
+}
+
+#[derive(Clone)]
+pub struct Device {
+    pub id: DeviceId,
+    pub name: String,
+    pub parent: Option<DeviceId>,
+    pub bus: Bus,
+    pub resources: Vec<Resource>,
+    driver: Option<&'static dyn Driver>,
+}
+
+impl Device {
+    ///
+    /// First interrupt line of the device
+    ///
+    pub fn irq(&self) -> Option<u8> {
+        self.resources.iter().find_map(|resource| match resource {
+            Resource::Irq(line) => Some(*line),
+            _ => None,
+        })
+    }
+
+    ///
+    /// `index`th I/O port range of the device
+    ///
+    pub fn io(&self, index: usize) -> Option<(u16, u16)> {
+        self.resources
+            .iter()
+            .filter_map(|resource| match resource {
+                Resource::Io { base, len } => Some((*base, *len)),
+                _ => None,
+            })
+            .nth(index)
+    }
+
+    pub fn pci(&self) -> Option<&PciDevice> {
+        match &self.bus {
+            Bus::Pci(device) => Some(device),
+            _ => None,
+        }
+    }
+
+    pub fn driver_name(&self) -> Option<&'static str> {
+        self.driver.map(|driver| driver.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError {
+    /// The device is not one the driver can handle after all
+    Unsupported,
+    /// The interrupt line is taken by another driver
+    IrqBusy(u8),
+    Failed(&'static str),
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::Unsupported => write!(f, "device not supported"),
+            DriverError::IrqBusy(line) => write!(f, "IRQ {} is already in use", line),
+            DriverError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+///
+/// Drivers are statics, the tree only keeps references to them
+///
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    ///
+    /// Runs once when the driver is registered, before any probe
+    ///
+    fn init(&self) {}
+
+    ///
+    /// Cheap check of whether the driver is for `device`, shouldn't touch the hardware
+    ///
+    fn matches(&self, device: &Device) -> bool;
+
+    ///
+    /// Sets the device up. If it fails other drivers get a chance
+    ///
+    fn probe(&self, device: &Device) -> Result<(), DriverError>;
+
+    ///
+    /// The device left the tree, release what `probe` took
+    ///
+    fn remove(&self, _device: &Device) {}
+
+    ///
+    /// The machine is about to reboot or power off, quiesce the device
+    ///
+    fn shutdown(&self, _device: &Device) {}
+}
+
+static DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new());
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Offers `device` to the registered drivers until one takes it. The locks aren't
+/// held while probing, probes can add devices of their own
+///
+fn bind(device: Device) {
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        if !driver.matches(&device) {
+            continue;
+        }
+        match driver.probe(&device) {
+            Ok(()) => {
+                crate::info!("driver: {} bound to {}", driver.name(), device.name);
+                if let Some(entry) = DEVICES.lock().iter_mut().find(|d| d.id == device.id) {
+                    entry.driver = Some(driver);
+                }
+                return;
+            }
+            Err(error) => crate::warn!("driver: {} on {}: {}", driver.name(), device.name, error),
+        }
+    }
+}
+
+///
+/// Adds a device to the tree and binds a driver to it if one matches
+///
+pub fn add_device(
+    name: impl Into<String>,
+    parent: Option<DeviceId>,
+    bus: Bus,
+    resources: Vec<Resource>,
+) -> DeviceId {
+    let device = Device {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name: name.into(),
+        parent,
+        bus,
+        resources,
+        driver: None,
+    };
+    let id = device.id;
+    DEVICES.lock().push(device.clone());
+    bind(device);
+    id
+}
+
+///
+/// Takes a device and everything below it out of the tree, children first
+///
+pub fn remove_device(id: DeviceId) {
+    let children: Vec<DeviceId> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.parent == Some(id))
+        .map(|device| device.id)
+        .collect();
+    for child in children {
+        remove_device(child);
+    }
+    let removed = {
+        let mut devices = DEVICES.lock();
+        devices
+            .iter()
+            .position(|device| device.id == id)
+            .map(|index| devices.remove(index))
+    };
+    if let Some(device) = removed {
+        if let Some(driver) = device.driver {
+            driver.remove(&device);
+        }
+    }
+}
+
+///
+/// Registers a driver and probes the devices nobody drives yet
+///
+pub fn register_driver(driver: &'static dyn Driver) {
+    driver.init();
+    DRIVERS.lock().push(driver);
+    let unbound: Vec<Device> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.driver.is_none() && driver.matches(device))
+        .cloned()
+        .collect();
+    for device in unbound {
+        bind(device);
+    }
+}
+
+///
+/// Copy of the tree, parents before their children
+///
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+pub fn find_device(name: &str) -> Option<Device> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name == name)
+        .cloned()
+}
+
+///
+/// Calls `shutdown` of every driver, the most recently added devices first
+///
+pub fn shutdown_all() {
+    // Don't deadlock if we're going down while somebody had the tree
+    let devices = match DEVICES.try_lock() {
+        Some(devices) => devices.clone(),
+        None => return,
+    };
+    for device in devices.iter().rev() {
+        if let Some(driver) = device.driver {
+            driver.shutdown(device);
+        }
+    }
+}
+
+fn irq_error(line: u8, error: IrqError) -> DriverError {
+    match error {
+        IrqError::Busy => DriverError::IrqBusy(line),
+        IrqError::Invalid => DriverError::Failed("invalid IRQ line"),
+        IrqError::Full => DriverError::Failed("no room for the interrupt handler"),
+    }
+}
+
+///
+/// Runs `handler` whenever IRQ `line` fires, with interrupts disabled. The interrupt
+/// is acknowledged once the handler returns
+///
+pub fn request_irq<F>(line: u8, handler: F) -> Result<HandlerId, DriverError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    irq::register(Irq::Line(line), handler).map_err(|error| irq_error(line, error))
+}
+
+///
+/// Like `request_irq` for devices that share their line, PCI ones mostly. The handler
+/// runs for every interrupt on the line and has to check whether its device raised it
+///
+pub fn request_shared_irq<F>(line: u8, handler: F) -> Result<HandlerId, DriverError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    irq::register_shared(Irq::Line(line), handler).map_err(|error| irq_error(line, error))
+}
+
+///
+/// Legacy devices every PC has, they can't be discovered
+///
+fn add_isa_devices(parent: DeviceId) {
+    use alloc::vec;
+    let isa = Some(parent);
+    add_device(
+        "pit",
+        isa,
+        Bus::Isa,
+        vec![Resource::Io { base: 0x40, len: 4 }, Resource::Irq(0)],
+    );
+    add_device(
+        rtc::DEVICE,
+        isa,
+        Bus::Isa,
+        vec![Resource::Io { base: 0x70, len: 2 }, Resource::Irq(8)],
+    );
+    add_device(
+        "com1",
+        isa,
+        Bus::Isa,
+        vec![
+            Resource::Io {
+                base: 0x3f8,
+                len: 8,
+            },
+            Resource::Irq(4),
+        ],
+    );
+    add_device(
+        ps2::KEYBOARD_DEVICE,
+        isa,
+        Bus::Isa,
+        vec![
+            Resource::Io { base: 0x60, len: 1 },
+            Resource::Io { base: 0x64, len: 1 },
+            Resource::Irq(1),
+        ],
+    );
+    add_device(
+        ps2::MOUSE_DEVICE,
+        isa,
+        Bus::Isa,
+        vec![
+            Resource::Io { base: 0x60, len: 1 },
+            Resource::Io { base: 0x64, len: 1 },
+            Resource::Irq(12),
+        ],
+    );
+    let ata = [(0x1f0, 0x3f6, 14), (0x170, 0x376, 15)];
+    for (name, &(io, control, line)) in ata::CHANNELS.iter().zip(ata.iter()) {
+        add_device(
+            *name,
+            isa,
+            Bus::Isa,
+            vec![
+                Resource::Io { base: io, len: 8 },
+                Resource::Io {
+                    base: control,
+                    len: 1,
+                },
+                Resource::Irq(line),
+            ],
+        );
+    }
+}
+
+fn pci_resources(device: &PciDevice) -> Vec<Resource> {
+    let mut resources: Vec<Resource> = device
+        .bars
+        .iter()
+        .flatten()
+        .map(|bar| match *bar {
+            Bar::Io { port, size } => Resource::Io {
+                base: port,
+                len: size,
+            },
+            bar => Resource::Memory {
+                base: bar.address(),
+                len: bar.size(),
+            },
+        })
+        .collect();
+    if device.interrupt_pin != 0 && device.interrupt_line < 16 {
+        resources.push(Resource::Irq(device.interrupt_line));
+    }
+    resources
+}
+
+fn add_pci_devices(parent: DeviceId) {
+    for device in crate::pci::devices() {
+        let name = alloc::format!("pci {}", device.address);
+        let resources = pci_resources(&device);
+        add_device(name, Some(parent), Bus::Pci(device), resources);
+    }
+}
+
+///
+/// Devices only the ACPI tables know about
+///
+fn add_acpi_devices(parent: DeviceId) {
+    use alloc::vec;
+    let acpi = match crate::acpi::get() {
+        Some(acpi) => acpi,
+        None => return,
+    };
+    if let Some(hpet) = &acpi.hpet {
+        let memory = Resource::Memory {
+            base: hpet.address.address,
+            len: 0x400,
+        };
+        add_device("hpet", Some(parent), Bus::Acpi, vec![memory]);
+    }
+}
+
+///
+/// Builds the tree from the legacy devices, the ACPI tables and the PCI registry, so
+/// call it after `acpi::init` and `pci::init`, and registers the built-in drivers
+///
+pub fn init() {
+    let root = add_device("platform", None, Bus::Root, Vec::new());
+    let isa = add_device("isa", Some(root), Bus::Root, Vec::new());
+    add_isa_devices(isa);
+    let acpi = add_device("acpi", Some(root), Bus::Root, Vec::new());
+    add_acpi_devices(acpi);
+    let pci = add_device("pci", Some(root), Bus::Root, Vec::new());
+    add_pci_devices(pci);
+    register_driver(&ps2::KEYBOARD);
+    register_driver(&ps2::MOUSE);
+    register_driver(&rtc::RTC);
+    register_driver(&ata::ATA);
+    register_driver(&virtio::blk::VIRTIO_BLK);
+    crate::shell::register(crate::shell::Command {
+        name: "lsdev",
+        help: "shows the device tree and the drivers",
+        handler: lsdev,
+    });
+}
+
+fn write_subtree(
+    out: &mut dyn fmt::Write,
+    devices: &[Device],
+    parent: Option<DeviceId>,
+    depth: usize,
+) -> fmt::Result {
+    for device in devices.iter().filter(|device| device.parent == parent) {
+        write!(out, "{:width$}{}", "", device.name, width = depth * 2)?;
+        if let Some(driver) = device.driver_name() {
+            write!(out, " [{}]", driver)?;
+        }
+        for resource in &device.resources {
+            match resource {
+                Resource::Io { base, len } => write!(out, " io {:#x}+{:#x}", base, len)?,
+                Resource::Memory { base, len } => write!(out, " mem {:#x}+{:#x}", base, len)?,
+                Resource::Irq(line) => write!(out, " irq {}", line)?,
+            }
+        }
+        writeln!(out)?;
+        write_subtree(out, devices, Some(device.id), depth + 1)?;
+    }
+    Ok(())
+}
+
+fn lsdev(out: &mut dyn fmt::Write, _arguments: &[&str]) -> crate::shell::CommandResult {
+    write_subtree(out, &devices(), None, 0)?;
+    Ok(())
+}
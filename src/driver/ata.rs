@@ -0,0 +1,545 @@
+//!
+//! ATA (IDE) disks in PIO mode
+//!
+//! Each of the two legacy channels has a master and a slave drive behind the same
+//! ports, so a channel runs one command at a time. Drives are found with IDENTIFY when
+//! the channel is probed and registered as block devices `hda` to `hdd`.
+//!
+//! Transfers go sector by sector through the data port. The drive raises the IRQ of
+//! the channel (14 or 15) whenever a sector is ready or the command is done, and the
+//! task waiting for it is woken then instead of polling the status.
+//!
+use super::{request_irq, Bus, Device, DeviceId, Driver, DriverError};
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::irq::{self, HandlerId};
+use crate::task::sync::AsyncMutex;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub const CHANNELS: [&str; 2] = ["ata0", "ata1"];
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Most sectors a command can move, a count of 0 means 256 in LBA28
+const MAX_SECTORS: usize = 256;
+
+// Registers, offsets from the I/O base
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+/// In the control register, keeps the drives from raising interrupts
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+const DRIVE_LBA: u8 = 0x40;
+const DRIVE_SLAVE: u8 = 0x10;
+/// Bits that must be set in the drive register of old drives
+const DRIVE_LEGACY: u8 = 0xa0;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_FLUSH: u8 = 0xe7;
+const COMMAND_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// How many times the status is polled before giving up on the drive
+const TIMEOUT: usize = 1_000_000;
+
+///
+/// What IDENTIFY says about a drive
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Identify {
+    sectors: u64,
+    lba48: bool,
+    model: [u8; 40],
+}
+
+impl Identify {
+    fn parse(words: &[u16; 256]) -> Self {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, i| {
+                sectors | (words[100 + i] as u64) << (16 * i)
+            })
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        // Two characters per word, the first one in the high byte
+        let mut model = [0; 40];
+        for (i, word) in words[27..47].iter().enumerate() {
+            model[i * 2] = (word >> 8) as u8;
+            model[i * 2 + 1] = *word as u8;
+        }
+        Identify {
+            sectors,
+            lba48,
+            model,
+        }
+    }
+
+    fn model(&self) -> String {
+        String::from_utf8_lossy(&self.model).trim().to_string()
+    }
+}
+
+///
+/// One of the ATA channels and its interrupt
+///
+struct Channel {
+    io: u16,
+    control: u16,
+    /// Held for a whole command, the drives share the registers
+    lock: AsyncMutex<()>,
+    interrupted: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Channel {
+    fn new(io: u16, control: u16) -> Self {
+        Channel {
+            io,
+            control,
+            lock: AsyncMutex::new(()),
+            interrupted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    ///
+    /// Status without acknowledging the interrupt
+    ///
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        let value = if enabled { 0 } else { CONTROL_NO_INTERRUPTS };
+        unsafe { Port::<u8>::new(self.control).write(value) }
+    }
+
+    ///
+    /// Drives need 400ns to update the status after a command or a drive selection,
+    /// reading the alternate status takes about 100ns
+    ///
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    ///
+    /// Runs in the interrupt handler. Reading the status acknowledges the interrupt
+    ///
+    fn interrupt(&self) {
+        self.read(STATUS);
+        self.interrupted.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Io("drive busy for too long"))
+    }
+
+    fn wait_data_request(&self) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = self.alternate_status();
+            if status & (STATUS_ERROR | STATUS_FAULT) != 0 {
+                return Err(BlockError::Io("drive error"));
+            }
+            if status & (STATUS_BUSY | STATUS_DATA_REQUEST) == STATUS_DATA_REQUEST {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Io("drive didn't ask for data"))
+    }
+
+    ///
+    /// Waits for the drive to raise the interrupt and checks how the step went
+    ///
+    async fn wait_interrupt(&self) -> Result<(), BlockError> {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            if self.interrupted.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        let status = self.alternate_status();
+        if status & STATUS_FAULT != 0 {
+            Err(BlockError::Io("drive fault"))
+        } else if status & STATUS_ERROR != 0 {
+            crate::warn!("ata: error {:#x}", self.read(ERROR));
+            Err(BlockError::Io("drive error"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn select(&self, slave: bool, bits: u8) -> Result<(), BlockError> {
+        let slave = if slave { DRIVE_SLAVE } else { 0 };
+        self.write(DRIVE, DRIVE_LEGACY | slave | bits);
+        self.delay();
+        self.wait_not_busy().map(|_| ())
+    }
+
+    ///
+    /// Selects the drive and sends a command for `count` sectors from `lba`. The
+    /// interrupt flag is cleared first so an old interrupt isn't taken for this one
+    ///
+    fn start(
+        &self,
+        disk: &AtaDisk,
+        lba: u64,
+        count: usize,
+        command: u8,
+        command_ext: u8,
+    ) -> Result<(), BlockError> {
+        if disk.lba48 {
+            self.select(disk.slave, DRIVE_LBA)?;
+            self.interrupted.store(false, Ordering::Release);
+            // High bytes first, the registers are two deep
+            self.write(SECTOR_COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, (lba >> 24) as u8);
+            self.write(LBA_MID, (lba >> 32) as u8);
+            self.write(LBA_HIGH, (lba >> 40) as u8);
+            self.write(SECTOR_COUNT, count as u8);
+            self.write(LBA_LOW, lba as u8);
+            self.write(LBA_MID, (lba >> 8) as u8);
+            self.write(LBA_HIGH, (lba >> 16) as u8);
+            self.write(COMMAND, command_ext);
+        } else {
+            self.select(disk.slave, DRIVE_LBA | (lba >> 24) as u8 & 0x0f)?;
+            self.interrupted.store(false, Ordering::Release);
+            self.write(SECTOR_COUNT, count as u8);
+            self.write(LBA_LOW, lba as u8);
+            self.write(LBA_MID, (lba >> 8) as u8);
+            self.write(LBA_HIGH, (lba >> 16) as u8);
+            self.write(COMMAND, command);
+        }
+        self.delay();
+        Ok(())
+    }
+
+    async fn read_sectors(
+        &self,
+        disk: &AtaDisk,
+        lba: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockError> {
+        let _guard = self.lock.lock().await;
+        let count = buffer.len() / SECTOR_SIZE;
+        self.start(disk, lba, count, COMMAND_READ, COMMAND_READ_EXT)?;
+        let mut data: Port<u16> = Port::new(self.io + DATA);
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_interrupt().await?;
+            self.wait_data_request()?;
+            for bytes in sector.chunks_exact_mut(2) {
+                bytes.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_sectors(
+        &self,
+        disk: &AtaDisk,
+        lba: u64,
+        buffer: &[u8],
+    ) -> Result<(), BlockError> {
+        let _guard = self.lock.lock().await;
+        let count = buffer.len() / SECTOR_SIZE;
+        self.start(disk, lba, count, COMMAND_WRITE, COMMAND_WRITE_EXT)?;
+        let mut data: Port<u16> = Port::new(self.io + DATA);
+        // The drive asks for the first sector without an interrupt, for the others
+        // the interrupt comes when the previous one is written
+        for sector in buffer.chunks_exact(SECTOR_SIZE) {
+            self.wait_data_request()?;
+            for bytes in sector.chunks_exact(2) {
+                unsafe { data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+            }
+            self.wait_interrupt().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self, disk: &AtaDisk) -> Result<(), BlockError> {
+        let _guard = self.lock.lock().await;
+        self.start(disk, 0, 0, COMMAND_FLUSH, COMMAND_FLUSH_EXT)?;
+        self.wait_interrupt().await
+    }
+
+    ///
+    /// Flushes the write cache without waiting for the interrupt, for when the machine
+    /// goes down. Does nothing if a command is running
+    ///
+    fn flush_now(&self, disk: &AtaDisk) {
+        if let Some(_guard) = self.lock.try_lock() {
+            let flushed = self
+                .start(disk, 0, 0, COMMAND_FLUSH, COMMAND_FLUSH_EXT)
+                .and_then(|_| self.wait_not_busy());
+            if let Err(error) = flushed {
+                crate::warn!("ata: flushing {} failed: {}", disk.name, error);
+            }
+        }
+    }
+
+    ///
+    /// Runs IDENTIFY on a drive, polling since the interrupts are off. `None` if there's
+    /// no drive or it isn't an ATA disk (ATAPI and SATA drives answer with a signature)
+    ///
+    fn identify(&self, slave: bool) -> Option<Identify> {
+        self.select(slave, 0).ok()?;
+        self.write(SECTOR_COUNT, 0);
+        self.write(LBA_LOW, 0);
+        self.write(LBA_MID, 0);
+        self.write(LBA_HIGH, 0);
+        self.write(COMMAND, COMMAND_IDENTIFY);
+        self.delay();
+        if self.alternate_status() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data_request().ok()?;
+        let mut data: Port<u16> = Port::new(self.io + DATA);
+        let mut words = [0; 256];
+        for word in words.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        self.read(STATUS);
+        Some(Identify::parse(&words))
+    }
+}
+
+pub struct AtaDisk {
+    name: String,
+    channel: Arc<Channel>,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+}
+
+impl BlockDevice for AtaDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a mut [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            block::check_range(self, start, buffer.len())?;
+            let mut lba = start;
+            for chunk in buffer.chunks_mut(MAX_SECTORS * SECTOR_SIZE) {
+                self.channel.read_sectors(self, lba, chunk).await?;
+                lba += (chunk.len() / SECTOR_SIZE) as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            block::check_range(self, start, buffer.len())?;
+            let mut lba = start;
+            for chunk in buffer.chunks(MAX_SECTORS * SECTOR_SIZE) {
+                self.channel.write_sectors(self, lba, chunk).await?;
+                lba += (chunk.len() / SECTOR_SIZE) as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, Result<(), BlockError>> {
+        Box::pin(self.channel.flush(self))
+    }
+}
+
+///
+/// A probed channel and what it registered
+///
+struct Probed {
+    device: DeviceId,
+    handler: HandlerId,
+    disks: Vec<Arc<AtaDisk>>,
+}
+
+pub struct AtaDriver {
+    channels: Mutex<Vec<Probed>>,
+}
+
+pub static ATA: AtaDriver = AtaDriver {
+    channels: Mutex::new(Vec::new()),
+};
+
+impl Driver for AtaDriver {
+    fn name(&self) -> &'static str {
+        "ata-pio"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        matches!(device.bus, Bus::Isa) && CHANNELS.contains(&device.name.as_str())
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        let index = CHANNELS
+            .iter()
+            .position(|name| *name == device.name)
+            .ok_or(DriverError::Unsupported)?;
+        let (io, control, line) = match (device.io(0), device.io(1), device.irq()) {
+            (Some((io, _)), Some((control, _)), Some(line)) => (io, control, line),
+            _ => return Err(DriverError::Unsupported),
+        };
+        let channel = Arc::new(Channel::new(io, control));
+        // Nothing drives the bus of an empty channel, the status reads as all ones
+        if channel.alternate_status() == 0xff {
+            return Err(DriverError::Failed("no drives"));
+        }
+        channel.set_interrupts(false);
+        let mut disks = Vec::new();
+        for &slave in &[false, true] {
+            if let Some(identify) = channel.identify(slave) {
+                let name = alloc::format!("hd{}", (b'a' + index as u8 * 2 + slave as u8) as char);
+                crate::info!(
+                    "ata: {} is {}, {} sectors{}",
+                    name,
+                    identify.model(),
+                    identify.sectors,
+                    if identify.lba48 { ", LBA48" } else { "" }
+                );
+                disks.push(Arc::new(AtaDisk {
+                    name,
+                    channel: channel.clone(),
+                    slave,
+                    lba48: identify.lba48,
+                    sectors: identify.sectors,
+                }));
+            }
+        }
+        if disks.is_empty() {
+            return Err(DriverError::Failed("no drives"));
+        }
+        let interrupting = channel.clone();
+        let handler = request_irq(line, move || interrupting.interrupt())?;
+        channel.set_interrupts(true);
+        for disk in &disks {
+            block::register(disk.clone());
+        }
+        self.channels.lock().push(Probed {
+            device: device.id,
+            handler,
+            disks,
+        });
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let probed = {
+            let mut channels = self.channels.lock();
+            match channels
+                .iter()
+                .position(|probed| probed.device == device.id)
+            {
+                Some(index) => channels.remove(index),
+                None => return,
+            }
+        };
+        let disks = probed
+            .disks
+            .iter()
+            .map(|disk| disk.clone() as Arc<dyn BlockDevice>)
+            .collect();
+        // The writes still need the interrupt
+        let handler = probed.handler;
+        block::remove(disks, move || irq::unregister(handler));
+    }
+
+    fn shutdown(&self, device: &Device) {
+        if let Some(channels) = self.channels.try_lock() {
+            let disks = channels
+                .iter()
+                .filter(|probed| probed.device == device.id)
+                .flat_map(|probed| probed.disks.iter());
+            for disk in disks {
+                disk.channel.flush_now(disk);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_identify() {
+    let mut words = [0u16; 256];
+    words[27] = u16::from_be_bytes(*b"QE");
+    words[28] = u16::from_be_bytes(*b"MU");
+    words[60] = 0x1000;
+    words[61] = 0x0001;
+    let identify = Identify::parse(&words);
+    assert!(!identify.lba48);
+    assert_eq!(identify.sectors, 0x1_1000);
+    assert_eq!(&identify.model[..4], b"QEMU");
+
+    words[83] = 1 << 10;
+    words[100] = 0x0000;
+    words[101] = 0x0000;
+    words[102] = 0x0002;
+    let identify = Identify::parse(&words);
+    assert!(identify.lba48);
+    assert_eq!(identify.sectors, 0x2_0000_0000);
+}
@@ -0,0 +1,96 @@
+//!
+//! Drivers of the two ports of the PS/2 controller: keyboard and mouse
+//!
+//! Both share the data port, so each interrupt handler reads one byte and hands it to
+//! its queue in `task`.
+//!
+use super::{request_irq, Bus, Device, Driver, DriverError};
+use crate::irq::{self, HandlerId};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub const KEYBOARD_DEVICE: &str = "ps2-keyboard";
+pub const MOUSE_DEVICE: &str = "ps2-mouse";
+
+const DATA_PORT: u16 = 0x60;
+
+fn read_data() -> u8 {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.read() }
+}
+
+fn is_isa_device(device: &Device, name: &str) -> bool {
+    matches!(device.bus, Bus::Isa) && device.name == name
+}
+
+fn keyboard_interrupt() {
+    crate::task::keyboard::add_scancode(read_data());
+}
+
+fn mouse_interrupt() {
+    crate::task::mouse::add_byte(read_data());
+}
+
+pub struct KeyboardDriver {
+    handler: Mutex<Option<HandlerId>>,
+}
+
+pub static KEYBOARD: KeyboardDriver = KeyboardDriver {
+    handler: Mutex::new(None),
+};
+
+impl Driver for KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "ps2-keyboard"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        is_isa_device(device, KEYBOARD_DEVICE)
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        let line = device.irq().ok_or(DriverError::Unsupported)?;
+        *self.handler.lock() = Some(request_irq(line, keyboard_interrupt)?);
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Device) {
+        if let Some(handler) = self.handler.lock().take() {
+            irq::unregister(handler);
+        }
+    }
+}
+
+pub struct MouseDriver {
+    handler: Mutex<Option<HandlerId>>,
+}
+
+pub static MOUSE: MouseDriver = MouseDriver {
+    handler: Mutex::new(None),
+};
+
+impl Driver for MouseDriver {
+    fn name(&self) -> &'static str {
+        "ps2-mouse"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        is_isa_device(device, MOUSE_DEVICE)
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        use x86_64::instructions::interrupts;
+        let line = device.irq().ok_or(DriverError::Unsupported)?;
+        // The keyboard handler would steal the answers of the mouse
+        interrupts::without_interrupts(crate::task::mouse::init).map_err(DriverError::Failed)?;
+        // Unmasks the line, and the cascade for line 12
+        *self.handler.lock() = Some(request_irq(line, mouse_interrupt)?);
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Device) {
+        if let Some(handler) = self.handler.lock().take() {
+            irq::unregister(handler);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//!
+//! Driver of the CMOS real-time clock
+//!
+//! Probing reads the clock so the kernel knows the wall clock time. The periodic
+//! interrupt stays off until somebody calls `time::rtc::enable_periodic_interrupt`, but
+//! its IRQ 8 handler is installed here.
+//!
+use super::{request_irq, Bus, Device, Driver, DriverError};
+use crate::irq::{self, HandlerId};
+use crate::time::rtc;
+use spin::Mutex;
+
+pub const DEVICE: &str = "rtc";
+
+pub struct RtcDriver {
+    handler: Mutex<Option<HandlerId>>,
+}
+
+pub static RTC: RtcDriver = RtcDriver {
+    handler: Mutex::new(None),
+};
+
+impl Driver for RtcDriver {
+    fn name(&self) -> &'static str {
+        "cmos-rtc"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        matches!(device.bus, Bus::Isa) && device.name == DEVICE
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        let century_register = crate::acpi::get()
+            .and_then(|acpi| acpi.fadt.as_ref())
+            .map_or(0, |fadt| fadt.century_register);
+        let now = rtc::init(century_register);
+        crate::info!("rtc: {} UTC", now);
+        if let Some(line) = device.irq() {
+            *self.handler.lock() = Some(request_irq(line, rtc::acknowledge_interrupt)?);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Device) {
+        rtc::disable_periodic_interrupt();
+        if let Some(handler) = self.handler.lock().take() {
+            irq::unregister(handler);
+        }
+    }
+
+    fn shutdown(&self, _device: &Device) {
+        rtc::disable_periodic_interrupt();
+    }
+}
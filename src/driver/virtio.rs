@@ -0,0 +1,410 @@
+//!
+//! Virtio over PCI
+//!
+//! Virtio devices talk to the driver through a transport, registers for the status,
+//! the features and the queues, and through virtqueues in memory. Two PCI transports
+//! exist: the legacy one, a block of I/O ports in BAR 0, and the modern one, regions of
+//! memory BARs described by vendor specific capabilities. Transitional devices (what
+//! QEMU gives by default) have both, and the legacy one is used since ports need no
+//! mapping. The regions of the modern ones are mapped by `init`, before the drivers probe
+//! them.
+//!
+//! The device drivers are in the submodules.
+//!
+pub mod blk;
+pub mod queue;
+
+use crate::pci::capability::ID_VENDOR_SPECIFIC;
+use crate::pci::{read_u8, Bar, PciAddress, PciDevice};
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use queue::Virtqueue;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+///
+/// Device ids of transitional devices, the modern ones are `0x1040` plus the type
+///
+const TRANSITIONAL_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103f;
+const MODERN_BASE_ID: u16 = 0x1040;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Modern devices refuse drivers that don't accept it
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Set in the ISR status when a queue has new used buffers
+const ISR_QUEUE: u8 = 1;
+
+// Legacy registers, offsets in the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// Where the device configuration starts while MSI-X is off
+const LEGACY_CONFIG: u16 = 0x14;
+
+// Types of the modern capabilities
+const CAPABILITY_COMMON: u8 = 1;
+const CAPABILITY_NOTIFY: u8 = 2;
+const CAPABILITY_ISR: u8 = 3;
+const CAPABILITY_DEVICE: u8 = 4;
+
+// Modern common configuration
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFFSET: usize = 0x1e;
+const COMMON_QUEUE_DESCRIPTORS: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+///
+/// Whether `device` is a virtio device of type `device_type` (2 for block devices)
+///
+pub fn is_virtio(device: &PciDevice, device_type: u16) -> bool {
+    if device.vendor_id != VENDOR_ID {
+        return false;
+    }
+    if TRANSITIONAL_IDS.contains(&device.device_id) {
+        // The subsystem id has the type
+        device.read_config(0x2c) >> 16 == device_type as u32
+    } else {
+        device.device_id == MODERN_BASE_ID + device_type
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ModernRegions {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: VirtAddr,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Legacy { io: u16 },
+    Modern(ModernRegions),
+}
+
+///
+/// Registers of one device, either transport
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Transport {
+    kind: Kind,
+}
+
+unsafe fn read_volatile<T>(address: VirtAddr) -> T {
+    address.as_ptr::<T>().read_volatile()
+}
+
+unsafe fn write_volatile<T>(address: VirtAddr, value: T) {
+    address.as_mut_ptr::<T>().write_volatile(value)
+}
+
+///
+/// Regions of the modern devices, mapped by `init`
+///
+static MODERN: Mutex<Vec<(PciAddress, ModernRegions)>> = Mutex::new(Vec::new());
+
+///
+/// Maps the capability regions of every virtio device that doesn't have the legacy
+/// transport. Call it after `pci::init` and before the drivers are registered
+///
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let devices = crate::pci::devices();
+    let modern = devices
+        .iter()
+        .filter(|device| device.vendor_id == VENDOR_ID)
+        .filter(|device| !Transport::has_legacy(device));
+    for device in modern {
+        match map_regions(device, mapper, frame_allocator) {
+            Ok(regions) => MODERN.lock().push((device.address, regions)),
+            Err(error) => crate::warn!("virtio: {}: {}", device.address, error),
+        }
+    }
+}
+
+fn map_regions(
+    device: &PciDevice,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<ModernRegions, &'static str> {
+    let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+    let mut notify_multiplier = 0;
+    let capabilities = device
+        .capabilities
+        .iter()
+        .filter(|capability| capability.id == ID_VENDOR_SPECIFIC);
+    for capability in capabilities {
+        let kind = read_u8(device.address, capability.offset + 3);
+        let bar = read_u8(device.address, capability.offset + 4) as usize;
+        let bar_offset = device.read_config(capability.offset + 8) as u64;
+        let length = device.read_config(capability.offset + 12) as u64;
+        let physical = match device.bars.get(bar).copied().flatten() {
+            Some(bar) if !bar.is_io() => PhysAddr::new(bar.address() + bar_offset),
+            _ => continue,
+        };
+        let slot = match kind {
+            CAPABILITY_COMMON => &mut common,
+            CAPABILITY_NOTIFY => &mut notify,
+            CAPABILITY_ISR => &mut isr,
+            CAPABILITY_DEVICE => &mut config,
+            _ => continue,
+        };
+        // The first one of each kind is the one to use
+        if slot.is_some() {
+            continue;
+        }
+        let address = crate::memory::map_mmio(physical, length, mapper, frame_allocator)
+            .map_err(|_| "couldn't map the virtio registers")?;
+        *slot = Some(address);
+        if kind == CAPABILITY_NOTIFY {
+            notify_multiplier = device.read_config(capability.offset + 16);
+        }
+    }
+    match (common, notify, isr, config) {
+        (Some(common), Some(notify), Some(isr), Some(device)) => Ok(ModernRegions {
+            common,
+            notify,
+            notify_multiplier,
+            isr,
+            device,
+        }),
+        _ => Err("missing virtio capabilities"),
+    }
+}
+
+impl Transport {
+    ///
+    /// Finds the registers of `device`. The regions of the modern transport have to be
+    /// mapped by `init` already
+    ///
+    pub fn new(device: &PciDevice) -> Result<Self, &'static str> {
+        if let Some(Bar::Io { port, .. }) = device.bars[0] {
+            if TRANSITIONAL_IDS.contains(&device.device_id) {
+                return Ok(Transport {
+                    kind: Kind::Legacy { io: port },
+                });
+            }
+        }
+        let regions = MODERN
+            .lock()
+            .iter()
+            .find(|(address, _)| *address == device.address)
+            .map(|&(_, regions)| regions)
+            .ok_or("virtio registers not mapped")?;
+        Ok(Transport {
+            kind: Kind::Modern(regions),
+        })
+    }
+
+    fn has_legacy(device: &PciDevice) -> bool {
+        matches!(device.bars[0], Some(Bar::Io { .. }))
+            && TRANSITIONAL_IDS.contains(&device.device_id)
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self.kind, Kind::Modern(_))
+    }
+
+    fn status(&self) -> u8 {
+        match self.kind {
+            Kind::Legacy { io } => unsafe { Port::<u8>::new(io + LEGACY_STATUS).read() },
+            Kind::Modern(regions) => unsafe { read_volatile(regions.common + COMMON_STATUS) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self.kind {
+            Kind::Legacy { io } => unsafe { Port::<u8>::new(io + LEGACY_STATUS).write(status) },
+            Kind::Modern(regions) => unsafe {
+                write_volatile(regions.common + COMMON_STATUS, status)
+            },
+        }
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    fn device_features(&self) -> u64 {
+        match self.kind {
+            Kind::Legacy { io } => unsafe {
+                Port::<u32>::new(io + LEGACY_DEVICE_FEATURES).read() as u64
+            },
+            Kind::Modern(regions) => unsafe {
+                let mut features = 0;
+                for half in 0..2u32 {
+                    write_volatile(regions.common + COMMON_DEVICE_FEATURE_SELECT, half);
+                    let bits: u32 = read_volatile(regions.common + COMMON_DEVICE_FEATURE);
+                    features |= (bits as u64) << (32 * half);
+                }
+                features
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self.kind {
+            Kind::Legacy { io } => unsafe {
+                Port::<u32>::new(io + LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Kind::Modern(regions) => unsafe {
+                for half in 0..2u32 {
+                    write_volatile(regions.common + COMMON_DRIVER_FEATURE_SELECT, half);
+                    let bits = (features >> (32 * half)) as u32;
+                    write_volatile(regions.common + COMMON_DRIVER_FEATURE, bits);
+                }
+            },
+        }
+    }
+
+    ///
+    /// Resets the device and agrees on the features: those in `wanted` the device has.
+    /// Returns them
+    ///
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.set_status(0);
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+        let mut wanted = wanted;
+        if self.is_modern() {
+            wanted |= FEATURE_VERSION_1;
+        }
+        let features = self.device_features() & wanted;
+        self.set_driver_features(features);
+        if self.is_modern() {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err("the device didn't accept the features");
+            }
+        }
+        Ok(features)
+    }
+
+    ///
+    /// Sets queue `index` up with at most `max_size` entries, the legacy transport
+    /// only takes the size the device says
+    ///
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<Virtqueue, &'static str> {
+        match self.kind {
+            Kind::Legacy { io } => {
+                let size = unsafe {
+                    Port::<u16>::new(io + LEGACY_QUEUE_SELECT).write(index);
+                    Port::<u16>::new(io + LEGACY_QUEUE_SIZE).read()
+                };
+                if size == 0 {
+                    return Err("the queue doesn't exist");
+                }
+                let queue = Virtqueue::new(index, size, 0)?;
+                let page = queue.descriptors_address().as_u64() >> 12;
+                unsafe { Port::<u32>::new(io + LEGACY_QUEUE_ADDRESS).write(page as u32) };
+                Ok(queue)
+            }
+            Kind::Modern(regions) => unsafe {
+                let common = regions.common;
+                write_volatile(common + COMMON_QUEUE_SELECT, index);
+                let device_size: u16 = read_volatile(common + COMMON_QUEUE_SIZE);
+                if device_size == 0 {
+                    return Err("the queue doesn't exist");
+                }
+                // Sizes are powers of two
+                let size = device_size.min(max_size.next_power_of_two());
+                write_volatile(common + COMMON_QUEUE_SIZE, size);
+                let notify_offset: u16 = read_volatile(common + COMMON_QUEUE_NOTIFY_OFFSET);
+                let queue = Virtqueue::new(index, size, notify_offset)?;
+                let address = |address: PhysAddr| address.as_u64();
+                write_volatile(
+                    common + COMMON_QUEUE_DESCRIPTORS,
+                    address(queue.descriptors_address()),
+                );
+                write_volatile(
+                    common + COMMON_QUEUE_DRIVER,
+                    address(queue.available_address()),
+                );
+                write_volatile(common + COMMON_QUEUE_DEVICE, address(queue.used_address()));
+                write_volatile(common + COMMON_QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            },
+        }
+    }
+
+    ///
+    /// Done setting up, the device can start working
+    ///
+    pub fn ready(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    pub fn reset(&self) {
+        self.set_status(0);
+    }
+
+    ///
+    /// Tells the device there are new buffers in `queue`
+    ///
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        match self.kind {
+            Kind::Legacy { io } => unsafe {
+                Port::<u16>::new(io + LEGACY_QUEUE_NOTIFY).write(queue.index())
+            },
+            Kind::Modern(regions) => unsafe {
+                let offset = queue.notify_offset() as u64 * regions.notify_multiplier as u64;
+                write_volatile(regions.notify + offset, queue.index());
+            },
+        }
+    }
+
+    ///
+    /// Reads and clears the interrupt status. True if a queue has something new
+    ///
+    pub fn acknowledge_interrupt(&self) -> bool {
+        let isr: u8 = match self.kind {
+            Kind::Legacy { io } => unsafe { Port::<u8>::new(io + LEGACY_ISR).read() },
+            Kind::Modern(regions) => unsafe { read_volatile(regions.isr) },
+        };
+        isr & ISR_QUEUE != 0
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match self.kind {
+            Kind::Legacy { io } => unsafe { Port::<u32>::new(io + LEGACY_CONFIG + offset).read() },
+            Kind::Modern(regions) => unsafe { read_volatile(regions.device + offset as u64) },
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        // Two halves, good enough for values that don't change
+        self.read_config_u32(offset) as u64 | (self.read_config_u32(offset + 4) as u64) << 32
+    }
+}
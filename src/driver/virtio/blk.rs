@@ -0,0 +1,285 @@
+//!
+//! Virtio block devices
+//!
+//! Every request is a chain of three parts: a header with the operation and the first
+//! sector, the data and a status byte the device fills in. Requests go through the one
+//! queue of the device and complete with an interrupt, so several tasks can have
+//! requests in flight at the same time. Disks are registered as `vda`, `vdb`, ...
+//!
+use super::queue::{self, Buffer, Virtqueue};
+use super::Transport;
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::driver::{request_shared_irq, Device, DeviceId, Driver, DriverError};
+use crate::irq::{self, HandlerId};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+pub const DEVICE_TYPE: u16 = 2;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const REQUEST_READ: u32 = 0;
+const REQUEST_WRITE: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Capacity in 512 byte sectors, whatever the block size of the device
+const CONFIG_CAPACITY: u16 = 0;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Entries asked for when the transport lets the driver choose
+const QUEUE_SIZE: u16 = 128;
+
+/// Most bytes moved by one request
+const MAX_REQUEST: usize = 64 * 1024;
+
+///
+/// Header and status of a request. Aligned so neither crosses a page
+///
+#[repr(C, align(32))]
+struct Request {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+    status: u8,
+}
+
+const HEADER_SIZE: usize = 16;
+
+pub struct VirtioBlk {
+    name: String,
+    transport: Transport,
+    queue: Mutex<Virtqueue>,
+    sectors: u64,
+    features: u64,
+}
+
+impl VirtioBlk {
+    ///
+    /// Runs in the interrupt handler, which is shared with other devices
+    ///
+    fn interrupt(&self) {
+        if self.transport.acknowledge_interrupt() {
+            self.queue.lock().collect();
+        }
+    }
+
+    ///
+    /// Sends one request with `data`, which the device fills in for reads. The data goes
+    /// through a buffer of its own, it stays with the queue if the caller stops waiting
+    ///
+    async fn request(&self, kind: u32, sector: u64, data: Vec<u8>) -> Result<Vec<u8>, BlockError> {
+        let request = Box::new(Request {
+            kind,
+            reserved: 0,
+            sector,
+            status: 0xff,
+        });
+        let header = VirtAddr::from_ptr(&*request as *const Request);
+        let mut buffers = Vec::new();
+        Buffer::add_range(&mut buffers, header, HEADER_SIZE, false).map_err(BlockError::Io)?;
+        let writable = kind == REQUEST_READ;
+        Buffer::add_range(
+            &mut buffers,
+            VirtAddr::from_ptr(data.as_ptr()),
+            data.len(),
+            writable,
+        )
+        .map_err(BlockError::Io)?;
+        let status = VirtAddr::from_ptr(&request.status as *const u8);
+        Buffer::add_range(&mut buffers, status, 1, true).map_err(BlockError::Io)?;
+        let (_, (request, data)) =
+            queue::submit(&self.transport, &self.queue, &buffers, (request, data))
+                .await
+                .map_err(BlockError::Io)?;
+        // Written by the device behind the compiler's back
+        match unsafe { core::ptr::read_volatile(&request.status) } {
+            STATUS_OK => Ok(data),
+            STATUS_UNSUPPORTED => Err(BlockError::Io("request not supported")),
+            _ => Err(BlockError::Io("device error")),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.features & FEATURE_READ_ONLY != 0
+    }
+
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a mut [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            block::check_range(self, start, buffer.len())?;
+            let mut sector = start;
+            for chunk in buffer.chunks_mut(MAX_REQUEST) {
+                let data = self
+                    .request(REQUEST_READ, sector, alloc::vec![0; chunk.len()])
+                    .await?;
+                chunk.copy_from_slice(&data);
+                sector += (chunk.len() / SECTOR_SIZE) as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            if self.read_only() {
+                return Err(BlockError::ReadOnly);
+            }
+            block::check_range(self, start, buffer.len())?;
+            let mut sector = start;
+            for chunk in buffer.chunks(MAX_REQUEST) {
+                self.request(REQUEST_WRITE, sector, chunk.to_vec()).await?;
+                sector += (chunk.len() / SECTOR_SIZE) as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, Result<(), BlockError>> {
+        Box::pin(async move {
+            // Without the feature the device writes through
+            if self.features & FEATURE_FLUSH == 0 {
+                return Ok(());
+            }
+            self.request(REQUEST_FLUSH, 0, Vec::new()).await?;
+            Ok(())
+        })
+    }
+}
+
+struct Probed {
+    device: DeviceId,
+    handler: HandlerId,
+    disk: Arc<VirtioBlk>,
+}
+
+pub struct VirtioBlkDriver {
+    disks: Mutex<Vec<Probed>>,
+}
+
+pub static VIRTIO_BLK: VirtioBlkDriver = VirtioBlkDriver {
+    disks: Mutex::new(Vec::new()),
+};
+
+static NEXT_DISK: AtomicU8 = AtomicU8::new(0);
+
+impl Driver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        device
+            .pci()
+            .map_or(false, |pci| super::is_virtio(pci, DEVICE_TYPE))
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        let pci = device.pci().ok_or(DriverError::Unsupported)?;
+        let line = device
+            .irq()
+            .ok_or(DriverError::Failed("no legacy interrupt"))?;
+        pci.enable_bus_master();
+        let transport = Transport::new(pci).map_err(DriverError::Failed)?;
+        let features = transport
+            .negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH)
+            .map_err(DriverError::Failed)?;
+        let queue = transport.setup_queue(0, QUEUE_SIZE).map_err(|error| {
+            transport.fail();
+            DriverError::Failed(error)
+        })?;
+        let queue_size = queue.size();
+        let letter = (b'a' + NEXT_DISK.fetch_add(1, Ordering::Relaxed)) as char;
+        let disk = Arc::new(VirtioBlk {
+            name: alloc::format!("vd{}", letter),
+            transport,
+            queue: Mutex::new(queue),
+            sectors: transport.read_config_u64(CONFIG_CAPACITY),
+            features,
+        });
+        let interrupted = disk.clone();
+        let handler =
+            request_shared_irq(line, move || interrupted.interrupt()).map_err(|error| {
+                transport.fail();
+                error
+            })?;
+        transport.ready();
+        crate::info!(
+            "virtio-blk: {} on {}, {} transport, queue of {}",
+            disk.name,
+            pci.address,
+            if transport.is_modern() {
+                "modern"
+            } else {
+                "legacy"
+            },
+            queue_size
+        );
+        block::register(disk.clone());
+        self.disks.lock().push(Probed {
+            device: device.id,
+            handler,
+            disk,
+        });
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let probed = {
+            let mut disks = self.disks.lock();
+            match disks.iter().position(|probed| probed.device == device.id) {
+                Some(index) => disks.remove(index),
+                None => return,
+            }
+        };
+        let disk = probed.disk.clone();
+        let handler = probed.handler;
+        // The device has to be up for the cached blocks to be written
+        block::remove(
+            alloc::vec![probed.disk as Arc<dyn BlockDevice>],
+            move || {
+                disk.transport.reset();
+                irq::unregister(handler);
+            },
+        );
+    }
+
+    fn shutdown(&self, device: &Device) {
+        if let Some(disks) = self.disks.try_lock() {
+            for probed in disks.iter().filter(|probed| probed.device == device.id) {
+                // Stops the device from touching memory while the machine goes down
+                probed.disk.transport.reset();
+            }
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//!
+//! Split virtqueues
+//!
+//! A queue is three rings in memory shared with the device: the descriptors, each
+//! pointing to a buffer and maybe to the next descriptor of its chain, the available
+//! ring where the driver puts the chains it wants processed and the used ring where
+//! the device puts them back. Everything lives in one allocation laid out the way the
+//! legacy transport wants it, which the modern one is happy with too.
+//!
+//! The interrupt handler calls `collect`, which frees the chains the device is done
+//! with and wakes the tasks waiting for them.
+//!
+//! The memory a chain points to belongs to the queue until the device hands the chain
+//! back: `push` takes it with the chain and `take` gives it back. A task that stops
+//! waiting leaves it behind, it's freed once the device is done with it.
+//!
+use super::Transport;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: usize = 4096;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+///
+/// Whatever keeps the memory of a chain alive while the device uses it
+///
+pub type Owned = Box<dyn Any + Send>;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+///
+/// A piece of memory for the device, `writable` if the device writes to it
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    pub writable: bool,
+}
+
+impl Buffer {
+    ///
+    /// Adds the buffers that cover `len` bytes at `start`, one per physically contiguous
+    /// run of pages. They aren't merged with the buffers already there, devices without
+    /// `ANY_LAYOUT` expect each part of a request in its own descriptors
+    ///
+    pub fn add_range(
+        buffers: &mut Vec<Buffer>,
+        start: VirtAddr,
+        len: usize,
+        writable: bool,
+    ) -> Result<(), &'static str> {
+        let first = buffers.len();
+        let mut address = start;
+        let end = start + len;
+        while address < end {
+            let page_end = (address + 1u64).align_up(PAGE_SIZE as u64).min(end);
+            let physical = crate::memory::virt_to_phys(address).ok_or("buffer isn't mapped")?;
+            let len = (page_end - address) as u32;
+            match buffers[first..].last_mut() {
+                Some(last) if last.address + last.len as u64 == physical => last.len += len,
+                _ => buffers.push(Buffer {
+                    address: physical,
+                    len,
+                    writable,
+                }),
+            }
+            address = page_end;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    done: bool,
+    written: u32,
+    waker: Option<Waker>,
+    owned: Option<Owned>,
+    /// Nobody waits for the chain anymore
+    abandoned: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    notify_offset: u16,
+    memory: NonNull<u8>,
+    layout: Layout,
+    physical: PhysAddr,
+    available_offset: usize,
+    used_offset: usize,
+    free: Vec<u16>,
+    next_available: u16,
+    last_used: u16,
+    /// What happened to the chain starting at each descriptor
+    slots: Vec<Slot>,
+    /// Tasks waiting for free descriptors
+    waiting: Vec<Waker>,
+}
+
+// The memory is only reached through the queue, which is behind a lock
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    ///
+    /// Allocates the rings of a queue with `size` entries. The device needs them
+    /// physically contiguous, which fails if the heap pages behind them aren't
+    ///
+    pub fn new(index: u16, size: u16, notify_offset: u16) -> Result<Self, &'static str> {
+        let entries = size as usize;
+        let available_offset = entries * core::mem::size_of::<Descriptor>();
+        let available_len = 6 + 2 * entries;
+        let used_offset = crate::allocator::align_up(available_offset + available_len, PAGE_SIZE);
+        let used_len = 6 + 8 * entries;
+        let total = crate::allocator::align_up(used_offset + used_len, PAGE_SIZE);
+        let layout = Layout::from_size_align(total, PAGE_SIZE).map_err(|_| "bad queue size")?;
+        let memory =
+            NonNull::new(unsafe { alloc_zeroed(layout) }).ok_or("no memory for the queue")?;
+        let start = VirtAddr::from_ptr(memory.as_ptr());
+        let physical = crate::memory::virt_to_phys(start);
+        let contiguous = physical.map_or(false, |physical| {
+            (0..total / PAGE_SIZE).all(|page| {
+                let offset = (page * PAGE_SIZE) as u64;
+                crate::memory::virt_to_phys(start + offset) == Some(physical + offset)
+            })
+        });
+        let physical = match physical {
+            Some(physical) if contiguous => physical,
+            _ => {
+                unsafe { dealloc(memory.as_ptr(), layout) };
+                return Err("the queue memory isn't physically contiguous");
+            }
+        };
+        Ok(Virtqueue {
+            index,
+            size,
+            notify_offset,
+            memory,
+            layout,
+            physical,
+            available_offset,
+            used_offset,
+            free: (0..size).rev().collect(),
+            next_available: 0,
+            last_used: 0,
+            slots: (0..size).map(|_| Slot::default()).collect(),
+            waiting: Vec::new(),
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn notify_offset(&self) -> u16 {
+        self.notify_offset
+    }
+
+    pub fn descriptors_address(&self) -> PhysAddr {
+        self.physical
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.physical + self.available_offset
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.physical + self.used_offset
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { (self.memory.as_ptr() as *mut Descriptor).add(index as usize) }
+    }
+
+    ///
+    /// Flags, index and then the ring, all `u16`
+    ///
+    fn available(&self, field: usize) -> *mut u16 {
+        unsafe { (self.memory.as_ptr().add(self.available_offset) as *mut u16).add(field) }
+    }
+
+    fn used_index(&self) -> u16 {
+        unsafe { (self.memory.as_ptr().add(self.used_offset + 2) as *const u16).read_volatile() }
+    }
+
+    fn used_element(&self, position: u16) -> UsedElement {
+        let slot = (position % self.size) as usize;
+        unsafe {
+            (self.memory.as_ptr().add(self.used_offset + 4) as *const UsedElement)
+                .add(slot)
+                .read_volatile()
+        }
+    }
+
+    ///
+    /// Puts a chain with `buffers` in the available ring and keeps `owned`, which holds
+    /// the memory they point to, until the chain is taken back. Returns the first
+    /// descriptor of the chain, or `owned` if there aren't enough free descriptors now
+    ///
+    pub fn push(&mut self, buffers: &[Buffer], owned: Owned) -> Result<u16, Owned> {
+        self.reclaim();
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return Err(owned);
+        }
+        let ids: Vec<u16> = (0..buffers.len()).filter_map(|_| self.free.pop()).collect();
+        for (i, (buffer, &id)) in buffers.iter().zip(ids.iter()).enumerate() {
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            let next = match ids.get(i + 1) {
+                Some(&next) => {
+                    flags |= DESCRIPTOR_NEXT;
+                    next
+                }
+                None => 0,
+            };
+            let descriptor = Descriptor {
+                address: buffer.address.as_u64(),
+                len: buffer.len,
+                flags,
+                next,
+            };
+            unsafe { self.descriptor(id).write_volatile(descriptor) };
+        }
+        let head = ids[0];
+        self.slots[head as usize] = Slot {
+            owned: Some(owned),
+            ..Slot::default()
+        };
+        let position = 2 + (self.next_available % self.size) as usize;
+        unsafe { self.available(position).write_volatile(head) };
+        self.next_available = self.next_available.wrapping_add(1);
+        // The device must see the entry before the new index
+        fence(Ordering::SeqCst);
+        unsafe { self.available(1).write_volatile(self.next_available) };
+        Ok(head)
+    }
+
+    ///
+    /// Takes what the device put in the used ring: frees the chains and wakes whoever
+    /// waits for them. The first descriptor of a chain stays taken until the chain is
+    /// taken back, it's what tells the chain apart
+    ///
+    pub fn collect(&mut self) {
+        fence(Ordering::SeqCst);
+        let used = self.used_index();
+        let mut freed = false;
+        while self.last_used != used {
+            let element = self.used_element(self.last_used);
+            self.last_used = self.last_used.wrapping_add(1);
+            let head = element.id as u16;
+            if head >= self.size || self.slots[head as usize].owned.is_none() {
+                continue;
+            }
+            let mut id = head;
+            loop {
+                let descriptor = unsafe { self.descriptor(id).read_volatile() };
+                if id != head {
+                    self.free.push(id);
+                }
+                if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                    break;
+                }
+                id = descriptor.next;
+            }
+            freed = true;
+            let slot = &mut self.slots[head as usize];
+            slot.done = true;
+            slot.written = element.len;
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+        if freed {
+            for waker in self.waiting.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    ///
+    /// How many bytes the device wrote in the chain starting at `head` and the memory
+    /// it kept, once the device is done with it
+    ///
+    pub fn take(&mut self, head: u16) -> Option<(u32, Owned)> {
+        let slot = self.slots.get_mut(head as usize)?;
+        if !slot.done || slot.abandoned {
+            return None;
+        }
+        let slot = core::mem::take(slot);
+        self.free.push(head);
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+        slot.owned.map(|owned| (slot.written, owned))
+    }
+
+    ///
+    /// Nobody waits for the chain starting at `head` anymore. What it keeps is freed
+    /// when the device is done with it
+    ///
+    pub fn abandon(&mut self, head: u16) {
+        if let Some(slot) = self.slots.get_mut(head as usize) {
+            slot.abandoned = true;
+            slot.waker = None;
+        }
+        self.reclaim();
+    }
+
+    ///
+    /// Frees the abandoned chains the device is done with. `collect` can't, it runs in
+    /// the interrupt handler and the heap may be locked under it
+    ///
+    fn reclaim(&mut self) {
+        let mut freed = false;
+        for head in 0..self.size {
+            let slot = &mut self.slots[head as usize];
+            if slot.abandoned && slot.done {
+                *slot = Slot::default();
+                self.free.push(head);
+                freed = true;
+            }
+        }
+        if freed {
+            for waker in self.waiting.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    fn poll_done(&mut self, head: u16, waker: &Waker) -> Poll<(u32, Owned)> {
+        match self.take(head) {
+            Some(done) => Poll::Ready(done),
+            None => {
+                self.slots[head as usize].waker = Some(waker.clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory.as_ptr(), self.layout) };
+    }
+}
+
+///
+/// Hands `buffers` to the device and waits until it's done with them. Returns how many
+/// bytes the device wrote and `owned` back.
+///
+/// `buffers` point into memory `owned` keeps alive, not into `owned` itself, which moves.
+/// The queue holds it while the device has the buffers, so the future can be dropped
+///
+pub async fn submit<T: Any + Send>(
+    transport: &Transport,
+    queue: &Mutex<Virtqueue>,
+    buffers: &[Buffer],
+    owned: T,
+) -> Result<(u32, T), &'static str> {
+    // The interrupt handler takes the queue too
+    if buffers.len() > without_interrupts(|| queue.lock().size()) as usize {
+        return Err("too many buffers for the queue");
+    }
+    let mut owned: Option<Owned> = Some(Box::new(owned));
+    let head = poll_fn(|cx| {
+        without_interrupts(|| {
+            let mut queue = queue.lock();
+            let pushed = queue.push(buffers, owned.take().expect("already pushed"));
+            match pushed {
+                Ok(head) => {
+                    transport.notify(&queue);
+                    Poll::Ready(head)
+                }
+                Err(back) => {
+                    owned = Some(back);
+                    queue.waiting.push(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    })
+    .await;
+    let in_flight = InFlight { queue, head };
+    let (written, owned) =
+        poll_fn(|cx| without_interrupts(|| queue.lock().poll_done(head, cx.waker()))).await;
+    core::mem::forget(in_flight);
+    let owned = owned
+        .downcast::<T>()
+        .expect("the queue gave back something else");
+    Ok((written, *owned))
+}
+
+///
+/// A chain the device has. Abandoned if the future waiting for it is dropped
+///
+struct InFlight<'a> {
+    queue: &'a Mutex<Virtqueue>,
+    head: u16,
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        without_interrupts(|| self.queue.lock().abandon(self.head));
+    }
+}
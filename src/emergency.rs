@@ -0,0 +1,137 @@
+//!
+//! Output path for exception handlers and panics.
+//!
+//! `println!` and `serial_println!` spin on `WRITER` and `SERIAL1`, so if an exception
+//! (or an NMI) fires while the interrupted code holds one of them the handler deadlocks.
+//! Everything here only ever uses `try_lock`: when the VGA writer is busy the output
+//! is skipped on screen, and when the serial port is busy the bytes are pushed to
+//! the UART directly. Once `enter_panic_mode` has been called both locks are forced
+//! open so the panic message always gets out.
+//!
+use crate::serial::{RawSerial, SERIAL1};
+use crate::vga_buffer::{Writer, WRITER};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+///
+/// The writer of the virtual terminal being shown, so the report is visible
+///
+fn on_screen_writer() -> &'static Mutex<Writer> {
+    match crate::vt::active() {
+        Some(terminal) => terminal.writer(),
+        None => &WRITER,
+    }
+}
+
+///
+/// Forcibly releases `WRITER` and `SERIAL1` and makes every later emergency print
+/// steal the locks if needed.
+///
+/// Whoever held the locks is never going to run again, so this is only meant
+/// to be called from panic handlers
+///
+pub fn enter_panic_mode() {
+    x86_64::instructions::interrupts::disable();
+    PANICKING.store(true, Ordering::SeqCst);
+    unsafe {
+        on_screen_writer().force_unlock();
+        crate::framebuffer::console::CONSOLE.force_unlock();
+        SERIAL1.force_unlock();
+    }
+}
+
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+///
+/// Writes to the terminal on screen only if it can be done without waiting
+///
+pub(crate) fn try_print_vga(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if crate::framebuffer::console::is_active() {
+        return crate::framebuffer::console::try_print(args);
+    }
+    let writer = on_screen_writer();
+    if is_panicking() {
+        unsafe { writer.force_unlock() };
+    }
+    if let Some(mut writer) = writer.try_lock() {
+        let _ = writer.write_fmt(args);
+    }
+}
+
+///
+/// Writes to the serial port, going around `SERIAL1` when it's locked
+///
+pub(crate) fn try_print_serial(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if is_panicking() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    match SERIAL1.try_lock() {
+        Some(mut serial) => {
+            let _ = serial.write_fmt(args);
+        }
+        None => {
+            let _ = RawSerial.write_fmt(args);
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        try_print_vga(args);
+        try_print_serial(args);
+    });
+}
+
+/// Prints to both the VGA buffer and the serial port without ever blocking
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::emergency::_print(format_args!($($arg)*)));
+}
+
+/// Like `emergency_print!`, appending a newline
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($($arg:tt)*) => ($crate::emergency_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[test_case]
+fn test_emergency_print_while_serial_locked() {
+    use x86_64::instructions::interrupts;
+    let text = "test_emergency_print_while_serial_locked output";
+    interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        // On a line of its own, so it isn't wrapped
+        emergency_println!();
+        emergency_print!("{}", text);
+        let writer = on_screen_writer().lock();
+        let (row, column) = writer.position();
+        for (i, byte) in text.bytes().enumerate() {
+            assert_eq!(writer.read_at(row, column - text.len() + i).0, byte);
+        }
+    });
+    emergency_println!();
+}
+
+#[test_case]
+fn test_emergency_print_while_writer_locked() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let writer = on_screen_writer().lock();
+        let _serial = SERIAL1.lock();
+        let position = writer.position();
+        // would deadlock with println!
+        emergency_println!("test_emergency_print_while_writer_locked output");
+        // Skipped on screen, the serial port still gets it
+        assert_eq!(writer.position(), position);
+    });
+}
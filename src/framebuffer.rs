@@ -0,0 +1,300 @@
+//!
+//! Linear framebuffer graphics
+//!
+//! Two ways of getting pixels on the screen are supported: the Bochs/QEMU `-vga std`
+//! adapter (BGA), which gives us a 32 bits per pixel linear framebuffer at any resolution,
+//! and the standard VGA mode 13h (320x200, 256 colors) as a fallback for real VGA hardware.
+//!
+pub mod bga;
+pub mod console;
+pub mod font;
+pub mod mode13h;
+
+use crate::vga_buffer::Color;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    ///
+    /// Color of the VGA text mode palette
+    ///
+    pub fn from_vga(color: Color) -> Self {
+        match color {
+            Color::Black => Rgb::new(0x00, 0x00, 0x00),
+            Color::Blue => Rgb::new(0x00, 0x00, 0xaa),
+            Color::Green => Rgb::new(0x00, 0xaa, 0x00),
+            Color::Cyan => Rgb::new(0x00, 0xaa, 0xaa),
+            Color::Red => Rgb::new(0xaa, 0x00, 0x00),
+            Color::Magenta => Rgb::new(0xaa, 0x00, 0xaa),
+            Color::Brown => Rgb::new(0xaa, 0x55, 0x00),
+            Color::LightGray => Rgb::new(0xaa, 0xaa, 0xaa),
+            Color::DarkGray => Rgb::new(0x55, 0x55, 0x55),
+            Color::LightBlue => Rgb::new(0x55, 0x55, 0xff),
+            Color::LightGreen => Rgb::new(0x55, 0xff, 0x55),
+            Color::LightCyan => Rgb::new(0x55, 0xff, 0xff),
+            Color::LightRed => Rgb::new(0xff, 0x55, 0x55),
+            Color::Pink => Rgb::new(0xff, 0x55, 0xff),
+            Color::Yellow => Rgb::new(0xff, 0xff, 0x55),
+            Color::White => Rgb::new(0xff, 0xff, 0xff),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    ///
+    /// One byte per pixel, index into a 3-3-2 RGB palette (see `mode13h`)
+    ///
+    Indexed8,
+    ///
+    /// Four bytes per pixel: blue, green, red and an unused byte
+    ///
+    Bgr32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Indexed8 => 1,
+            PixelFormat::Bgr32 => 4,
+        }
+    }
+}
+
+///
+/// A mapped linear framebuffer
+///
+pub struct Framebuffer {
+    base: *mut u8,
+    width: usize,
+    height: usize,
+    /// Bytes between the start of two consecutive lines
+    pitch: usize,
+    format: PixelFormat,
+}
+
+// The framebuffer memory is only ever accessed through the owner of this struct
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    ///
+    /// Unsafe because `base` must point to mapped video memory of at least
+    /// `pitch * height` bytes that nobody else uses
+    ///
+    pub unsafe fn new(
+        base: VirtAddr,
+        width: usize,
+        height: usize,
+        pitch: usize,
+        format: PixelFormat,
+    ) -> Self {
+        Self {
+            base: base.as_mut_ptr(),
+            width,
+            height,
+            pitch,
+            format,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    ///
+    /// Converts a color to the value stored in memory for this pixel format
+    ///
+    pub fn encode(&self, color: Rgb) -> u32 {
+        match self.format {
+            PixelFormat::Indexed8 => (color.r & 0xe0 | (color.g >> 3) & 0x1c | color.b >> 6) as u32,
+            PixelFormat::Bgr32 => (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32,
+        }
+    }
+
+    pub fn decode(&self, raw: u32) -> Rgb {
+        match self.format {
+            PixelFormat::Indexed8 => {
+                let raw = raw as u8;
+                Rgb::new(raw & 0xe0, (raw & 0x1c) << 3, (raw & 0x03) << 6)
+            }
+            PixelFormat::Bgr32 => Rgb::new((raw >> 16) as u8, (raw >> 8) as u8, raw as u8),
+        }
+    }
+
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u8 {
+        let offset = y * self.pitch + x * self.format.bytes_per_pixel();
+        unsafe { self.base.add(offset) }
+    }
+
+    ///
+    /// Writes an already encoded pixel. Out of bounds pixels are ignored
+    ///
+    pub fn put_raw(&mut self, x: usize, y: usize, raw: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let ptr = self.pixel_ptr(x, y);
+        unsafe {
+            match self.format {
+                PixelFormat::Indexed8 => ptr.write_volatile(raw as u8),
+                PixelFormat::Bgr32 => (ptr as *mut u32).write_volatile(raw),
+            }
+        }
+    }
+
+    pub fn get_raw(&self, x: usize, y: usize) -> u32 {
+        if x >= self.width || y >= self.height {
+            return 0;
+        }
+        let ptr = self.pixel_ptr(x, y);
+        unsafe {
+            match self.format {
+                PixelFormat::Indexed8 => ptr.read_volatile() as u32,
+                PixelFormat::Bgr32 => (ptr as *const u32).read_volatile(),
+            }
+        }
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        let raw = self.encode(color);
+        self.put_raw(x, y, raw);
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Rgb {
+        self.decode(self.get_raw(x, y))
+    }
+
+    ///
+    /// Fills a rectangle, clipped to the screen
+    ///
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let raw = self.encode(color);
+        let x_end = (x + width).min(self.width);
+        let y_end = (y + height).min(self.height);
+        for py in y..y_end {
+            for px in x..x_end {
+                self.put_raw(px, py, raw);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    ///
+    /// Moves the whole picture `lines` pixels up, filling the bottom with `fill`
+    ///
+    pub fn scroll_up(&mut self, lines: usize, fill: Rgb) {
+        let lines = lines.min(self.height);
+        let moved = (self.height - lines) * self.pitch;
+        unsafe {
+            core::ptr::copy(self.base.add(lines * self.pitch), self.base, moved);
+        }
+        self.fill_rect(0, self.height - lines, self.width, lines, fill);
+    }
+
+    ///
+    /// Copies raw bytes into line `y` starting at pixel `x`, used to blit whole rows
+    ///
+    pub fn write_row_bytes(&mut self, x: usize, y: usize, bytes: &[u8]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let max = (self.width - x) * self.format.bytes_per_pixel();
+        let len = bytes.len().min(max);
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.pixel_ptr(x, y), len);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Which display the kernel console is going to use
+///
+pub enum ConsoleKind {
+    /// The 80x25 VGA text mode, see `vga_buffer`
+    Text,
+    /// A `console::FramebufferConsole` on the best graphics mode available
+    Graphics,
+}
+
+pub const DEFAULT_WIDTH: usize = 1024;
+pub const DEFAULT_HEIGHT: usize = 768;
+
+///
+/// Switches to a graphics mode (BGA if available, mode 13h otherwise) and returns the framebuffer
+///
+pub fn init(
+    physical_memory_offset: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Framebuffer, &'static str> {
+    if bga::is_available() {
+        // The framebuffer is BAR 0 of the adaptor, if the PCI bus was scanned
+        let pci_match = crate::pci::PciMatch::device(bga::PCI_VENDOR_ID, bga::PCI_DEVICE_ID);
+        let lfb_address = crate::pci::find(&pci_match)
+            .first()
+            .and_then(|device| device.bars[0])
+            .map_or(bga::QEMU_LFB_ADDRESS, |bar| bar.address());
+        bga::init(
+            DEFAULT_WIDTH,
+            DEFAULT_HEIGHT,
+            PhysAddr::new(lfb_address),
+            mapper,
+            frame_allocator,
+        )
+    } else {
+        Ok(mode13h::init(physical_memory_offset))
+    }
+}
+
+///
+/// Sets up the console selected at boot. With `ConsoleKind::Graphics` every `print!`
+/// goes to a framebuffer console from now on
+///
+pub fn init_console(
+    kind: ConsoleKind,
+    physical_memory_offset: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    match kind {
+        ConsoleKind::Text => Ok(()),
+        ConsoleKind::Graphics => {
+            let framebuffer = init(physical_memory_offset, mapper, frame_allocator)?;
+            console::install(console::FramebufferConsole::new(
+                framebuffer,
+                font::builtin(),
+            ));
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//!
+//! Bochs Graphics Adaptor, the display of QEMU `-vga std` and Bochs
+//!
+//! Everything is programmed through an index/data pair of I/O ports, and the
+//! framebuffer is linear memory at the address of BAR 0 of the PCI device `1234:1111`.
+//!
+use super::{Framebuffer, PixelFormat};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+const INDEX_PORT: u16 = 0x01ce;
+const DATA_PORT: u16 = 0x01cf;
+
+const INDEX_ID: u16 = 0;
+const INDEX_XRES: u16 = 1;
+const INDEX_YRES: u16 = 2;
+const INDEX_BPP: u16 = 3;
+const INDEX_ENABLE: u16 = 4;
+const INDEX_VIRT_WIDTH: u16 = 6;
+const INDEX_X_OFFSET: u16 = 8;
+const INDEX_Y_OFFSET: u16 = 9;
+
+const ENABLED: u16 = 0x01;
+const LFB_ENABLED: u16 = 0x40;
+
+/// First and last versions of the interface, older ones don't have a linear framebuffer
+const ID_MIN: u16 = 0xb0c2;
+const ID_MAX: u16 = 0xb0c5;
+
+///
+/// Where QEMU puts the framebuffer BAR by default. The real address is in BAR 0
+/// of the PCI device
+///
+pub const QEMU_LFB_ADDRESS: u64 = 0xfd00_0000;
+
+pub const PCI_VENDOR_ID: u16 = 0x1234;
+pub const PCI_DEVICE_ID: u16 = 0x1111;
+
+fn write_register(index: u16, value: u16) {
+    let mut index_port: Port<u16> = Port::new(INDEX_PORT);
+    let mut data_port: Port<u16> = Port::new(DATA_PORT);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+fn read_register(index: u16) -> u16 {
+    let mut index_port: Port<u16> = Port::new(INDEX_PORT);
+    let mut data_port: Port<u16> = Port::new(DATA_PORT);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+pub fn is_available() -> bool {
+    let id = read_register(INDEX_ID);
+    (ID_MIN..=ID_MAX).contains(&id)
+}
+
+///
+/// Sets the resolution, 32 bits per pixel, and maps the framebuffer at `lfb_address`
+///
+pub fn init(
+    width: usize,
+    height: usize,
+    lfb_address: PhysAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Framebuffer, &'static str> {
+    if !is_available() {
+        return Err("no Bochs graphics adaptor");
+    }
+    // The mode can only be changed while the display is disabled
+    write_register(INDEX_ENABLE, 0);
+    write_register(INDEX_XRES, width as u16);
+    write_register(INDEX_YRES, height as u16);
+    write_register(INDEX_BPP, 32);
+    write_register(INDEX_VIRT_WIDTH, width as u16);
+    write_register(INDEX_X_OFFSET, 0);
+    write_register(INDEX_Y_OFFSET, 0);
+    write_register(INDEX_ENABLE, ENABLED | LFB_ENABLED);
+    if read_register(INDEX_XRES) as usize != width || read_register(INDEX_YRES) as usize != height {
+        return Err("resolution not supported by the adaptor");
+    }
+
+    let pitch = width * PixelFormat::Bgr32.bytes_per_pixel();
+    let size = (pitch * height) as u64;
+    let base = crate::memory::map_mmio(lfb_address, size, mapper, frame_allocator)
+        .map_err(|_| "couldn't map the framebuffer")?;
+    Ok(unsafe { Framebuffer::new(base, width, height, pitch, PixelFormat::Bgr32) })
+}
@@ -0,0 +1,344 @@
+//!
+//! Text console drawn on a framebuffer
+//!
+//! It understands the same input as `vga_buffer::Writer`: ANSI escape sequences are
+//! handled by `vga_buffer::ansi` and non-ASCII characters go through `vga_buffer::cp437`,
+//! so the font has to be laid out in code page 437 order like the built-in one.
+//!
+//! Once a console is installed, `print!`, the VGA log sink and the panic output are
+//! drawn here instead of the text mode buffer. Whatever is written to the active virtual
+//! terminal is drawn here too; the other terminals keep writing to their text buffers.
+//!
+use super::font::PsfFont;
+use super::{Framebuffer, Rgb};
+use crate::vga_buffer::ansi::{self, Action};
+use crate::vga_buffer::{cp437, Color};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const DEFAULT_FOREGROUND: Color = Color::Yellow;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+
+///
+/// Bright variant of a color, what the `bold` SGR does to the foreground
+///
+fn brightened(color: Color) -> Color {
+    match color {
+        Color::Black => Color::DarkGray,
+        Color::Blue => Color::LightBlue,
+        Color::Green => Color::LightGreen,
+        Color::Cyan => Color::LightCyan,
+        Color::Red => Color::LightRed,
+        Color::Magenta => Color::Pink,
+        Color::Brown => Color::Yellow,
+        Color::LightGray => Color::White,
+        bright => bright,
+    }
+}
+
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    font: PsfFont,
+    column: usize,
+    row: usize,
+    columns: usize,
+    rows: usize,
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    /// Foreground from before bold, put back by SGR 22
+    unbold_foreground: Color,
+    saved_position: (usize, usize),
+    ansi: ansi::Parser,
+}
+
+impl FramebufferConsole {
+    pub fn new(framebuffer: Framebuffer, font: PsfFont) -> Self {
+        let columns = framebuffer.width() / font.width();
+        let rows = framebuffer.height() / font.height();
+        let mut console = Self {
+            framebuffer,
+            font,
+            column: 0,
+            row: 0,
+            columns,
+            rows,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            unbold_foreground: DEFAULT_FOREGROUND,
+            saved_position: (0, 0),
+            ansi: ansi::Parser::new(),
+        };
+        console.clear_screen();
+        console
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    ///
+    /// Returns `(row, column)` of the next character to be written
+    ///
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    pub fn framebuffer(&mut self) -> &mut Framebuffer {
+        &mut self.framebuffer
+    }
+
+    pub fn clear_screen(&mut self) {
+        self.framebuffer.clear(Rgb::from_vga(self.background));
+        self.row = 0;
+        self.column = 0;
+    }
+
+    pub fn write_string(&mut self, string: &str) {
+        for character in string.chars() {
+            if character.is_ascii() {
+                if let Some(action) = self.ansi.advance(character as u8) {
+                    self.apply(action);
+                }
+            } else {
+                self.write_glyph(cp437::from_char_or_placeholder(character));
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            byte => self.write_glyph(byte),
+        }
+    }
+
+    ///
+    /// Draws the glyph `byte` even if it's a control character, `0x0a` is `◙` here
+    ///
+    fn write_glyph(&mut self, byte: u8) {
+        if self.column >= self.columns {
+            self.new_line();
+        }
+        self.draw_glyph(self.row, self.column, byte);
+        self.column += 1;
+    }
+
+    ///
+    /// Draws glyph `byte` on a cell with the current colors
+    ///
+    fn draw_glyph(&mut self, row: usize, column: usize, byte: u8) {
+        let foreground = self.framebuffer.encode(Rgb::from_vga(self.foreground));
+        let background = self.framebuffer.encode(Rgb::from_vga(self.background));
+        let glyph = self.font.glyph(byte as usize);
+        let (x, y) = (column * self.font.width(), row * self.font.height());
+        for glyph_y in 0..self.font.height() {
+            for glyph_x in 0..self.font.width() {
+                let raw = if self.font.is_set(glyph, glyph_x, glyph_y) {
+                    foreground
+                } else {
+                    background
+                };
+                self.framebuffer.put_raw(x + glyph_x, y + glyph_y, raw);
+            }
+        }
+    }
+
+    ///
+    /// Clears `height` rows and `width` columns starting at the given cell with the
+    /// current background. The region is clipped to the screen
+    ///
+    pub fn clear_region(&mut self, row: usize, column: usize, height: usize, width: usize) {
+        let (font_width, font_height) = (self.font.width(), self.font.height());
+        let height = height.min(self.rows.saturating_sub(row));
+        let width = width.min(self.columns.saturating_sub(column));
+        self.framebuffer.fill_rect(
+            column * font_width,
+            row * font_height,
+            width * font_width,
+            height * font_height,
+            Rgb::from_vga(self.background),
+        );
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row < self.rows - 1 {
+            self.row += 1;
+            return;
+        }
+        let font_height = self.font.height();
+        self.framebuffer
+            .scroll_up(font_height, Rgb::from_vga(DEFAULT_BACKGROUND));
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Print(byte) => match byte {
+                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                b'\r' => self.column = 0,
+                // backspace
+                0x08 => self.column = self.column.saturating_sub(1),
+                b'\t' => {
+                    let next_stop = (self.column / 8 + 1) * 8;
+                    while self.column < next_stop.min(self.columns) {
+                        self.write_byte(b' ');
+                    }
+                }
+                _ => self.write_byte(cp437::PLACEHOLDER),
+            },
+            Action::CursorUp(n) => self.row = self.row.saturating_sub(n as usize),
+            Action::CursorDown(n) => self.row = (self.row + n as usize).min(self.rows - 1),
+            Action::CursorForward(n) => {
+                self.column = (self.column + n as usize).min(self.columns - 1)
+            }
+            Action::CursorBack(n) => self.column = self.column.saturating_sub(n as usize),
+            Action::CursorPosition(row, column) => {
+                self.row = (row as usize).min(self.rows - 1);
+                self.column = (column as usize).min(self.columns - 1);
+            }
+            Action::EraseDisplay(mode) => {
+                let (row, column) = (self.row, self.column);
+                match mode {
+                    0 => {
+                        self.clear_region(row, column, 1, self.columns);
+                        self.clear_region(row + 1, 0, self.rows, self.columns);
+                    }
+                    1 => {
+                        self.clear_region(0, 0, row, self.columns);
+                        self.clear_region(row, 0, 1, column + 1);
+                    }
+                    _ => self.clear_region(0, 0, self.rows, self.columns),
+                }
+            }
+            Action::EraseLine(mode) => {
+                let (row, column) = (self.row, self.column);
+                match mode {
+                    0 => self.clear_region(row, column, 1, self.columns),
+                    1 => self.clear_region(row, 0, 1, column + 1),
+                    _ => self.clear_region(row, 0, 1, self.columns),
+                }
+            }
+            Action::SelectGraphicRendition(params) => self.select_graphic_rendition(params),
+            Action::SaveCursor => self.saved_position = (self.row, self.column),
+            Action::RestoreCursor => {
+                let (row, column) = self.saved_position;
+                self.row = row;
+                self.column = column;
+            }
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: ansi::Params) {
+        if params.as_slice().is_empty() {
+            self.reset_graphic_rendition();
+        }
+        for &param in params.as_slice() {
+            match param {
+                0 => self.reset_graphic_rendition(),
+                1 => {
+                    if !self.bold {
+                        self.unbold_foreground = self.foreground;
+                    }
+                    self.bold = true;
+                    self.foreground = brightened(self.foreground);
+                }
+                22 => {
+                    if self.bold {
+                        self.foreground = self.unbold_foreground;
+                    }
+                    self.bold = false;
+                }
+                7 => core::mem::swap(&mut self.foreground, &mut self.background),
+                30..=37 => {
+                    self.foreground = ansi::ansi_color(param - 30, self.bold);
+                    self.unbold_foreground = ansi::ansi_color(param - 30, false);
+                }
+                90..=97 => {
+                    self.foreground = ansi::ansi_color(param - 90, true);
+                    self.unbold_foreground = self.foreground;
+                }
+                39 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.unbold_foreground = DEFAULT_FOREGROUND;
+                }
+                40..=47 => self.background = ansi::ansi_color(param - 40, false),
+                100..=107 => self.background = ansi::ansi_color(param - 100, true),
+                49 => self.background = DEFAULT_BACKGROUND,
+                _ => {}
+            }
+        }
+    }
+
+    fn reset_graphic_rendition(&mut self) {
+        self.foreground = DEFAULT_FOREGROUND;
+        self.background = DEFAULT_BACKGROUND;
+        self.bold = false;
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+pub static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+///
+/// Makes `console` the kernel console
+///
+pub fn install(console: FramebufferConsole) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        *CONSOLE.lock() = Some(console);
+        ACTIVE.store(true, Ordering::SeqCst);
+    });
+}
+
+///
+/// Whether the kernel output goes to a framebuffer console instead of the text mode
+///
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+///
+/// Writes to the console only if it can be done without waiting, see `emergency`
+///
+pub(crate) fn try_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if crate::emergency::is_panicking() {
+        unsafe { CONSOLE.force_unlock() };
+    }
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if let Some(console) = console.as_mut() {
+            let _ = console.write_fmt(args);
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            let _ = console.write_fmt(args);
+        }
+    });
+}
@@ -0,0 +1,137 @@
+//!
+//! PC Screen Font (PSF) bitmap fonts, the format used by the Linux console
+//!
+//! Both versions are supported. Glyphs are looked up by index, the built-in font has
+//! its 256 glyphs in code page 437 order so the bytes produced by `vga_buffer::cp437`
+//! can be drawn directly. Unicode tables in the file are ignored.
+//!
+use core::convert::TryInto;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_HEADER_SIZE: usize = 4;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+
+///
+/// 8x16 font derived from DejaVu Sans Mono, laid out as code page 437. Under the
+/// Bitstream Vera license, the text is in `LICENSE-DejaVu.txt` next to it
+///
+static BUILTIN: &[u8] = include_bytes!("default8x16.psf");
+
+#[derive(Debug, Clone, Copy)]
+pub struct PsfFont {
+    data: &'static [u8],
+    glyphs_offset: usize,
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+impl PsfFont {
+    ///
+    /// Parses a PSF1 or PSF2 font, returning `None` if the header is invalid or
+    /// the data is too short for the glyphs it announces
+    ///
+    pub fn parse(data: &'static [u8]) -> Option<Self> {
+        let font = if data.starts_with(&PSF1_MAGIC) {
+            let mode = *data.get(2)?;
+            let height = *data.get(3)? as usize;
+            Self {
+                data,
+                glyphs_offset: PSF1_HEADER_SIZE,
+                glyph_count: if mode & PSF1_MODE_512 != 0 { 512 } else { 256 },
+                bytes_per_glyph: height,
+                width: 8,
+                height,
+            }
+        } else if data.starts_with(&PSF2_MAGIC) {
+            let header_size = read_u32(data, 8)? as usize;
+            if header_size < PSF2_HEADER_SIZE {
+                return None;
+            }
+            Self {
+                data,
+                glyphs_offset: header_size,
+                glyph_count: read_u32(data, 16)? as usize,
+                bytes_per_glyph: read_u32(data, 20)? as usize,
+                height: read_u32(data, 24)? as usize,
+                width: read_u32(data, 28)? as usize,
+            }
+        } else {
+            return None;
+        };
+        if font.width == 0
+            || font.height == 0
+            || font.bytes_per_glyph < font.bytes_per_row() * font.height
+        {
+            return None;
+        }
+        let end = font.glyphs_offset + font.glyph_count * font.bytes_per_glyph;
+        if end > data.len() {
+            return None;
+        }
+        Some(font)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    ///
+    /// Rows are padded to a whole number of bytes, most significant bit first
+    ///
+    pub fn bytes_per_row(&self) -> usize {
+        (self.width + 7) / 8
+    }
+
+    ///
+    /// Bitmap of glyph `index`, falling back to glyph 0 when it doesn't exist
+    ///
+    pub fn glyph(&self, index: usize) -> &'static [u8] {
+        let index = if index < self.glyph_count { index } else { 0 };
+        let start = self.glyphs_offset + index * self.bytes_per_glyph;
+        &self.data[start..start + self.bytes_per_glyph]
+    }
+
+    ///
+    /// Whether pixel `(x, y)` of a glyph bitmap is set
+    ///
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let byte = glyph[y * self.bytes_per_row() + x / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+///
+/// The font compiled into the kernel
+///
+pub fn builtin() -> PsfFont {
+    PsfFont::parse(BUILTIN).expect("the built-in font is a valid PSF file")
+}
+
+#[test_case]
+fn test_builtin_font() {
+    let font = builtin();
+    assert_eq!((font.width(), font.height()), (8, 16));
+    assert_eq!(font.glyph_count(), 256);
+    // A space is empty and a full block is full
+    assert!(font.glyph(b' ' as usize).iter().all(|&row| row == 0));
+    assert!(font.glyph(0xdb).iter().all(|&row| row == 0xff));
+    assert!(PsfFont::parse(&[0, 1, 2, 3]).is_none());
+}
@@ -0,0 +1,114 @@
+//!
+//! VGA mode 13h: 320x200 pixels, one byte per pixel, framebuffer at 0xa0000
+//!
+//! The mode is set by writing the register values the BIOS would use, since we can't
+//! call `int 0x10` from long mode. The palette is reprogrammed as 3-3-2 RGB so pixels
+//! can be encoded without looking colors up (see `PixelFormat::Indexed8`).
+//!
+//! Switching back to text mode is not supported: the font in plane 2 gets overwritten.
+//!
+use super::{Framebuffer, PixelFormat};
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 200;
+const FRAMEBUFFER_ADDRESS: u64 = 0xa0000;
+
+const MISC_OUTPUT_PORT: u16 = 0x3c2;
+const SEQUENCER_INDEX_PORT: u16 = 0x3c4;
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const GRAPHICS_INDEX_PORT: u16 = 0x3ce;
+const ATTRIBUTE_PORT: u16 = 0x3c0;
+const INPUT_STATUS_PORT: u16 = 0x3da;
+const DAC_WRITE_INDEX_PORT: u16 = 0x3c8;
+const DAC_DATA_PORT: u16 = 0x3c9;
+
+const MISC_OUTPUT: u8 = 0x63;
+const SEQUENCER: [u8; 5] = [0x03, 0x01, 0x0f, 0x00, 0x0e];
+const CRTC: [u8; 25] = [
+    0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0xbf, 0x1f, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x9c, 0x0e, 0x8f, 0x28, 0x40, 0x96, 0xb9, 0xa3, 0xff,
+];
+const GRAPHICS: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0f, 0xff];
+const ATTRIBUTE: [u8; 21] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x41, 0x00, 0x0f, 0x00, 0x00,
+];
+
+unsafe fn write_indexed(index_port: u16, index: u8, value: u8) {
+    let mut index_register: Port<u8> = Port::new(index_port);
+    let mut data_register: Port<u8> = Port::new(index_port + 1);
+    index_register.write(index);
+    data_register.write(value);
+}
+
+unsafe fn write_registers() {
+    let mut misc: Port<u8> = Port::new(MISC_OUTPUT_PORT);
+    misc.write(MISC_OUTPUT);
+
+    for (index, &value) in SEQUENCER.iter().enumerate() {
+        write_indexed(SEQUENCER_INDEX_PORT, index as u8, value);
+    }
+
+    // CRTC registers 0-7 are write protected by bit 7 of register 0x11
+    let mut crtc = CRTC;
+    crtc[0x03] |= 0x80;
+    crtc[0x11] &= !0x80;
+    write_indexed(CRTC_INDEX_PORT, 0x11, crtc[0x11]);
+    for (index, &value) in crtc.iter().enumerate() {
+        write_indexed(CRTC_INDEX_PORT, index as u8, value);
+    }
+
+    for (index, &value) in GRAPHICS.iter().enumerate() {
+        write_indexed(GRAPHICS_INDEX_PORT, index as u8, value);
+    }
+
+    // The attribute controller uses a single port for index and data, reading the
+    // input status register resets it to expect an index
+    let mut attribute: Port<u8> = Port::new(ATTRIBUTE_PORT);
+    let mut input_status: Port<u8> = Port::new(INPUT_STATUS_PORT);
+    for (index, &value) in ATTRIBUTE.iter().enumerate() {
+        input_status.read();
+        attribute.write(index as u8);
+        attribute.write(value);
+    }
+    // Give the palette back to the display
+    input_status.read();
+    attribute.write(0x20);
+}
+
+///
+/// Programs the DAC with a 3-3-2 RGB palette (the DAC takes 6 bit components)
+///
+unsafe fn write_palette() {
+    let mut index: Port<u8> = Port::new(DAC_WRITE_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(DAC_DATA_PORT);
+    index.write(0);
+    for color in 0..=255u8 {
+        let red = (color >> 5) & 0x07;
+        let green = (color >> 2) & 0x07;
+        let blue = color & 0x03;
+        // Widened, 63 times a component doesn't fit in a u8
+        data.write((red as u16 * 63 / 7) as u8);
+        data.write((green as u16 * 63 / 7) as u8);
+        data.write((blue as u16 * 63 / 3) as u8);
+    }
+}
+
+///
+/// Switches to mode 13h. The framebuffer is reached through the physical memory mapping
+///
+pub fn init(physical_memory_offset: VirtAddr) -> Framebuffer {
+    unsafe {
+        write_registers();
+        write_palette();
+        Framebuffer::new(
+            physical_memory_offset + FRAMEBUFFER_ADDRESS,
+            WIDTH,
+            HEIGHT,
+            WIDTH,
+            PixelFormat::Indexed8,
+        )
+    }
+}
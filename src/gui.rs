@@ -0,0 +1,395 @@
+//!
+//! 2D drawing and the window compositor
+//!
+//! Anything implementing `Canvas` (the framebuffer or an in-memory `Surface`) can be
+//! drawn on through a `Painter`, which clips every primitive to a rectangle and
+//! translates coordinates so the same drawing code works for a window or the screen.
+//!
+pub mod compositor;
+pub mod double_buffer;
+pub mod surface;
+
+pub use double_buffer::DoubleBuffer;
+pub use surface::Surface;
+
+use crate::framebuffer::font::PsfFont;
+use crate::framebuffer::{Framebuffer, Rgb};
+use crate::vga_buffer::cp437;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Point {
+    pub const fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// First column to the right of the rectangle
+    pub fn right(&self) -> i32 {
+        self.x + self.width
+    }
+
+    /// First row below the rectangle
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        point.x >= self.x && point.x < self.right() && point.y >= self.y && point.y < self.bottom()
+    }
+
+    ///
+    /// The overlapping part of both rectangles, empty if they don't overlap
+    ///
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        Rect::new(x, y, (right - x).max(0), (bottom - y).max(0))
+    }
+
+    ///
+    /// Smallest rectangle containing both
+    ///
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32) -> Rect {
+        Rect::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+}
+
+///
+/// Something pixels can be written to
+///
+pub trait Canvas {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb);
+
+    ///
+    /// Fills `len` pixels of a row. Implementations can do better than a pixel at a time
+    ///
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: Rgb) {
+        for px in x..x + len {
+            self.set_pixel(px, y, color);
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width() as i32, self.height() as i32)
+    }
+}
+
+impl Canvas for Framebuffer {
+    fn width(&self) -> usize {
+        Framebuffer::width(self)
+    }
+
+    fn height(&self) -> usize {
+        Framebuffer::height(self)
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        self.put_pixel(x, y, color);
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: Rgb) {
+        self.fill_rect(x, y, len, 1, color);
+    }
+}
+
+///
+/// Draws on a canvas. Coordinates are relative to `origin` and nothing is drawn
+/// outside of `clip` (in canvas coordinates)
+///
+pub struct Painter<'a, C: Canvas + ?Sized> {
+    canvas: &'a mut C,
+    origin: Point,
+    clip: Rect,
+}
+
+impl<'a, C: Canvas + ?Sized> Painter<'a, C> {
+    ///
+    /// A painter for the whole canvas
+    ///
+    pub fn new(canvas: &'a mut C) -> Self {
+        let clip = canvas.bounds();
+        Self {
+            canvas,
+            origin: Point::new(0, 0),
+            clip,
+        }
+    }
+
+    ///
+    /// A painter that only draws inside `area`, which also becomes the origin
+    ///
+    pub fn with_area(canvas: &'a mut C, area: Rect) -> Self {
+        let clip = canvas.bounds().intersection(&area);
+        Self {
+            canvas,
+            origin: Point::new(area.x, area.y),
+            clip,
+        }
+    }
+
+    ///
+    /// Restricts drawing further, `clip` is in painter coordinates
+    ///
+    pub fn clip_to(&mut self, clip: Rect) {
+        let clip = clip.offset(self.origin.x, self.origin.y);
+        self.clip = self.clip.intersection(&clip);
+    }
+
+    ///
+    /// Visible area in painter coordinates
+    ///
+    pub fn clip(&self) -> Rect {
+        self.clip.offset(-self.origin.x, -self.origin.y)
+    }
+
+    pub fn draw_pixel(&mut self, x: i32, y: i32, color: Rgb) {
+        let point = Point::new(x + self.origin.x, y + self.origin.y);
+        if self.clip.contains(point) {
+            self.canvas
+                .set_pixel(point.x as usize, point.y as usize, color);
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        let rect = rect
+            .offset(self.origin.x, self.origin.y)
+            .intersection(&self.clip);
+        if rect.is_empty() {
+            return;
+        }
+        for y in rect.y..rect.bottom() {
+            self.canvas
+                .fill_span(rect.x as usize, y as usize, rect.width as usize, color);
+        }
+    }
+
+    pub fn clear(&mut self, color: Rgb) {
+        let clip = self.clip();
+        self.fill_rect(clip, color);
+    }
+
+    ///
+    /// Outline of a rectangle, one pixel wide
+    ///
+    pub fn draw_rect(&mut self, rect: Rect, color: Rgb) {
+        if rect.is_empty() {
+            return;
+        }
+        let (right, bottom) = (rect.right() - 1, rect.bottom() - 1);
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), color);
+    }
+
+    ///
+    /// Bresenham's line, both ends included
+    ///
+    pub fn draw_line(&mut self, from: Point, to: Point, color: Rgb) {
+        let dx = (to.x - from.x).abs();
+        let dy = -(to.y - from.y).abs();
+        let step_x = if from.x < to.x { 1 } else { -1 };
+        let step_y = if from.y < to.y { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (from.x, from.y);
+        loop {
+            self.draw_pixel(x, y, color);
+            if x == to.x && y == to.y {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    ///
+    /// Midpoint circle algorithm
+    ///
+    pub fn draw_circle(&mut self, center: Point, radius: i32, color: Rgb) {
+        let (mut x, mut y) = (radius, 0);
+        let mut error = 1 - radius;
+        while x >= y {
+            for &(px, py) in &[
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.draw_pixel(center.x + px, center.y + py, color);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    pub fn fill_circle(&mut self, center: Point, radius: i32, color: Rgb) {
+        for dy in -radius..=radius {
+            // Widest x such that x² + dy² <= r²
+            let mut dx = 0;
+            while (dx + 1) * (dx + 1) + dy * dy <= radius * radius {
+                dx += 1;
+            }
+            self.fill_rect(
+                Rect::new(center.x - dx, center.y + dy, 2 * dx + 1, 1),
+                color,
+            );
+        }
+    }
+
+    ///
+    /// Fills a triangle scanline by scanline
+    ///
+    pub fn fill_triangle(&mut self, a: Point, b: Point, c: Point, color: Rgb) {
+        let mut points = [a, b, c];
+        points.sort_unstable_by_key(|point| point.y);
+        let [top, middle, bottom] = points;
+        // x of the edge from `from` to `to` at row `y`
+        let edge_x = |from: Point, to: Point, y: i32| {
+            if to.y == from.y {
+                from.x
+            } else {
+                from.x + (to.x - from.x) * (y - from.y) / (to.y - from.y)
+            }
+        };
+        for y in top.y..=bottom.y {
+            let long = edge_x(top, bottom, y);
+            let short = if y < middle.y {
+                edge_x(top, middle, y)
+            } else {
+                edge_x(middle, bottom, y)
+            };
+            let (left, right) = (long.min(short), long.max(short));
+            self.fill_rect(Rect::new(left, y, right - left + 1, 1), color);
+        }
+    }
+
+    ///
+    /// Copies `surface` with its top left corner at `position`
+    ///
+    pub fn blit(&mut self, surface: &Surface, position: Point) {
+        let target = Rect::new(
+            position.x + self.origin.x,
+            position.y + self.origin.y,
+            surface.width() as i32,
+            surface.height() as i32,
+        )
+        .intersection(&self.clip);
+        if target.is_empty() {
+            return;
+        }
+        let source_x = target.x - position.x - self.origin.x;
+        let source_y = target.y - position.y - self.origin.y;
+        for row in 0..target.height {
+            for column in 0..target.width {
+                let color = surface.pixel((source_x + column) as usize, (source_y + row) as usize);
+                self.canvas.set_pixel(
+                    (target.x + column) as usize,
+                    (target.y + row) as usize,
+                    color,
+                );
+            }
+        }
+    }
+
+    ///
+    /// Draws `text` with its top left corner at `position`. Characters go through
+    /// code page 437, and the background is left alone when `background` is `None`
+    ///
+    pub fn draw_text(
+        &mut self,
+        font: &PsfFont,
+        text: &str,
+        position: Point,
+        foreground: Rgb,
+        background: Option<Rgb>,
+    ) {
+        let mut x = position.x;
+        for character in text.chars() {
+            let glyph = font.glyph(cp437::from_char_or_placeholder(character) as usize);
+            for glyph_y in 0..font.height() {
+                for glyph_x in 0..font.width() {
+                    let color = if font.is_set(glyph, glyph_x, glyph_y) {
+                        foreground
+                    } else if let Some(background) = background {
+                        background
+                    } else {
+                        continue;
+                    };
+                    self.draw_pixel(x + glyph_x as i32, position.y + glyph_y as i32, color);
+                }
+            }
+            x += font.width() as i32;
+        }
+    }
+}
+
+#[test_case]
+fn test_rect_intersection() {
+    let a = Rect::new(0, 0, 10, 10);
+    let b = Rect::new(5, 8, 10, 10);
+    assert_eq!(a.intersection(&b), Rect::new(5, 8, 5, 2));
+    assert!(a.intersection(&Rect::new(20, 20, 5, 5)).is_empty());
+    assert_eq!(a.union(&b), Rect::new(0, 0, 15, 18));
+    assert!(a.contains(Point::new(9, 9)) && !a.contains(Point::new(10, 9)));
+}
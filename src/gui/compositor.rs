@@ -0,0 +1,519 @@
+//!
+//! Window compositor
+//!
+//! Tasks ask for a window with `create_window` and get a `WindowHandle`: they draw on
+//! the window surface with `WindowHandle::draw` and read the keyboard and mouse input
+//! sent to it from `WindowHandle::events`. The compositor task (`run`) owns the screen:
+//! it moves the mouse pointer, focuses, raises and drags windows, and redraws the
+//! areas that changed into a `DoubleBuffer`.
+//!
+use super::{DoubleBuffer, Painter, Point, Rect, Surface};
+use crate::framebuffer::font::{self, PsfFont};
+use crate::framebuffer::{Framebuffer, Rgb};
+use crate::task::mouse::{MouseButtons, MouseEvent, MouseEventStream};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::future::poll_fn;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+const DESKTOP_COLOR: Rgb = Rgb::new(0x00, 0x55, 0x80);
+const BORDER_COLOR: Rgb = Rgb::new(0x20, 0x20, 0x20);
+const TITLE_COLOR: Rgb = Rgb::new(0x60, 0x60, 0x60);
+const FOCUSED_TITLE_COLOR: Rgb = Rgb::new(0x00, 0x00, 0xaa);
+
+const BORDER: i32 = 1;
+const TITLE_PADDING: i32 = 2;
+const WINDOW_EVENT_QUEUE_SIZE: usize = 64;
+
+///
+/// Mouse pointer, `#` is black and `.` white
+///
+const CURSOR: [&str; 12] = [
+    "#         ",
+    "##        ",
+    "#.#       ",
+    "#..#      ",
+    "#...#     ",
+    "#....#    ",
+    "#.....#   ",
+    "#......#  ",
+    "#....#### ",
+    "#..#..#   ",
+    "#.# #..#  ",
+    "##   ##   ",
+];
+const CURSOR_WIDTH: i32 = 10;
+const CURSOR_HEIGHT: i32 = CURSOR.len() as i32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WindowId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Input sent to a window. Positions are relative to the window contents
+///
+pub enum WindowEvent {
+    Key(DecodedKey),
+    MouseMove(Point, MouseButtons),
+    MouseDown(Point, MouseButtons),
+    MouseUp(Point, MouseButtons),
+    Focus(bool),
+}
+
+///
+/// Part of a window shared between the compositor and the owner of the handle
+///
+struct WindowEvents {
+    queue: ArrayQueue<WindowEvent>,
+    waker: AtomicWaker,
+}
+
+impl WindowEvents {
+    fn push(&self, event: WindowEvent) {
+        // If the owner doesn't keep up the oldest events are the ones that matter least
+        if self.queue.push(event).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+struct Window {
+    id: WindowId,
+    title: String,
+    /// Where the contents are on the screen, decorations are drawn around it
+    frame: Rect,
+    surface: Surface,
+    events: Arc<WindowEvents>,
+}
+
+impl Window {
+    fn title_bar(&self, font: &PsfFont) -> Rect {
+        let height = font.height() as i32 + 2 * TITLE_PADDING;
+        Rect::new(
+            self.frame.x,
+            self.frame.y - height,
+            self.frame.width,
+            height,
+        )
+    }
+
+    ///
+    /// The whole window on the screen: contents, title bar and border
+    ///
+    fn outer_frame(&self, font: &PsfFont) -> Rect {
+        let title_bar = self.title_bar(font);
+        Rect::new(
+            self.frame.x - BORDER,
+            title_bar.y - BORDER,
+            self.frame.width + 2 * BORDER,
+            self.frame.height + title_bar.height + 2 * BORDER,
+        )
+    }
+
+    fn to_local(&self, point: Point) -> Point {
+        Point::new(point.x - self.frame.x, point.y - self.frame.y)
+    }
+}
+
+pub struct Compositor {
+    screen: DoubleBuffer,
+    font: PsfFont,
+    /// Bottom to top
+    windows: Vec<Window>,
+    focused: Option<WindowId>,
+    next_id: usize,
+    cursor: Point,
+    buttons: MouseButtons,
+    /// Window being dragged and where it was grabbed, relative to its frame
+    dragging: Option<(WindowId, Point)>,
+}
+
+impl Compositor {
+    fn new(framebuffer: Framebuffer) -> Self {
+        let screen = DoubleBuffer::new(framebuffer);
+        let cursor = Point::new(screen.width() as i32 / 2, screen.height() as i32 / 2);
+        Self {
+            screen,
+            font: font::builtin(),
+            windows: Vec::new(),
+            focused: None,
+            next_id: 0,
+            cursor,
+            buttons: MouseButtons::default(),
+            dragging: None,
+        }
+    }
+
+    fn index_of(&self, id: WindowId) -> Option<usize> {
+        self.windows.iter().position(|window| window.id == id)
+    }
+
+    fn window_mut(&mut self, id: WindowId) -> Option<&mut Window> {
+        self.windows.iter_mut().find(|window| window.id == id)
+    }
+
+    fn invalidate_window(&mut self, id: WindowId) {
+        if let Some(index) = self.index_of(id) {
+            let frame = self.windows[index].outer_frame(&self.font);
+            self.screen.invalidate(frame);
+        }
+    }
+
+    fn cursor_rect(&self) -> Rect {
+        Rect::new(self.cursor.x, self.cursor.y, CURSOR_WIDTH, CURSOR_HEIGHT)
+    }
+
+    ///
+    /// Topmost window under `point`
+    ///
+    fn window_at(&self, point: Point) -> Option<usize> {
+        self.windows
+            .iter()
+            .rposition(|window| window.outer_frame(&self.font).contains(point))
+    }
+
+    fn focus(&mut self, id: Option<WindowId>) {
+        if self.focused == id {
+            return;
+        }
+        if let Some(old) = self.focused {
+            if let Some(window) = self.window_mut(old) {
+                window.events.push(WindowEvent::Focus(false));
+            }
+            self.invalidate_window(old);
+        }
+        self.focused = id;
+        if let Some(new) = id {
+            if let Some(index) = self.index_of(new) {
+                // Raise it
+                let window = self.windows.remove(index);
+                window.events.push(WindowEvent::Focus(true));
+                self.windows.push(window);
+            }
+            self.invalidate_window(new);
+        }
+    }
+
+    fn handle_mouse(&mut self, event: MouseEvent) {
+        let old_cursor = self.cursor_rect();
+        let bounds = self.screen.bounds();
+        self.cursor.x = (self.cursor.x + event.dx as i32)
+            .max(0)
+            .min(bounds.width - 1);
+        // The mouse reports upward movement as positive
+        self.cursor.y = (self.cursor.y - event.dy as i32)
+            .max(0)
+            .min(bounds.height - 1);
+        self.screen.invalidate(old_cursor);
+        self.screen.invalidate(self.cursor_rect());
+
+        let pressed = event.buttons.left && !self.buttons.left;
+        let released = !event.buttons.left && self.buttons.left;
+        self.buttons = event.buttons;
+        let cursor = self.cursor;
+
+        if pressed {
+            let clicked = self.window_at(cursor).map(|index| self.windows[index].id);
+            self.focus(clicked);
+            if let Some(index) = clicked.and_then(|id| self.index_of(id)) {
+                let window = &self.windows[index];
+                if window.frame.contains(cursor) {
+                    window.events.push(WindowEvent::MouseDown(
+                        window.to_local(cursor),
+                        event.buttons,
+                    ));
+                } else {
+                    let grab = Point::new(cursor.x - window.frame.x, cursor.y - window.frame.y);
+                    self.dragging = Some((window.id, grab));
+                }
+            }
+            return;
+        }
+        if released && self.dragging.take().is_some() {
+            return;
+        }
+
+        if let Some((id, grab)) = self.dragging {
+            self.invalidate_window(id);
+            if let Some(window) = self.window_mut(id) {
+                window.frame.x = cursor.x - grab.x;
+                window.frame.y = cursor.y - grab.y;
+            }
+            self.invalidate_window(id);
+            return;
+        }
+
+        // Motion and releases go to the focused window while the pointer is over it
+        let focused = self.focused.and_then(|id| self.index_of(id));
+        if let Some(window) = focused.map(|index| &self.windows[index]) {
+            if window.frame.contains(cursor) {
+                let local = window.to_local(cursor);
+                let event = if released {
+                    WindowEvent::MouseUp(local, event.buttons)
+                } else {
+                    WindowEvent::MouseMove(local, event.buttons)
+                };
+                window.events.push(event);
+            }
+        }
+    }
+
+    ///
+    /// Redraws the changed part of the screen and shows it
+    ///
+    fn compose(&mut self) {
+        if self.screen.dirty().is_empty() {
+            return;
+        }
+        let font = self.font;
+        let focused = self.focused;
+        let cursor = self.cursor;
+        let windows = &self.windows;
+        let mut painter = self.screen.painter();
+        painter.clear(DESKTOP_COLOR);
+        for window in windows {
+            draw_decorations(&mut painter, &font, window, focused == Some(window.id));
+            painter.blit(&window.surface, Point::new(window.frame.x, window.frame.y));
+        }
+        draw_cursor(&mut painter, cursor);
+        drop(painter);
+        self.screen.present();
+    }
+}
+
+fn draw_decorations(
+    painter: &mut Painter<Surface>,
+    font: &PsfFont,
+    window: &Window,
+    focused: bool,
+) {
+    painter.draw_rect(window.outer_frame(font), BORDER_COLOR);
+    let title_bar = window.title_bar(font);
+    let title_color = if focused {
+        FOCUSED_TITLE_COLOR
+    } else {
+        TITLE_COLOR
+    };
+    painter.fill_rect(title_bar, title_color);
+    // Cut the title where it stops fitting in the bar
+    let fitting = ((title_bar.width - 2 * TITLE_PADDING) / font.width() as i32).max(0) as usize;
+    let end = window
+        .title
+        .char_indices()
+        .nth(fitting)
+        .map_or(window.title.len(), |(index, _)| index);
+    painter.draw_text(
+        font,
+        &window.title[..end],
+        Point::new(title_bar.x + TITLE_PADDING, title_bar.y + TITLE_PADDING),
+        Rgb::WHITE,
+        None,
+    );
+}
+
+fn draw_cursor(painter: &mut Painter<Surface>, position: Point) {
+    for (y, line) in CURSOR.iter().enumerate() {
+        for (x, pixel) in line.bytes().enumerate() {
+            let color = match pixel {
+                b'#' => Rgb::BLACK,
+                b'.' => Rgb::WHITE,
+                _ => continue,
+            };
+            painter.draw_pixel(position.x + x as i32, position.y + y as i32, color);
+        }
+    }
+}
+
+static COMPOSITOR: Mutex<Option<Compositor>> = Mutex::new(None);
+static REDRAW_REQUESTED: AtomicBool = AtomicBool::new(false);
+static REDRAW_WAKER: AtomicWaker = AtomicWaker::new();
+
+///
+/// Takes over the framebuffer. `run` has to be spawned for anything to show up
+///
+pub fn init(framebuffer: Framebuffer) {
+    *COMPOSITOR.lock() = Some(Compositor::new(framebuffer));
+    request_redraw();
+}
+
+///
+/// Whether the compositor owns the screen
+///
+pub fn is_running() -> bool {
+    COMPOSITOR.lock().is_some()
+}
+
+fn request_redraw() {
+    REDRAW_REQUESTED.store(true, Ordering::SeqCst);
+    REDRAW_WAKER.wake();
+}
+
+///
+/// Sends a key to the focused window. Returns `false` if no window has the focus
+///
+pub fn push_key(key: DecodedKey) -> bool {
+    let mut compositor = COMPOSITOR.lock();
+    let compositor = match compositor.as_mut() {
+        Some(compositor) => compositor,
+        None => return false,
+    };
+    let focused = compositor.focused.and_then(|id| compositor.index_of(id));
+    match focused {
+        Some(index) => {
+            compositor.windows[index].events.push(WindowEvent::Key(key));
+            true
+        }
+        None => false,
+    }
+}
+
+///
+/// Opens a window with its contents at `frame` (screen coordinates) and gives it the focus
+///
+pub fn create_window(title: &str, frame: Rect) -> Option<WindowHandle> {
+    let mut compositor = COMPOSITOR.lock();
+    let compositor = compositor.as_mut()?;
+    let id = WindowId(compositor.next_id);
+    compositor.next_id += 1;
+    let events = Arc::new(WindowEvents {
+        queue: ArrayQueue::new(WINDOW_EVENT_QUEUE_SIZE),
+        waker: AtomicWaker::new(),
+    });
+    compositor.windows.push(Window {
+        id,
+        title: String::from(title),
+        frame,
+        surface: Surface::new(frame.width as usize, frame.height as usize, Rgb::WHITE),
+        events: events.clone(),
+    });
+    compositor.focus(Some(id));
+    compositor.invalidate_window(id);
+    request_redraw();
+    Some(WindowHandle { id, events })
+}
+
+///
+/// A window owned by a task. The window is closed when the handle is dropped
+///
+pub struct WindowHandle {
+    id: WindowId,
+    events: Arc<WindowEvents>,
+}
+
+impl WindowHandle {
+    pub fn id(&self) -> WindowId {
+        self.id
+    }
+
+    ///
+    /// Draws on the window contents and schedules a redraw
+    ///
+    pub fn draw<F: FnOnce(&mut Painter<Surface>)>(&self, draw: F) {
+        let mut compositor = COMPOSITOR.lock();
+        if let Some(compositor) = compositor.as_mut() {
+            if let Some(window) = compositor.window_mut(self.id) {
+                draw(&mut window.surface.painter());
+                let frame = window.frame;
+                compositor.screen.invalidate(frame);
+                request_redraw();
+            }
+        }
+    }
+
+    ///
+    /// Size of the contents
+    ///
+    pub fn size(&self) -> (usize, usize) {
+        let mut compositor = COMPOSITOR.lock();
+        compositor
+            .as_mut()
+            .and_then(|compositor| compositor.window_mut(self.id))
+            .map_or((0, 0), |window| {
+                (window.frame.width as usize, window.frame.height as usize)
+            })
+    }
+
+    pub fn events(&self) -> WindowEventStream {
+        WindowEventStream {
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl Drop for WindowHandle {
+    fn drop(&mut self) {
+        let mut compositor = COMPOSITOR.lock();
+        if let Some(compositor) = compositor.as_mut() {
+            compositor.invalidate_window(self.id);
+            if let Some(index) = compositor.index_of(self.id) {
+                compositor.windows.remove(index);
+            }
+            if compositor.focused == Some(self.id) {
+                let top = compositor.windows.last().map(|window| window.id);
+                compositor.focused = None;
+                compositor.focus(top);
+            }
+            request_redraw();
+        }
+    }
+}
+
+pub struct WindowEventStream {
+    events: Arc<WindowEvents>,
+}
+
+impl Stream for WindowEventStream {
+    type Item = WindowEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<WindowEvent>> {
+        let events = &self.events;
+        if let Ok(event) = events.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+        events.waker.register(&cx.waker());
+        match events.queue.pop() {
+            Ok(event) => Poll::Ready(Some(event)),
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+enum Input {
+    Mouse(MouseEvent),
+    Redraw,
+}
+
+///
+/// The compositor task: handles the mouse and redraws whenever something changed
+///
+pub async fn run() {
+    let mut mouse = MouseEventStream::new();
+    loop {
+        let input = poll_fn(|cx| {
+            if let Poll::Ready(Some(event)) = mouse.poll_next_unpin(cx) {
+                return Poll::Ready(Input::Mouse(event));
+            }
+            REDRAW_WAKER.register(&cx.waker());
+            if REDRAW_REQUESTED.swap(false, Ordering::SeqCst) {
+                return Poll::Ready(Input::Redraw);
+            }
+            Poll::Pending
+        })
+        .await;
+        let mut compositor = COMPOSITOR.lock();
+        if let Some(compositor) = compositor.as_mut() {
+            if let Input::Mouse(event) = input {
+                compositor.handle_mouse(event);
+            }
+            compositor.compose();
+        }
+    }
+}
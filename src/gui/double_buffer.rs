@@ -0,0 +1,86 @@
+//!
+//! Double buffering: everything is drawn off screen and the changed area is copied to
+//! the framebuffer in one go, so half-drawn frames are never visible
+//!
+use super::{Canvas, Painter, Rect, Surface};
+use crate::framebuffer::{Framebuffer, PixelFormat, Rgb};
+
+pub struct DoubleBuffer {
+    back: Surface,
+    front: Framebuffer,
+    /// Part of the back buffer changed since the last `present`
+    dirty: Rect,
+}
+
+impl DoubleBuffer {
+    pub fn new(front: Framebuffer) -> Self {
+        let back = Surface::new(front.width(), front.height(), Rgb::BLACK);
+        Self {
+            dirty: back.bounds(),
+            back,
+            front,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.back.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.back.height()
+    }
+
+    pub fn bounds(&self) -> Rect {
+        self.back.bounds()
+    }
+
+    ///
+    /// Marks an area as changed so the next `present` copies it
+    ///
+    pub fn invalidate(&mut self, area: Rect) {
+        self.dirty = self.dirty.union(&area.intersection(&self.back.bounds()));
+    }
+
+    pub fn dirty(&self) -> Rect {
+        self.dirty
+    }
+
+    ///
+    /// A painter on the back buffer, clipped to the area that will be presented
+    ///
+    pub fn painter(&mut self) -> Painter<Surface> {
+        let dirty = self.dirty;
+        let mut painter = Painter::new(&mut self.back);
+        painter.clip_to(dirty);
+        painter
+    }
+
+    ///
+    /// Copies the changed area to the screen
+    ///
+    pub fn present(&mut self) {
+        let dirty = self.dirty;
+        if dirty.is_empty() {
+            return;
+        }
+        let (x, width) = (dirty.x as usize, dirty.width as usize);
+        for y in dirty.y as usize..dirty.bottom() as usize {
+            let row = &self.back.row(y)[x..x + width];
+            match self.front.format() {
+                PixelFormat::Bgr32 => {
+                    let bytes = unsafe {
+                        core::slice::from_raw_parts(row.as_ptr() as *const u8, row.len() * 4)
+                    };
+                    self.front.write_row_bytes(x, y, bytes);
+                }
+                PixelFormat::Indexed8 => {
+                    for column in x..x + width {
+                        let color = self.back.pixel(column, y);
+                        self.front.put_pixel(column, y, color);
+                    }
+                }
+            }
+        }
+        self.dirty = Rect::default();
+    }
+}
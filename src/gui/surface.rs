@@ -0,0 +1,80 @@
+//!
+//! In-memory pixel buffers: window contents and the back buffer of the screen
+//!
+use super::{Canvas, Painter};
+use crate::framebuffer::Rgb;
+use alloc::vec;
+use alloc::vec::Vec;
+
+///
+/// Pixels are stored as `0x00RRGGBB`, which is also the memory layout of
+/// `PixelFormat::Bgr32`, so rows can be copied to the screen as they are
+///
+pub struct Surface {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+
+fn pack(color: Rgb) -> u32 {
+    (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32
+}
+
+fn unpack(pixel: u32) -> Rgb {
+    Rgb::new((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8)
+}
+
+impl Surface {
+    pub fn new(width: usize, height: usize, color: Rgb) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![pack(color); width * height],
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+        if x >= self.width || y >= self.height {
+            return Rgb::BLACK;
+        }
+        unpack(self.pixels[y * self.width + x])
+    }
+
+    ///
+    /// Packed pixels of row `y`
+    ///
+    pub fn row(&self, y: usize) -> &[u32] {
+        &self.pixels[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn painter(&mut self) -> Painter<Surface> {
+        Painter::new(self)
+    }
+}
+
+impl Canvas for Surface {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = pack(color);
+        }
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, color: Rgb) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let end = (x + len).min(self.width);
+        let start = y * self.width;
+        for pixel in &mut self.pixels[start + x..start + end] {
+            *pixel = pack(color);
+        }
+    }
+}
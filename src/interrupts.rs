@@ -1,6 +1,7 @@
+use crate::apic;
+use crate::emergency_println;
 use crate::gdt;
 use crate::hlt_loop;
-use crate::{print, println};
 use lazy_static::lazy_static;
 
 ///
@@ -30,7 +31,7 @@ use lazy_static::lazy_static;
 ///     pub virtualization: Entry<HandlerFunc>,
 ///     pub security_exception: Entry<HandlerFuncWithErrCode>,
 /// }
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -42,8 +43,10 @@ lazy_static! {
                 // Set the interrupt stack index to swap to
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // Everything after the exceptions goes to the handlers of `irq`
+        for (index, &stub) in VECTOR_STUBS.iter().enumerate() {
+            idt[FIRST_VECTOR as usize + index].set_handler_fn(stub);
+        }
         idt
     };
 }
@@ -63,15 +66,16 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE ACCESS FAULT");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    // The faulting code might be holding WRITER or SERIAL1
+    emergency_println!("EXCEPTION: PAGE ACCESS FAULT");
+    emergency_println!("Accessed address: {:?}", Cr2::read());
+    emergency_println!("Error Code: {:?}", error_code);
+    emergency_println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("Exception Breakpoint\n{:#?}", stack_frame);
+    emergency_println!("Exception Breakpoint\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -81,21 +85,72 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("Double Fault\n{:#?}", stack_frame);
 }
 
+#[test_case]
+fn test_line_vectors() {
+    // Where the secondary PIC really raises them
+    assert_eq!(line_vector(8), Some(40));
+    assert_eq!(line_vector(15), Some(47));
+    for line in 0..16 {
+        let vector = line_vector(line).unwrap();
+        assert_eq!(vector_line(vector), Some(line));
+    }
+    assert_eq!(vector_line(PIC_1_OFFSET - 1), None);
+}
+
+#[test_case]
+fn test_gsi_line_vectors() {
+    let lines = [16, 40, apic::MAX_LINES - 1];
+    if apic::is_enabled() {
+        for &line in &lines {
+            let vector = line_vector(line).unwrap();
+            assert_eq!(vector, apic::GSI_VECTOR_BASE + line - 16);
+            assert_eq!(vector_line(vector), Some(line));
+        }
+    } else {
+        // The PICs only have 16
+        for &line in &lines {
+            assert_eq!(line_vector(line), None);
+        }
+        assert_eq!(vector_line(apic::GSI_VECTOR_BASE), None);
+    }
+    assert_eq!(line_vector(apic::MAX_LINES), None);
+}
+
+#[test_case]
+fn test_mouse_line_is_unmasked() {
+    use x86_64::instructions::port::Port;
+    fn nothing() {}
+    // Line 12 is the mouse, on the secondary PIC
+    assert_eq!(line_vector(12), Some(44));
+    if apic::is_enabled() {
+        return;
+    }
+    let handler = crate::irq::register(crate::irq::Irq::Line(12), nothing).unwrap();
+    let (secondary, primary) =
+        unsafe { (Port::<u8>::new(0xa1).read(), Port::<u8>::new(0x21).read()) };
+    crate::irq::unregister(handler);
+    assert_eq!(secondary & 1 << 4, 0);
+    // The cascade, or nothing from the secondary PIC gets through
+    assert_eq!(primary & 1 << 2, 0);
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
     x86_64::instructions::interrupts::int3();
 }
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use pic8259_simple::ChainedPics;
 use spin;
 
 /// Where we wanna store external interrupts
 ///
-/// `32` is where the exception interrupts finish, 47 because there is 15 more
+/// `32` is where the exception interrupts finish. Each PIC has 8 lines and the secondary
+/// one ignores the low 3 bits of its offset, so its vectors start right after
 ///
 pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = 47;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 ///
 /// PICS send us external interruptions
@@ -106,69 +161,159 @@ pub const PIC_2_OFFSET: u8 = 47;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-#[derive(Copy, Debug, Clone)]
-#[repr(u8)]
 ///
-/// InterruptIndex represents the position in which the interrupt
-/// handler is located
+/// First vector that isn't a CPU exception
+///
+pub(crate) const FIRST_VECTOR: u8 = 32;
+
+///
+/// Only the repeat operand of `IRQ_COUNTS`, every element gets its own copy. A `static`
+/// can't be repeated and the atomics aren't `Copy`
+///
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+const LINES: usize = apic::MAX_LINES as usize;
+
+///
+/// How many times each interrupt line fired
 ///
-pub enum InterruptIndex {
-    ///
-    /// timer interrupts by the PIC are on the line 0,
-    /// so we set it to the offset
-    ///
-    Timer = PIC_1_OFFSET,
-    Keyboard = PIC_1_OFFSET + 1,
+static IRQ_COUNTS: [AtomicU64; LINES] = [NO_INTERRUPTS; LINES];
+
+pub(crate) fn count_irq(irq: u8) {
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+pub(crate) fn irq_counts() -> [u64; LINES] {
+    let mut counts = [0; LINES];
+    for (count, counter) in counts.iter_mut().zip(IRQ_COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
     }
+    counts
+}
 
-    fn as_usize(self) -> usize {
-        self as u8 as usize
+///
+/// Clears the mask bit of an IRQ line, the firmware may have left some of them masked.
+/// Lines of the secondary PIC also need the cascade line (2) of the primary one
+///
+pub(crate) fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+    if apic::is_enabled() {
+        return apic::set_masked(irq, false);
+    }
+    let mut primary: Port<u8> = Port::new(0x21);
+    let mut secondary: Port<u8> = Port::new(0xa1);
+    unsafe {
+        if irq < 8 {
+            let mask = primary.read();
+            primary.write(mask & !(1 << irq));
+        } else {
+            let mask = secondary.read();
+            secondary.write(mask & !(1 << (irq - 8)));
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+        }
     }
 }
 
 ///
-/// Timer interrupt handler
+/// Sets the mask bit of an IRQ line, the PIC stops raising it
 ///
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // print!(".");
-    use crate::task::executor::Executor;
-    use crate::task::Task;
-    use x86_64::instructions::interrupts;
-    // Tell the PIC to notify that we handled the interrupt
-
+pub(crate) fn mask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+    if apic::is_enabled() {
+        return apic::set_masked(irq, true);
+    }
+    if irq >= 16 {
+        return;
+    }
+    let (mut port, bit): (Port<u8>, u8) = if irq < 8 {
+        (Port::new(0x21), irq)
+    } else {
+        (Port::new(0xa1), irq - 8)
+    };
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        let mask = port.read();
+        port.write(mask | 1 << bit);
     }
 }
-async fn test2() -> u64 {
-    3
+
+///
+/// Vector raised for IRQ `line`, if there's such a line. Only the I/O APIC has lines
+/// after 15
+///
+pub(crate) fn line_vector(line: u8) -> Option<u8> {
+    match line {
+        0..=7 => Some(PIC_1_OFFSET + line),
+        8..=15 => Some(PIC_2_OFFSET + line - 8),
+        line if line < apic::MAX_LINES && apic::is_enabled() => {
+            Some(apic::GSI_VECTOR_BASE + line - 16)
+        }
+        _ => None,
+    }
 }
 
-async fn test(scancode: u8) {
-    let i = test2().await;
-    println!("e {}", scancode);
+///
+/// IRQ line behind `vector`, if it's the one of a line. The inverse of `line_vector`
+///
+pub(crate) fn vector_line(vector: u8) -> Option<u8> {
+    match vector {
+        vector if (PIC_1_OFFSET..PIC_1_OFFSET + 8).contains(&vector) => Some(vector - PIC_1_OFFSET),
+        vector if (PIC_2_OFFSET..PIC_2_OFFSET + 8).contains(&vector) => {
+            Some(vector - PIC_2_OFFSET + 8)
+        }
+        vector if vector >= apic::GSI_VECTOR_BASE && apic::is_enabled() => {
+            let line = vector - apic::GSI_VECTOR_BASE + 16;
+            Some(line).filter(|&line| line < apic::MAX_LINES)
+        }
+        _ => None,
+    }
 }
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // use crate::key::{Key, XT};
-    // use core::convert::TryFrom;
-    use x86_64::instructions::port::Port;
-    let mut port = Port::new(0x60);
 
-    let scancode: u8 = unsafe { port.read() };
+///
+/// Acknowledges `vector` to the controller that raised it. Spurious interrupts of the
+/// local APIC must not be acknowledged
+///
+pub(crate) fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        if vector != apic::SPURIOUS_VECTOR {
+            apic::end_of_interrupt();
+        }
+    } else if vector_line(vector).is_some() {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
 
-    crate::task::keyboard::add_scancode(scancode);
+macro_rules! vector_stubs {
+    ($($vector:literal)*) => {
+        ///
+        /// One entry point per vector from `FIRST_VECTOR` on, the IDT can't tell a handler
+        /// which vector was raised
+        ///
+        const VECTOR_STUBS: [HandlerFunc; 256 - FIRST_VECTOR as usize] = [$({
+            extern "x86-interrupt" fn stub(_stack_frame: &mut InterruptStackFrame) {
+                crate::irq::dispatch($vector);
+            }
+            stub
+        }),*];
+    };
+}
 
-    // for i in 0..100 {
-    // Spawner::send_task(test());
-    // }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+vector_stubs! {
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+    48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    64 65 66 67 68 69 70 71 72 73 74 75 76 77 78 79
+    80 81 82 83 84 85 86 87 88 89 90 91 92 93 94 95
+    96 97 98 99 100 101 102 103 104 105 106 107 108 109 110 111
+    112 113 114 115 116 117 118 119 120 121 122 123 124 125 126 127
+    128 129 130 131 132 133 134 135 136 137 138 139 140 141 142 143
+    144 145 146 147 148 149 150 151 152 153 154 155 156 157 158 159
+    160 161 162 163 164 165 166 167 168 169 170 171 172 173 174 175
+    176 177 178 179 180 181 182 183 184 185 186 187 188 189 190 191
+    192 193 194 195 196 197 198 199 200 201 202 203 204 205 206 207
+    208 209 210 211 212 213 214 215 216 217 218 219 220 221 222 223
+    224 225 226 227 228 229 230 231 232 233 234 235 236 237 238 239
+    240 241 242 243 244 245 246 247 248 249 250 251 252 253 254 255
 }
@@ -0,0 +1,218 @@
+//!
+//! Interrupt handler registry
+//!
+//! Every vector after the CPU exceptions has a stub in the IDT that runs the handlers
+//! registered here for it and then acknowledges the interrupt to the controller, so
+//! handlers never send the EOI themselves. Handlers are closures or plain functions and
+//! can be registered for a legacy IRQ line or straight for a vector, and removed again.
+//! A line can be shared when every handler on it was registered with `register_shared`.
+//!
+//! The table has a fixed size so it can be used before the heap exists: a plain function
+//! or a closure that captures nothing takes no memory when boxed.
+//!
+use crate::interrupts::{self, FIRST_VECTOR};
+use alloc::boxed::Box;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+///
+/// How many handlers can be registered at the same time
+///
+pub const MAX_HANDLERS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Irq {
+    /// IRQ line, 0 to 15 with the PICs and up to `apic::MAX_LINES` with the I/O APIC
+    Line(u8),
+    /// IDT vector, from 32 on
+    Vector(u8),
+}
+
+impl Irq {
+    pub fn vector(self) -> Result<u8, IrqError> {
+        match self {
+            Irq::Line(line) => interrupts::line_vector(line).ok_or(IrqError::Invalid),
+            Irq::Vector(vector) if vector >= FIRST_VECTOR => Ok(vector),
+            Irq::Vector(_) => Err(IrqError::Invalid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// No such line, or the vector is a CPU exception
+    Invalid,
+    /// Somebody has the line and it isn't shared
+    Busy,
+    /// There are already `MAX_HANDLERS` handlers
+    Full,
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrqError::Invalid => write!(f, "invalid interrupt"),
+            IrqError::Busy => write!(f, "interrupt already in use"),
+            IrqError::Full => write!(f, "too many interrupt handlers"),
+        }
+    }
+}
+
+///
+/// Returned by `register`, gives the handler back to `unregister`
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId(u64);
+
+type Handler = Box<dyn Fn() + Send + Sync>;
+
+struct Entry {
+    id: HandlerId,
+    irq: Irq,
+    vector: u8,
+    shared: bool,
+    handler: Handler,
+}
+
+const NO_ENTRY: Option<Entry> = None;
+
+///
+/// The interrupt handlers take it for reading, so it's only written with interrupts
+/// disabled
+///
+static HANDLERS: RwLock<[Option<Entry>; MAX_HANDLERS]> = RwLock::new([NO_ENTRY; MAX_HANDLERS]);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn add(irq: Irq, shared: bool, handler: Handler) -> Result<HandlerId, IrqError> {
+    let vector = irq.vector()?;
+    let id = without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let conflict = handlers
+            .iter()
+            .flatten()
+            .any(|entry| entry.vector == vector && !(shared && entry.shared));
+        if conflict {
+            return Err(IrqError::Busy);
+        }
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::Full)?;
+        let id = HandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        *slot = Some(Entry {
+            id,
+            irq,
+            vector,
+            shared,
+            handler,
+        });
+        Ok(id)
+    })?;
+    if let Irq::Line(line) = irq {
+        interrupts::unmask_irq(line);
+    }
+    Ok(id)
+}
+
+///
+/// Runs `handler` every time `irq` fires, with interrupts disabled. Fails if anybody
+/// else has the interrupt
+///
+/// The handler runs with the table locked, so it can't register or unregister
+/// handlers itself; it has to leave that to a task
+///
+pub fn register<F>(irq: Irq, handler: F) -> Result<HandlerId, IrqError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    add(irq, false, Box::new(handler))
+}
+
+///
+/// Like `register`, but other shared handlers can be on the same interrupt. All of them
+/// run every time, each one has to check whether its device raised it
+///
+pub fn register_shared<F>(irq: Irq, handler: F) -> Result<HandlerId, IrqError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    add(irq, true, Box::new(handler))
+}
+
+///
+/// Removes a handler. A legacy line is masked once nobody handles it
+///
+pub fn unregister(id: HandlerId) {
+    let (removed, still_used) = without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let removed = handlers
+            .iter_mut()
+            .find(|slot| slot.as_ref().map(|entry| entry.id) == Some(id))
+            .and_then(Option::take);
+        let still_used = removed.as_ref().map_or(false, |removed| {
+            handlers
+                .iter()
+                .flatten()
+                .any(|entry| entry.vector == removed.vector)
+        });
+        (removed, still_used)
+    });
+    if let Some(Entry {
+        irq: Irq::Line(line),
+        ..
+    }) = removed
+    {
+        if !still_used {
+            interrupts::mask_irq(line);
+        }
+    }
+}
+
+///
+/// How many handlers `irq` has
+///
+pub fn handler_count(irq: Irq) -> usize {
+    let vector = match irq.vector() {
+        Ok(vector) => vector,
+        Err(_) => return 0,
+    };
+    HANDLERS
+        .read()
+        .iter()
+        .flatten()
+        .filter(|entry| entry.vector == vector)
+        .count()
+}
+
+///
+/// Called by the IDT stub of `vector`. Holds the read lock while the handlers run, a
+/// handler that calls `register` or `unregister` would spin forever
+///
+pub(crate) fn dispatch(vector: u8) {
+    if let Some(line) = interrupts::vector_line(vector) {
+        interrupts::count_irq(line);
+    }
+    for entry in HANDLERS.read().iter().flatten() {
+        if entry.vector == vector {
+            (entry.handler)();
+        }
+    }
+    interrupts::end_of_interrupt(vector);
+}
+
+#[test_case]
+fn test_shared_lines_reject_exclusive_handlers() {
+    // Line 5 is free in QEMU, functions don't need the heap
+    fn nothing() {}
+    let first = register_shared(Irq::Line(5), nothing).unwrap();
+    let second = register_shared(Irq::Line(5), nothing).unwrap();
+    assert_eq!(register(Irq::Line(5), nothing), Err(IrqError::Busy));
+    assert_eq!(handler_count(Irq::Line(5)), 2);
+    unregister(first);
+    unregister(second);
+    assert_eq!(handler_count(Irq::Line(5)), 0);
+    assert_eq!(register(Irq::Vector(3), nothing), Err(IrqError::Invalid));
+}
@@ -18,15 +18,31 @@ use x86_64::{
 };
 
 // All of the components of the so
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod block;
+mod bytes;
+pub mod driver;
+pub mod emergency;
+pub mod framebuffer;
 pub mod gdt;
+pub mod gui;
 mod interrupts;
+pub mod irq;
 pub mod key;
+pub mod log;
 pub mod memory;
+pub mod pci;
+pub mod power;
 pub mod qemu;
 pub mod serial;
+pub mod shell;
 pub mod task;
+pub mod time;
+pub mod vfs;
 pub mod vga_buffer;
+pub mod vt;
 
 use core::panic::PanicInfo;
 
@@ -37,6 +53,7 @@ pub fn init_os() {
     interrupts::init_dt();
     // Initialize PICS so we know where the external interrupts are going
     unsafe { interrupts::PICS.lock().initialize() };
+    irq::register(irq::Irq::Line(0), time::tick).expect("timer interrupt taken");
     x86_64::instructions::interrupts::enable();
 }
 
@@ -98,6 +115,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    emergency::enter_panic_mode();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     qemu::exit_qemu(qemu::QemuExitCode::Failed);
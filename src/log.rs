@@ -0,0 +1,589 @@
+//!
+//! Leveled kernel logging
+//!
+//! Every record goes through `_log`, which checks the filters (a default level plus
+//! per-target overrides matched by module path prefix) and hands the record to every
+//! registered `Sink`. The kernel registers three sinks by default: the VGA console,
+//! the serial port and an in-memory ring buffer that can be read back later (`dmesg`).
+//!
+//! Logging is safe from interrupt and exception handlers: nothing on this path spins
+//! on a lock, a busy sink either falls back to a lock-free path or skips the record.
+//!
+use crate::time::rtc::{DateTime, SystemTime};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+///
+/// Severity of a log record, `Error` being the most severe
+///
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    ///
+    /// SGR escape used by the console sinks, rendered both by the VGA writer
+    /// and by the terminal on the other side of the serial port
+    ///
+    pub fn ansi_color(self) -> &'static str {
+        match self {
+            Level::Error => "\x1b[91m",
+            Level::Warn => "\x1b[93m",
+            Level::Info => "\x1b[97m",
+            Level::Debug => "\x1b[37m",
+            Level::Trace => "\x1b[90m",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+///
+/// Maximum level that passes a filter. `Off` lets nothing through
+///
+pub enum LevelFilter {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LevelFilter {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    pub fn allows(self, level: Level) -> bool {
+        level as u8 <= self as u8
+    }
+}
+
+impl FromStr for LevelFilter {
+    type Err = &'static str;
+
+    ///
+    /// Parses names like `info` or `TRACE`, used by whoever lets the user tweak the filters
+    ///
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let filters = [
+            ("off", LevelFilter::Off),
+            ("error", LevelFilter::Error),
+            ("warn", LevelFilter::Warn),
+            ("info", LevelFilter::Info),
+            ("debug", LevelFilter::Debug),
+            ("trace", LevelFilter::Trace),
+        ];
+        filters
+            .iter()
+            .find(|(filter_name, _)| filter_name.eq_ignore_ascii_case(name))
+            .map(|&(_, filter)| filter)
+            .ok_or("unknown log level")
+    }
+}
+
+///
+/// A single log line before it gets formatted by the sinks
+///
+pub struct Record<'a> {
+    pub level: Level,
+    /// Usually the `module_path!()` of the caller
+    pub target: &'a str,
+    pub timestamp: Duration,
+    /// Date and time, once the RTC was read
+    pub wall_clock: Option<DateTime>,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl<'a> fmt::Display for Record<'a> {
+    ///
+    /// `[    1.234567] INFO  rust_os::memory: message`, with the date and time in front of
+    /// the uptime when they're known
+    ///
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(wall_clock) = self.wall_clock {
+            write!(f, "{} ", wall_clock)?;
+        }
+        write!(
+            f,
+            "[{:>5}.{:06}] {:<5} {}: {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.level,
+            self.target,
+            self.args
+        )
+    }
+}
+
+///
+/// Destination of log records.
+///
+/// Sinks are shared between every CPU context that logs so they must do their own locking
+///
+pub trait Sink: Sync {
+    fn name(&self) -> &str;
+    fn log(&self, record: &Record);
+}
+
+///
+/// Writes records to the VGA text buffer with a color depending on the level
+/// (the writer understands ANSI escapes)
+///
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn name(&self) -> &str {
+        "vga"
+    }
+
+    fn log(&self, record: &Record) {
+        use crate::vga_buffer::WRITER;
+        use core::fmt::Write;
+        let color = record.level.ansi_color();
+        if crate::framebuffer::console::is_active() {
+            return crate::framebuffer::console::try_print(format_args!(
+                "{}{}\x1b[0m\n",
+                color, record
+            ));
+        }
+        // Never wait for the writer, we might be logging from an interrupt handler
+        // that fired while it was held
+        if crate::emergency::is_panicking() {
+            unsafe { WRITER.force_unlock() };
+        }
+        if let Some(mut writer) = WRITER.try_lock() {
+            let _ = writeln!(writer, "{}{}\x1b[0m", color, record);
+        }
+    }
+}
+
+///
+/// Writes records to the host through `SERIAL1`
+///
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn name(&self) -> &str {
+        "serial"
+    }
+
+    fn log(&self, record: &Record) {
+        crate::emergency::try_print_serial(format_args!(
+            "{}{}\x1b[0m\n",
+            record.level.ansi_color(),
+            record
+        ));
+    }
+}
+
+const RING_BUFFER_SIZE: usize = 16 * 1024;
+const MAX_LINE_LENGTH: usize = 256;
+
+///
+/// Fixed-size circular byte buffer that keeps the most recent log lines.
+///
+/// When it's full the oldest whole lines are evicted, so reading always starts at
+/// the beginning of a line
+///
+pub struct RingBuffer {
+    data: [u8; RING_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; RING_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        if self.len == RING_BUFFER_SIZE {
+            self.evict_line();
+        }
+        let end = (self.start + self.len) % RING_BUFFER_SIZE;
+        self.data[end] = byte;
+        self.len += 1;
+    }
+
+    fn pop_byte(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % RING_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Drops bytes up to and including the first newline
+    fn evict_line(&mut self) {
+        while let Some(byte) = self.pop_byte() {
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+
+    ///
+    /// Calls `f` with every stored line (without the trailing newline), oldest first.
+    ///
+    /// Lines longer than `MAX_LINE_LENGTH` are split
+    ///
+    pub fn for_each_line(&self, mut f: impl FnMut(&str)) {
+        let mut line = [0u8; MAX_LINE_LENGTH];
+        let mut line_len = 0;
+        for i in 0..self.len {
+            let byte = self.data[(self.start + i) % RING_BUFFER_SIZE];
+            if byte != b'\n' {
+                line[line_len] = byte;
+                line_len += 1;
+            }
+            if byte == b'\n' || line_len == MAX_LINE_LENGTH {
+                f(core::str::from_utf8(&line[..line_len]).unwrap_or("<invalid utf-8>"));
+                line_len = 0;
+            }
+        }
+        if line_len > 0 {
+            f(core::str::from_utf8(&line[..line_len]).unwrap_or("<invalid utf-8>"));
+        }
+    }
+}
+
+impl Default for RingBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for RingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Keeps the last `RING_BUFFER_SIZE` bytes of logs in memory, see `dmesg`
+///
+pub struct RingBufferSink {
+    buffer: Mutex<RingBuffer>,
+    /// Records lost because the buffer was being read when they arrived
+    dropped: AtomicUsize,
+}
+
+impl RingBufferSink {
+    pub const fn new() -> Self {
+        Self {
+            buffer: Mutex::new(RingBuffer::new()),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RingBufferSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for RingBufferSink {
+    fn name(&self) -> &str {
+        "ring"
+    }
+
+    fn log(&self, record: &Record) {
+        use core::fmt::Write;
+        match self.buffer.try_lock() {
+            Some(mut buffer) => {
+                let _ = writeln!(buffer, "{}", record);
+            }
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub static VGA_SINK: VgaSink = VgaSink;
+pub static SERIAL_SINK: SerialSink = SerialSink;
+pub static RING_BUFFER_SINK: RingBufferSink = RingBufferSink::new();
+
+const MAX_SINKS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct SinkSlot {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+///
+/// Registered sinks. It's a fixed array so logging works before the heap is initialized
+///
+static SINKS: Mutex<[Option<SinkSlot>; MAX_SINKS]> = Mutex::new([
+    Some(SinkSlot {
+        sink: &VGA_SINK,
+        level: LevelFilter::Info,
+    }),
+    Some(SinkSlot {
+        sink: &SERIAL_SINK,
+        level: LevelFilter::Trace,
+    }),
+    Some(SinkSlot {
+        sink: &RING_BUFFER_SINK,
+        level: LevelFilter::Trace,
+    }),
+    None,
+    None,
+    None,
+    None,
+    None,
+]);
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(LevelFilter::Info as u8);
+
+lazy_static! {
+    ///
+    /// Per-target overrides of the default level, matched by module path prefix.
+    ///
+    /// `Vec::new` doesn't allocate, so reading this before the heap exists is fine
+    ///
+    static ref TARGET_FILTERS: Mutex<Vec<(String, LevelFilter)>> = Mutex::new(Vec::new());
+}
+
+///
+/// Registers a new sink that will receive records up to `level`.
+///
+/// Returns `Err` if there are already `MAX_SINKS` sinks registered
+///
+pub fn register_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), &'static str> {
+    let mut sinks = SINKS.lock();
+    match sinks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(SinkSlot { sink, level });
+            Ok(())
+        }
+        None => Err("too many log sinks"),
+    }
+}
+
+///
+/// Changes the level of the sink called `name`. Returns false if there is no such sink
+///
+pub fn set_sink_level(name: &str, level: LevelFilter) -> bool {
+    let mut sinks = SINKS.lock();
+    for slot in sinks.iter_mut().flatten() {
+        if slot.sink.name() == name {
+            slot.level = level;
+            return true;
+        }
+    }
+    false
+}
+
+pub fn set_default_level(level: LevelFilter) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn default_level() -> LevelFilter {
+    LevelFilter::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed))
+}
+
+///
+/// Overrides the level for every target starting with `target` (e.g. `rust_os::task`).
+///
+/// Needs the heap
+///
+pub fn set_target_level(target: &str, level: LevelFilter) {
+    let mut filters = TARGET_FILTERS.lock();
+    match filters.iter_mut().find(|(name, _)| name == target) {
+        Some((_, filter)) => *filter = level,
+        None => filters.push((String::from(target), level)),
+    }
+}
+
+pub fn clear_target_level(target: &str) {
+    TARGET_FILTERS.lock().retain(|(name, _)| name != target);
+}
+
+///
+/// Level that applies to `target`: the longest matching override or the default one
+///
+pub fn level_for(target: &str) -> LevelFilter {
+    // Being changed right now (maybe by the code we interrupted), use the default
+    let filters = match TARGET_FILTERS.try_lock() {
+        Some(filters) => filters,
+        None => return default_level(),
+    };
+    filters
+        .iter()
+        .filter(|(name, _)| target.starts_with(name.as_str()))
+        .max_by_key(|(name, _)| name.len())
+        .map(|&(_, level)| level)
+        .unwrap_or_else(default_level)
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    level_for(target).allows(level)
+}
+
+///
+/// Calls `f` with every line kept in the ring buffer, oldest first
+///
+pub fn dmesg(f: impl FnMut(&str)) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        RING_BUFFER_SINK.buffer.lock().for_each_line(f);
+    });
+}
+
+#[doc(hidden)]
+///
+/// Internal entry point of the logging macros
+///
+pub fn _log(level: Level, target: &str, args: fmt::Arguments) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        if !enabled(level, target) {
+            return;
+        }
+        let record = Record {
+            level,
+            target,
+            timestamp: crate::time::uptime(),
+            wall_clock: SystemTime::now().map(|now| now.date_time()),
+            args,
+        };
+        // Copy the slots so a sink can log (or register another sink) without deadlocking.
+        // If they are being modified, at least the serial port gets the record
+        let sinks = match SINKS.try_lock() {
+            Some(sinks) => *sinks,
+            None => {
+                SERIAL_SINK.log(&record);
+                return;
+            }
+        };
+        for slot in sinks.iter().flatten() {
+            if slot.level.allows(level) {
+                slot.sink.log(&record);
+            }
+        }
+    });
+}
+
+/// Logs a record with the given level, optionally with an explicit `target:`
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => (
+        $crate::log::_log($level, $target, format_args!($($arg)+))
+    );
+    ($level:expr, $($arg:tt)+) => (
+        $crate::log::_log($level, module_path!(), format_args!($($arg)+))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+#[test_case]
+fn test_ring_buffer_evicts_whole_lines() {
+    use core::fmt::Write;
+    let mut ring = RingBuffer::new();
+    let line = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcde\n";
+    for _ in 0..(RING_BUFFER_SIZE / line.len() + 10) {
+        ring.write_str(line).unwrap();
+    }
+    assert!(ring.len() <= RING_BUFFER_SIZE);
+    ring.for_each_line(|stored| assert_eq!(stored, &line[..line.len() - 1]));
+}
+
+#[test_case]
+fn test_level_filter_allows() {
+    assert!(LevelFilter::Info.allows(Level::Error));
+    assert!(LevelFilter::Info.allows(Level::Info));
+    assert!(!LevelFilter::Info.allows(Level::Debug));
+    assert!(!LevelFilter::Off.allows(Level::Error));
+    assert_eq!("TRACE".parse(), Ok(LevelFilter::Trace));
+    assert!("loud".parse::<LevelFilter>().is_err());
+}
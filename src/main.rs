@@ -8,6 +8,8 @@ extern crate alloc;
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use core::panic::PanicInfo;
 use rust_os::allocator;
+use rust_os::framebuffer::{self, ConsoleKind};
+use rust_os::gui;
 use rust_os::memory;
 use rust_os::println;
 use rust_os::task::keyboard;
@@ -54,6 +56,67 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator =
         unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    let acpi = match rust_os::acpi::init() {
+        Ok(acpi) => Some(acpi),
+        Err(error) => {
+            rust_os::warn!("No ACPI: {}", error);
+            None
+        }
+    };
+    let topology = acpi
+        .and_then(|acpi| acpi.madt.as_ref())
+        .map(|madt| madt.topology())
+        .unwrap_or_else(rust_os::apic::Topology::legacy);
+    if let Err(error) = rust_os::apic::init(&topology, &mut mapper, &mut frame_allocator) {
+        rust_os::warn!("Staying with the PIC: {}", error);
+    }
+    let hpet = acpi
+        .and_then(|acpi| acpi.hpet.as_ref())
+        .map(|hpet| x86_64::PhysAddr::new(hpet.address.address));
+    rust_os::time::init(hpet, &mut mapper, &mut frame_allocator);
+    interrupts::without_interrupts(|| {
+        use rust_os::vga_buffer::{scrollback::DEFAULT_HISTORY_LINES, WRITER};
+        WRITER.lock().set_scrollback_lines(DEFAULT_HISTORY_LINES);
+    });
+    rust_os::vt::init();
+    if let Some(mcfg) = acpi.and_then(|acpi| acpi.mcfg.as_ref()) {
+        for entry in &mcfg.entries {
+            if let Err(error) = rust_os::pci::config::add_ecam_region(
+                entry.base,
+                entry.segment,
+                entry.start_bus,
+                entry.end_bus,
+                &mut mapper,
+                &mut frame_allocator,
+            ) {
+                rust_os::warn!(
+                    "Couldn't map the ECAM of segment {}: {:?}",
+                    entry.segment,
+                    error
+                );
+            }
+        }
+    }
+    rust_os::pci::init();
+    rust_os::driver::virtio::init(&mut mapper, &mut frame_allocator);
+    rust_os::vfs::init();
+    rust_os::block::init();
+    rust_os::driver::init();
+    let console = if cfg!(feature = "graphics-console") {
+        ConsoleKind::Graphics
+    } else {
+        ConsoleKind::Text
+    };
+    if let Err(error) = framebuffer::init_console(
+        console,
+        phys_memory_offset,
+        &mut mapper,
+        &mut frame_allocator,
+    ) {
+        rust_os::warn!("Falling back to the text console: {}", error);
+    }
+    let desktop = cfg!(feature = "desktop")
+        && start_desktop(phys_memory_offset, &mut mapper, &mut frame_allocator);
 
     struct C {
         c: u16,
@@ -91,21 +154,78 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Welcome :) Everything is fine");
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
     executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(rust_os::vfs::initrd::load()));
+    executor.spawn(Task::new(rust_os::block::partition::scan_devices()));
+    executor.spawn(Task::new(rust_os::block::cache::flush_periodically()));
+    // Terminal 0 keeps the kernel log, the shell is on Alt+F2
+    if let Some(terminal) = rust_os::vt::terminal(1) {
+        executor.spawn(Task::new(rust_os::shell::run(terminal)));
+    }
+    if desktop {
+        executor.spawn(Task::new(gui::compositor::run()));
+        executor.spawn(Task::new(paint_window()));
+    }
     executor.run();
 
     rust_os::hlt_loop();
 }
 
-async fn async_number() -> u32 {
-    42
+///
+/// Gives the screen to the window compositor. Returns false if there's no graphics mode
+///
+fn start_desktop(
+    phys_memory_offset: x86_64::VirtAddr,
+    mapper: &mut impl x86_64::structures::paging::Mapper<x86_64::structures::paging::Size4KiB>,
+    frame_allocator: &mut impl x86_64::structures::paging::FrameAllocator<
+        x86_64::structures::paging::Size4KiB,
+    >,
+) -> bool {
+    let framebuffer = match framebuffer::init(phys_memory_offset, mapper, frame_allocator) {
+        Ok(framebuffer) => framebuffer,
+        Err(error) => {
+            rust_os::warn!("No desktop: {}", error);
+            return false;
+        }
+    };
+    gui::compositor::init(framebuffer);
+    true
 }
 
-async fn example_task() {
-    for i in 0..10 {
-        let number = async_number().await;
-        println!("async number: {}", number);
+///
+/// Demo window: draws while the left button is held, `c` clears it
+///
+async fn paint_window() {
+    use futures_util::stream::StreamExt;
+    use gui::compositor::WindowEvent;
+    use gui::{Point, Rect};
+    use pc_keyboard::DecodedKey;
+    use rust_os::framebuffer::Rgb;
+
+    let window = match gui::compositor::create_window("Paint", Rect::new(100, 100, 320, 240)) {
+        Some(window) => window,
+        None => return,
+    };
+    let mut events = window.events();
+    let mut last: Option<Point> = None;
+    while let Some(event) = events.next().await {
+        match event {
+            WindowEvent::MouseDown(point, _) => {
+                window.draw(|painter| painter.fill_circle(point, 2, Rgb::BLACK));
+                last = Some(point);
+            }
+            WindowEvent::MouseMove(point, buttons) if buttons.left => {
+                if let Some(from) = last {
+                    window.draw(|painter| painter.draw_line(from, point, Rgb::BLACK));
+                }
+                last = Some(point);
+            }
+            WindowEvent::MouseUp(..) => last = None,
+            WindowEvent::Key(DecodedKey::Unicode('c')) => {
+                window.draw(|painter| painter.clear(Rgb::WHITE));
+            }
+            _ => {}
+        }
     }
 }
 
@@ -113,7 +233,9 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    rust_os::emergency::enter_panic_mode();
+    // Goes to the terminal on screen and to the serial port
+    rust_os::emergency_println!("{}", info);
     rust_os::hlt_loop();
 }
 
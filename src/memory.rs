@@ -1,15 +1,32 @@
-use crate::println;
+use crate::warn;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use x86_64::structures::paging::OffsetPageTable;
 use x86_64::PhysAddr;
 use x86_64::{structures::paging::PageTable, VirtAddr};
 
+///
+/// Where the bootloader mapped the physical memory, 0 until `init` is called
+///
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize a new offset pages table so we can fill it later
 /// with map
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+///
+/// The offset given to `init`, for code that needs to reach physical memory later on
+///
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
 ///
 /// Returns a mutable reference to the active level 4 table
 ///
@@ -37,6 +54,14 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -
     translate_addr_inner(addr, physical_memory_offset)
 }
 
+///
+/// Physical address behind a kernel virtual address, for handing buffers to devices
+/// that do DMA. `None` before `init` or if the address isn't mapped
+///
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    translate_addr_inner(addr, physical_memory_offset()?)
+}
+
 ///
 /// Example on how translating a physical memory offset type table map
 /// could work
@@ -102,6 +127,26 @@ use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 
 const SIZE_USABLE_FRAMES: usize = 32007;
 
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Frames the allocator can hand out
+    pub usable: usize,
+    pub allocated: usize,
+}
+
+///
+/// Usage of the physical frames of the `BootInfoFrameAllocator`
+///
+pub fn frame_stats() -> FrameStats {
+    FrameStats {
+        usable: USABLE_FRAMES.load(Ordering::Relaxed),
+        allocated: ALLOCATED_FRAMES.load(Ordering::Relaxed),
+    }
+}
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -124,12 +169,13 @@ impl BootInfoFrameAllocator {
         };
         for frame in boot_info_frame_allocator.usable_frames() {
             if i >= SIZE_USABLE_FRAMES {
-                println!("[WARNING] We are not covering all the available memory, that's ok though. If we want more memory set the constant to support more physical pages \nNumber of frames: {}. Available ones: {:?}", i, boot_info_frame_allocator.usable_frames().count() );
+                warn!("We are not covering all the available memory, that's ok though. If we want more memory set the constant to support more physical pages \nNumber of frames: {}. Available ones: {:?}", i, boot_info_frame_allocator.usable_frames().count() );
                 break;
             }
             boot_info_frame_allocator.available_frames[i] = Some(frame);
             i += 1;
         }
+        USABLE_FRAMES.store(i, Ordering::Relaxed);
         boot_info_frame_allocator
     }
 
@@ -154,6 +200,46 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.available_frames[self.next];
         self.next += 1;
+        if frame.is_some() {
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
         frame
     }
 }
+
+use x86_64::structures::paging::mapper::MapToError;
+
+///
+/// Virtual window where device memory gets mapped, far from the heap
+///
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+
+static NEXT_MMIO_ADDRESS: AtomicU64 = AtomicU64::new(MMIO_START);
+
+///
+/// Maps `size` bytes of device memory starting at `physical` into the MMIO window,
+/// uncached, and returns the virtual address of `physical`.
+///
+/// Mappings are never released
+///
+pub fn map_mmio(
+    physical: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(physical);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(physical + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let pages = frames.end.start_address() - frames.start.start_address() + 4096;
+    let virtual_start = NEXT_MMIO_ADDRESS.fetch_add(pages, Ordering::Relaxed);
+
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE;
+    for (i, frame) in frames.enumerate() {
+        let page = Page::containing_address(VirtAddr::new(virtual_start + i as u64 * 4096));
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    let page_offset = physical.as_u64() - first_frame.start_address().as_u64();
+    Ok(VirtAddr::new(virtual_start + page_offset))
+}
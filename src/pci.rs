@@ -0,0 +1,514 @@
+//!
+//! PCI bus
+//!
+//! Configuration space is reached through the legacy `0xCF8`/`0xCFC` ports, or through
+//! memory mapped ECAM regions once they have been registered with `add_ecam_region`
+//! (their location comes from the ACPI MCFG table). ECAM is the only way to reach
+//! segments other than 0 and the extended configuration space past offset 256.
+//!
+//! `init` enumerates every bus behind the host bridges and keeps what it found in a
+//! registry that drivers search with `find`.
+//!
+pub mod capability;
+pub mod config;
+
+pub use capability::{Capability, MsiCapability, MsixCapability};
+pub use config::{add_ecam_region, read_config, write_config};
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+// Offsets of the common configuration header
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0e;
+pub const BAR0: u16 = 0x10;
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+pub const INTERRUPT_LINE: u16 = 0x3c;
+pub const INTERRUPT_PIN: u16 = 0x3d;
+
+pub const COMMAND_IO_SPACE: u16 = 0x0001;
+pub const COMMAND_MEMORY_SPACE: u16 = 0x0002;
+pub const COMMAND_BUS_MASTER: u16 = 0x0004;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 0x0400;
+
+const STATUS_CAPABILITIES: u16 = 0x0010;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const NO_DEVICE: u16 = 0xffff;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_config(address, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_config(address, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+}
+
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    let aligned = offset & !0x3;
+    let shift = (offset & 0x2) * 8;
+    let old = read_config(address, aligned);
+    let new = old & !(0xffff << shift) | (value as u32) << shift;
+    write_config(address, aligned, new);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// A decoded base address register
+///
+pub enum Bar {
+    Memory32 {
+        address: u32,
+        size: u32,
+        prefetchable: bool,
+    },
+    Memory64 {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl Bar {
+    ///
+    /// Physical address (or port) the BAR points to
+    ///
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { address, .. } => address as u64,
+            Bar::Memory64 { address, .. } => address,
+            Bar::Io { port, .. } => port as u64,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { size, .. } => size as u64,
+            Bar::Memory64 { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+
+    pub fn is_io(&self) -> bool {
+        matches!(self, Bar::Io { .. })
+    }
+}
+
+///
+/// Writes all ones to the BAR and reads back which bits stuck to learn its size
+///
+fn probe_bar_size(address: PciAddress, offset: u16) -> u32 {
+    let original = read_config(address, offset);
+    write_config(address, offset, 0xffff_ffff);
+    let mask = read_config(address, offset);
+    write_config(address, offset, original);
+    mask
+}
+
+///
+/// Decodes the 6 BARs of a device (2 of a bridge). A 64 bit BAR takes two slots,
+/// the second one is left as `None`
+///
+fn read_bars(address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    // Decoding has to be off while the BARs hold garbage
+    let command = read_u16(address, COMMAND);
+    write_u16(
+        address,
+        COMMAND,
+        command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index as u16 * 4;
+        let value = read_config(address, offset);
+        if value & 0x1 == 1 {
+            let mask = probe_bar_size(address, offset) & 0xffff_fffc;
+            if mask != 0 {
+                bars[index] = Some(Bar::Io {
+                    port: (value & 0xffff_fffc) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                });
+            }
+            index += 1;
+            continue;
+        }
+        let prefetchable = value & 0x8 != 0;
+        match (value >> 1) & 0x3 {
+            // 64 bit
+            0x2 if index + 1 < count => {
+                let high_offset = offset + 4;
+                let high = read_config(address, high_offset);
+                let low_mask = probe_bar_size(address, offset) & 0xffff_fff0;
+                let high_mask = probe_bar_size(address, high_offset);
+                let mask = (high_mask as u64) << 32 | low_mask as u64;
+                if mask != 0 {
+                    bars[index] = Some(Bar::Memory64 {
+                        address: (high as u64) << 32 | (value & 0xffff_fff0) as u64,
+                        size: (!mask).wrapping_add(1),
+                        prefetchable,
+                    });
+                }
+                index += 2;
+            }
+            _ => {
+                let mask = probe_bar_size(address, offset) & 0xffff_fff0;
+                if mask != 0 {
+                    bars[index] = Some(Bar::Memory32 {
+                        address: value & 0xffff_fff0,
+                        size: (!mask).wrapping_add(1),
+                        prefetchable,
+                    });
+                }
+                index += 1;
+            }
+        }
+    }
+    write_u16(address, COMMAND, command);
+    bars
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Legacy IRQ line set up by the firmware, 0xff if none
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the device doesn't use legacy interrupts
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    pub msi: Option<MsiCapability>,
+    pub msix: Option<MsixCapability>,
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Option<Self> {
+        let vendor_id = read_u16(address, VENDOR_ID);
+        if vendor_id == NO_DEVICE {
+            return None;
+        }
+        let class_register = read_config(address, REVISION);
+        let header_type = read_u8(address, HEADER_TYPE) & HEADER_TYPE_MASK;
+        let bar_count = match header_type {
+            0x00 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let capabilities = if read_u16(address, STATUS) & STATUS_CAPABILITIES != 0 {
+            capability::read_list(address, read_u8(address, CAPABILITIES_POINTER))
+        } else {
+            Vec::new()
+        };
+        let msi = capabilities
+            .iter()
+            .find_map(|capability| MsiCapability::read(address, capability));
+        let msix = capabilities
+            .iter()
+            .find_map(|capability| MsixCapability::read(address, capability));
+        Some(Self {
+            address,
+            vendor_id,
+            device_id: read_u16(address, DEVICE_ID),
+            class: (class_register >> 24) as u8,
+            subclass: (class_register >> 16) as u8,
+            prog_if: (class_register >> 8) as u8,
+            revision: class_register as u8,
+            header_type,
+            interrupt_line: read_u8(address, INTERRUPT_LINE),
+            interrupt_pin: read_u8(address, INTERRUPT_PIN),
+            bars: read_bars(address, bar_count),
+            capabilities,
+            msi,
+            msix,
+        })
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.class == CLASS_BRIDGE && self.subclass == SUBCLASS_PCI_BRIDGE
+    }
+
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        write_config(self.address, offset, value)
+    }
+
+    ///
+    /// Sets bits of the command register
+    ///
+    pub fn enable(&self, command_bits: u16) {
+        let command = read_u16(self.address, COMMAND);
+        write_u16(self.address, COMMAND, command | command_bits);
+    }
+
+    ///
+    /// Lets the device decode its BARs and do DMA
+    ///
+    pub fn enable_bus_master(&self) {
+        self.enable(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.class_name()
+        )
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, _) => "mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x09, _) => "input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+///
+/// What a driver looks for. Fields left as `None` match anything
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn field<T: PartialEq>(wanted: Option<T>, value: T) -> bool {
+            wanted.map_or(true, |wanted| wanted == value)
+        }
+        field(self.vendor_id, device.vendor_id)
+            && field(self.device_id, device.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let first = PciAddress::new(segment, bus, device, 0);
+        if read_u16(first, VENDOR_ID) == NO_DEVICE {
+            continue;
+        }
+        let functions = if read_u8(first, HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 {
+            8
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let address = PciAddress::new(segment, bus, device, function);
+            if let Some(found) = PciDevice::read(address) {
+                let secondary_bus = if found.is_bridge() {
+                    Some(read_u8(address, SECONDARY_BUS))
+                } else {
+                    None
+                };
+                devices.push(found);
+                if let Some(secondary_bus) = secondary_bus.filter(|&secondary| secondary > bus) {
+                    scan_bus(segment, secondary_bus, devices);
+                }
+            }
+        }
+    }
+}
+
+///
+/// Finds every device of the segments we can reach
+///
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for segment in config::segments() {
+        let host = PciAddress::new(segment, 0, 0, 0);
+        if read_u8(host, HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+            scan_bus(segment, 0, &mut devices);
+        } else {
+            // Several host controllers, function N is the one of bus N
+            for function in 0..8 {
+                let host = PciAddress::new(segment, 0, 0, function);
+                if read_u16(host, VENDOR_ID) != NO_DEVICE {
+                    scan_bus(segment, function, &mut devices);
+                }
+            }
+        }
+    }
+    devices
+}
+
+///
+/// Scans the buses and fills the registry. Can be called again, for instance after
+/// ECAM became available
+///
+pub fn init() {
+    let devices = enumerate();
+    crate::info!("pci: {} devices", devices.len());
+    for device in &devices {
+        crate::debug!("pci: {}", device);
+    }
+    *DEVICES.lock() = devices;
+    crate::shell::register(crate::shell::Command {
+        name: "lspci",
+        help: "lists the PCI devices",
+        handler: lspci,
+    });
+}
+
+///
+/// Copy of the registry
+///
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+///
+/// Devices matching `wanted`, in bus order
+///
+pub fn find(wanted: &PciMatch) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|device| wanted.matches(device))
+        .cloned()
+        .collect()
+}
+
+fn lspci(out: &mut dyn fmt::Write, _arguments: &[&str]) -> crate::shell::CommandResult {
+    for device in DEVICES.lock().iter() {
+        write!(out, "{}", device)?;
+        if device.msix.is_some() {
+            write!(out, " [MSI-X]")?;
+        } else if device.msi.is_some() {
+            write!(out, " [MSI]")?;
+        }
+        writeln!(out)?;
+        for (index, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                let kind = if bar.is_io() { "I/O" } else { "memory" };
+                writeln!(
+                    out,
+                    "    BAR{} {} at {:#x} ({:#x} bytes)",
+                    index,
+                    kind,
+                    bar.address(),
+                    bar.size()
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_pci_host_bridge_is_present() {
+    // QEMU always has a host bridge at 00:00.0
+    let host = PciAddress::new(0, 0, 0, 0);
+    assert_ne!(read_u16(host, VENDOR_ID), NO_DEVICE);
+    assert_eq!(read_u8(host, 0x0b), CLASS_BRIDGE);
+}
@@ -0,0 +1,134 @@
+//!
+//! Capability list of a device, and the MSI and MSI-X capabilities
+//!
+use super::{read_config, read_u16, read_u8, write_u16, PciAddress};
+use alloc::vec::Vec;
+
+pub const ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const ID_MSI: u8 = 0x05;
+pub const ID_VENDOR_SPECIFIC: u8 = 0x09;
+pub const ID_PCI_EXPRESS: u8 = 0x10;
+pub const ID_MSIX: u8 = 0x11;
+
+/// A broken list could loop forever, there's room for at most this many entries
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where it starts in the configuration space
+    pub offset: u16,
+}
+
+///
+/// Follows the linked list of capabilities starting at `pointer`
+///
+pub(super) fn read_list(address: PciAddress, pointer: u8) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    let mut pointer = pointer & 0xfc;
+    while pointer != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let offset = pointer as u16;
+        capabilities.push(Capability {
+            id: read_u8(address, offset),
+            offset,
+        });
+        pointer = read_u8(address, offset + 1) & 0xfc;
+    }
+    capabilities
+}
+
+const MSI_CONTROL_ENABLE: u16 = 0x0001;
+const MSI_CONTROL_64_BIT: u16 = 0x0080;
+const MSI_CONTROL_PER_VECTOR_MASKING: u16 = 0x0100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiCapability {
+    pub offset: u16,
+    pub is_64_bit: bool,
+    pub per_vector_masking: bool,
+    /// How many vectors the device can use, a power of 2 up to 32
+    pub max_vectors: u8,
+}
+
+impl MsiCapability {
+    pub fn read(address: PciAddress, capability: &Capability) -> Option<Self> {
+        if capability.id != ID_MSI {
+            return None;
+        }
+        let control = read_u16(address, capability.offset + 2);
+        Some(Self {
+            offset: capability.offset,
+            is_64_bit: control & MSI_CONTROL_64_BIT != 0,
+            per_vector_masking: control & MSI_CONTROL_PER_VECTOR_MASKING != 0,
+            max_vectors: 1 << ((control >> 1) & 0x7).min(5),
+        })
+    }
+
+    ///
+    /// Points the device at `message_address` with `message_data` and enables MSI
+    /// with a single vector
+    ///
+    pub fn enable(&self, address: PciAddress, message_address: u64, message_data: u16) {
+        let offset = self.offset;
+        super::write_config(address, offset + 4, message_address as u32);
+        let data_offset = if self.is_64_bit {
+            super::write_config(address, offset + 8, (message_address >> 32) as u32);
+            offset + 12
+        } else {
+            offset + 8
+        };
+        write_u16(address, data_offset, message_data);
+        let control = read_u16(address, offset + 2);
+        // Multiple message enable = 0: one vector
+        write_u16(address, offset + 2, control & !0x0070 | MSI_CONTROL_ENABLE);
+    }
+}
+
+const MSIX_CONTROL_ENABLE: u16 = 0x8000;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 0x4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsixCapability {
+    pub offset: u16,
+    /// Number of entries of the vector table
+    pub table_size: u16,
+    /// BAR holding the vector table and offset inside of it
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// BAR holding the pending bit array and offset inside of it
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+impl MsixCapability {
+    pub fn read(address: PciAddress, capability: &Capability) -> Option<Self> {
+        if capability.id != ID_MSIX {
+            return None;
+        }
+        let control = read_u16(address, capability.offset + 2);
+        let table = read_config(address, capability.offset + 4);
+        let pba = read_config(address, capability.offset + 8);
+        Some(Self {
+            offset: capability.offset,
+            table_size: (control & 0x07ff) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+        })
+    }
+
+    ///
+    /// Enables MSI-X. The vectors themselves are set up in the table, which is in
+    /// memory behind `table_bar`
+    ///
+    pub fn set_enabled(&self, address: PciAddress, enabled: bool) {
+        let control = read_u16(address, self.offset + 2) & !MSIX_CONTROL_FUNCTION_MASK;
+        let control = if enabled {
+            control | MSIX_CONTROL_ENABLE
+        } else {
+            control & !MSIX_CONTROL_ENABLE
+        };
+        write_u16(address, self.offset + 2, control);
+    }
+}
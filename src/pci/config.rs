@@ -0,0 +1,139 @@
+//!
+//! Configuration space access
+//!
+use super::PciAddress;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+
+/// Size of the ECAM window of one bus: 32 devices, 8 functions, 4 KiB each
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+///
+/// A memory mapped configuration space window, described by an MCFG entry
+///
+#[derive(Debug, Clone, Copy)]
+struct EcamRegion {
+    base: VirtAddr,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl EcamRegion {
+    fn address_of(&self, address: PciAddress, offset: u16) -> Option<VirtAddr> {
+        if address.segment != self.segment
+            || address.bus < self.start_bus
+            || address.bus > self.end_bus
+        {
+            return None;
+        }
+        let function_offset = ((address.bus - self.start_bus) as u64) << 20
+            | (address.device as u64) << 15
+            | (address.function as u64) << 12;
+        Some(self.base + function_offset + (offset & 0xffc) as u64)
+    }
+}
+
+static ECAM_REGIONS: Mutex<Vec<EcamRegion>> = Mutex::new(Vec::new());
+
+///
+/// The configuration registers are reached through an index/data port pair, so the
+/// pair has to be used by one CPU at a time
+///
+static LEGACY_PORTS: Mutex<()> = Mutex::new(());
+
+///
+/// Maps the ECAM window of buses `start_bus..=end_bus` of `segment`, found at `base`.
+/// From now on configuration accesses to those buses go through memory
+///
+pub fn add_ecam_region(
+    base: PhysAddr,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let size = (end_bus as u64 - start_bus as u64 + 1) * ECAM_BUS_SIZE;
+    let mapped = crate::memory::map_mmio(base, size, mapper, frame_allocator)?;
+    ECAM_REGIONS.lock().push(EcamRegion {
+        base: mapped,
+        segment,
+        start_bus,
+        end_bus,
+    });
+    Ok(())
+}
+
+fn ecam_address(address: PciAddress, offset: u16) -> Option<VirtAddr> {
+    ECAM_REGIONS
+        .lock()
+        .iter()
+        .find_map(|region| region.address_of(address, offset))
+}
+
+///
+/// Segments with a configuration space we can reach. Segment 0 always is, through the ports
+///
+pub fn segments() -> Vec<u16> {
+    let mut segments: Vec<u16> = ECAM_REGIONS
+        .lock()
+        .iter()
+        .map(|region| region.segment)
+        .collect();
+    segments.push(0);
+    segments.sort_unstable();
+    segments.dedup();
+    segments
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    0x8000_0000
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xfc) as u32
+}
+
+///
+/// Reads the dword at `offset` (rounded down to 4). Registers that can't be reached
+/// read as all ones, like a missing device
+///
+pub fn read_config(address: PciAddress, offset: u16) -> u32 {
+    if let Some(virtual_address) = ecam_address(address, offset) {
+        return unsafe { (virtual_address.as_ptr() as *const u32).read_volatile() };
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return 0xffff_ffff;
+    }
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+    let mut data_port: Port<u32> = Port::new(CONFIG_DATA_PORT);
+    let _guard = LEGACY_PORTS.lock();
+    unsafe {
+        address_port.write(legacy_address(address, offset));
+        data_port.read()
+    }
+}
+
+pub fn write_config(address: PciAddress, offset: u16, value: u32) {
+    if let Some(virtual_address) = ecam_address(address, offset) {
+        unsafe { (virtual_address.as_mut_ptr() as *mut u32).write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return;
+    }
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+    let mut data_port: Port<u32> = Port::new(CONFIG_DATA_PORT);
+    let _guard = LEGACY_PORTS.lock();
+    unsafe {
+        address_port.write(legacy_address(address, offset));
+        data_port.write(value);
+    }
+}
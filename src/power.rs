@@ -0,0 +1,157 @@
+//!
+//! Rebooting and powering off the machine
+//!
+//! Both try ACPI first, which is what works on real machines, and then the older
+//! mechanisms: the reset line of the 8042 and a triple fault to reboot, the shutdown
+//! ports of the emulators to power off.
+//!
+use crate::acpi::{self, AddressSpace, Fadt};
+use crate::{hlt_loop, warn};
+use x86_64::instructions::port::Port;
+
+const PM1_CONTROL_SCI_ENABLE: u16 = 1 << 0;
+const PM1_CONTROL_SLEEP_ENABLE: u16 = 1 << 13;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SOFT_OFF: u8 = 5;
+
+/// How many times a register is polled before giving up
+const TIMEOUT: usize = 100_000;
+
+///
+/// Restarts the machine: ACPI reset register, reset line of the keyboard controller and
+/// then a triple fault. The drivers get to shut their devices down first
+///
+pub fn reboot() -> ! {
+    crate::driver::shutdown_all();
+    x86_64::instructions::interrupts::disable();
+    let fadt = acpi::get().and_then(|acpi| acpi.fadt.as_ref());
+    if let Some(fadt) = fadt {
+        acpi_reset(fadt);
+    }
+    if fadt.map_or(true, |fadt| fadt.has_8042) {
+        keyboard_controller_reset();
+    }
+    triple_fault()
+}
+
+///
+/// Writes the reset value of the FADT to its register, if the machine has one
+///
+fn acpi_reset(fadt: &Fadt) {
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+    match register.space {
+        AddressSpace::Io => unsafe {
+            Port::<u8>::new(register.address as u16).write(fadt.reset_value);
+        },
+        AddressSpace::Memory => {
+            if let Some(offset) = crate::memory::physical_memory_offset() {
+                let address = offset + register.address;
+                unsafe { address.as_mut_ptr::<u8>().write_volatile(fadt.reset_value) };
+            }
+        }
+        _ => return,
+    }
+    // Give the chipset a moment before trying something else
+    for _ in 0..TIMEOUT {
+        core::hint::spin_loop();
+    }
+}
+
+fn keyboard_controller_reset() {
+    let mut status: Port<u8> = Port::new(0x64);
+    unsafe {
+        // Wait for the controller input buffer to be empty and pulse the reset line
+        for _ in 0..TIMEOUT {
+            if status.read() & 0x02 == 0 {
+                break;
+            }
+        }
+        status.write(0xfe);
+    }
+}
+
+///
+/// Loads an empty IDT and raises an exception, which the CPU can't deliver
+///
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    hlt_loop();
+}
+
+///
+/// Switches to ACPI mode, if the firmware left the machine in legacy mode
+///
+fn enable_acpi(fadt: &Fadt) -> Result<(), &'static str> {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control_block);
+    if unsafe { control.read() } & PM1_CONTROL_SCI_ENABLE != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Err("can't switch to ACPI mode");
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command).write(fadt.acpi_enable) };
+    for _ in 0..TIMEOUT {
+        if unsafe { control.read() } & PM1_CONTROL_SCI_ENABLE != 0 {
+            return Ok(());
+        }
+    }
+    Err("the firmware didn't switch to ACPI mode")
+}
+
+///
+/// Enters the S5 sleep state with the SLP_TYP values of the `\_S5` package
+///
+fn acpi_power_off() -> Result<(), &'static str> {
+    let acpi = acpi::get().ok_or("no ACPI tables")?;
+    let fadt = acpi.fadt.as_ref().ok_or("no FADT")?;
+    if fadt.pm1a_control_block == 0 {
+        return Err("no PM1a control register");
+    }
+    let sleep_type = acpi.sleep_type(SOFT_OFF).ok_or("no \\_S5 package")?;
+    enable_acpi(fadt)?;
+    let write = |block: u16, sleep_type: u8| {
+        let mut control: Port<u16> = Port::new(block);
+        unsafe {
+            let value = control.read() & !(0x7 << SLEEP_TYPE_SHIFT);
+            control
+                .write(value | (sleep_type as u16) << SLEEP_TYPE_SHIFT | PM1_CONTROL_SLEEP_ENABLE);
+        }
+    };
+    write(fadt.pm1a_control_block, sleep_type.a);
+    if fadt.pm1b_control_block != 0 {
+        write(fadt.pm1b_control_block, sleep_type.b);
+    }
+    for _ in 0..TIMEOUT {
+        core::hint::spin_loop();
+    }
+    Err("still running after entering S5")
+}
+
+///
+/// Powers off the machine through ACPI, or with the shutdown ports of the emulators if
+/// that fails. Anywhere else this just halts. The drivers get to shut their devices
+/// down first
+///
+pub fn shutdown() -> ! {
+    crate::driver::shutdown_all();
+    x86_64::instructions::interrupts::disable();
+    if let Err(error) = acpi_power_off() {
+        warn!("ACPI power off failed: {}", error);
+    }
+    unsafe {
+        // QEMU (PIIX4 and Q35 ACPI ports), then old QEMU and Bochs, then VirtualBox
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xb004).write(0x2000);
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+    warn!("Couldn't power off, halting");
+    hlt_loop();
+}
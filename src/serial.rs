@@ -13,6 +13,40 @@ lazy_static! {
     };
 }
 
+const COM1: u16 = 0x3F8;
+
+///
+/// Writes straight to the COM1 registers without going through `SERIAL1`.
+///
+/// Only the emergency output uses it, when `SERIAL1` is held by the code we interrupted.
+/// It assumes the port was already initialized by `SERIAL1`
+///
+pub struct RawSerial;
+
+impl RawSerial {
+    pub fn send(&mut self, byte: u8) {
+        use x86_64::instructions::port::Port;
+        let mut data: Port<u8> = Port::new(COM1);
+        let mut line_status: Port<u8> = Port::new(COM1 + 5);
+        unsafe {
+            // Wait until the transmitter holding register is empty
+            while line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            data.write(byte);
+        }
+    }
+}
+
+impl core::fmt::Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -0,0 +1,160 @@
+//!
+//! Kernel shell
+//!
+//! An async task that reads lines from a virtual terminal and runs the command named by
+//! the first word. Commands live in a registry so other modules can add their own with
+//! `register`; the built-in ones are in `commands`.
+//!
+pub mod commands;
+
+use crate::vt::Terminal;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use futures_util::stream::StreamExt;
+use spin::Mutex;
+
+pub const PROMPT: &str = "> ";
+
+const MAX_LINE: usize = 256;
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// Wrong arguments, holds the expected usage
+    Usage(&'static str),
+    Failed(String),
+    /// Writing the output failed
+    Output,
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> Self {
+        CommandError::Output
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::Failed(message) => write!(f, "{}", message),
+            CommandError::Output => write!(f, "couldn't write the output"),
+        }
+    }
+}
+
+pub type CommandResult = Result<(), CommandError>;
+
+///
+/// Runs a command. Gets where to write its output and the arguments after the name
+///
+pub type Handler = fn(&mut dyn fmt::Write, &[&str]) -> CommandResult;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// One line shown by `help`
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+///
+/// Adds a command, replacing the one with the same name if there is one
+///
+pub fn register(command: Command) {
+    let mut commands = COMMANDS.lock();
+    match commands
+        .iter_mut()
+        .find(|existing| existing.name == command.name)
+    {
+        Some(existing) => *existing = command,
+        None => commands.push(command),
+    }
+}
+
+pub fn find(name: &str) -> Option<Command> {
+    COMMANDS
+        .lock()
+        .iter()
+        .find(|command| command.name == name)
+        .copied()
+}
+
+///
+/// Copy of the registered commands, sorted by name
+///
+pub fn commands() -> Vec<Command> {
+    let mut commands = COMMANDS.lock().clone();
+    commands.sort_by_key(|command| command.name);
+    commands
+}
+
+///
+/// Splits `line` in words and runs it
+///
+pub fn execute(out: &mut dyn fmt::Write, line: &str) -> CommandResult {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, arguments) = match words.split_first() {
+        Some((name, arguments)) => (*name, arguments),
+        None => return Ok(()),
+    };
+    // Don't hold the registry lock while the command runs, it might register others
+    let command = find(name)
+        .ok_or_else(|| CommandError::Failed(alloc::format!("{}: command not found", name)))?;
+    (command.handler)(out, arguments)
+}
+
+///
+/// Writes to a virtual terminal
+///
+pub struct TerminalOutput(&'static Terminal);
+
+impl fmt::Write for TerminalOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_fmt(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+///
+/// Shell task reading from `terminal`. The terminal stops echoing keys, the shell
+/// does it itself so it can handle backspace
+///
+pub async fn run(terminal: &'static Terminal) {
+    use core::fmt::Write;
+    commands::register_builtins();
+    terminal.set_echo(false);
+    let mut out = TerminalOutput(terminal);
+    let mut input = terminal.input();
+    let mut line = String::new();
+    let _ = write!(
+        out,
+        "Kernel shell, type `help` to list the commands\n{}",
+        PROMPT
+    );
+    while let Some(character) = input.next().await {
+        match character {
+            '\n' => {
+                let _ = writeln!(out);
+                if let Err(error) = execute(&mut out, &line) {
+                    let _ = writeln!(out, "{}", error);
+                }
+                line.clear();
+                let _ = write!(out, "{}", PROMPT);
+            }
+            // Backspace
+            '\u{8}' => {
+                if line.pop().is_some() {
+                    let _ = write!(out, "\u{8} \u{8}");
+                }
+            }
+            character if !character.is_control() && line.len() < MAX_LINE => {
+                line.push(character);
+                let _ = write!(out, "{}", character);
+            }
+            _ => {}
+        }
+    }
+}
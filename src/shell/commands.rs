@@ -0,0 +1,245 @@
+//!
+//! Built-in shell commands
+//!
+use super::{register, Command, CommandError, CommandResult};
+use core::fmt::Write;
+
+const BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "lists the commands",
+        handler: help,
+    },
+    Command {
+        name: "mem",
+        help: "physical frame and heap usage",
+        handler: mem,
+    },
+    Command {
+        name: "tasks",
+        help: "state of the async executor",
+        handler: tasks,
+    },
+    Command {
+        name: "irq",
+        help: "how many times each interrupt line fired",
+        handler: irq,
+    },
+    Command {
+        name: "pt",
+        help: "pt <address>: walks the page tables for a virtual address",
+        handler: pt,
+    },
+    Command {
+        name: "uptime",
+        help: "time since boot",
+        handler: uptime,
+    },
+    Command {
+        name: "date",
+        help: "date and time in UTC",
+        handler: date,
+    },
+    Command {
+        name: "dmesg",
+        help: "kernel log kept in memory",
+        handler: dmesg,
+    },
+    Command {
+        name: "clear",
+        help: "clears the screen",
+        handler: clear,
+    },
+    Command {
+        name: "reboot",
+        help: "restarts the machine",
+        handler: reboot,
+    },
+    Command {
+        name: "shutdown",
+        help: "powers off the machine",
+        handler: shutdown,
+    },
+];
+
+pub fn register_builtins() {
+    for &command in BUILTINS {
+        register(command);
+    }
+}
+
+fn help(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    for command in super::commands() {
+        writeln!(out, "{:<10} {}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn mem(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    let frames = crate::memory::frame_stats();
+    writeln!(
+        out,
+        "frames: {} used of {} ({} KiB of {} KiB)",
+        frames.allocated,
+        frames.usable,
+        frames.allocated * 4,
+        frames.usable * 4
+    )?;
+    let heap = crate::allocator::stats();
+    writeln!(
+        out,
+        "heap:   {} bytes used of {} ({}%)",
+        heap.used,
+        heap.size,
+        heap.used * 100 / heap.size
+    )?;
+    Ok(())
+}
+
+fn tasks(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    let stats = crate::task::executor::stats();
+    writeln!(out, "running:   {}", stats.running)?;
+    writeln!(out, "spawned:   {}", stats.spawned)?;
+    writeln!(out, "completed: {}", stats.completed)?;
+    writeln!(out, "polls:     {}", stats.polls)?;
+    writeln!(out, "queued:    {}", stats.pending_spawns)?;
+    Ok(())
+}
+
+const IRQ_NAMES: [&str; 16] = [
+    "timer", "keyboard", "cascade", "com2", "com1", "lpt2", "floppy", "lpt1", "rtc", "", "", "",
+    "mouse", "fpu", "ata0", "ata1",
+];
+
+fn irq(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    for (line, count) in crate::interrupts::irq_counts().iter().enumerate() {
+        if *count > 0 {
+            let name = IRQ_NAMES.get(line).copied().unwrap_or("");
+            writeln!(out, "{:>2} {:<10} {}", line, name, count)?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Parses a decimal or `0x` prefixed hexadecimal number, `_` separators allowed
+///
+fn parse_address(text: &str) -> Option<u64> {
+    let (digits, radix) = match text.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (text, 10),
+    };
+    let mut value: u64 = 0;
+    let mut seen_digit = false;
+    for character in digits.chars().filter(|&character| character != '_') {
+        let digit = character.to_digit(radix)?;
+        value = value.checked_mul(radix as u64)?.checked_add(digit as u64)?;
+        seen_digit = true;
+    }
+    if seen_digit {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn pt(out: &mut dyn Write, arguments: &[&str]) -> CommandResult {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::{PageTable, PageTableFlags};
+    use x86_64::{PhysAddr, VirtAddr};
+
+    const USAGE: &str = "pt <address>";
+    let address = match arguments {
+        [address] => parse_address(address).ok_or(CommandError::Usage(USAGE))?,
+        _ => return Err(CommandError::Usage(USAGE)),
+    };
+    let address = VirtAddr::try_new(address)
+        .map_err(|_| CommandError::Failed(alloc::format!("{:#x} is not canonical", address)))?;
+    let offset = crate::memory::physical_memory_offset()
+        .ok_or_else(|| CommandError::Failed("memory is not initialized".into()))?;
+
+    let mut table_address = Cr3::read().0.start_address();
+    for level in (1..=4).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let index = (address.as_u64() >> shift) as usize & 0x1ff;
+        let table: &PageTable = unsafe { &*(offset + table_address.as_u64()).as_ptr() };
+        let entry = &table[index];
+        writeln!(
+            out,
+            "L{} [{:>3}] {:#012x} {:?}",
+            level,
+            index,
+            entry.addr().as_u64(),
+            entry.flags()
+        )?;
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            writeln!(out, "not mapped")?;
+            return Ok(());
+        }
+        // Level 3 and 2 entries can map 1 GiB and 2 MiB pages directly
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_offset = address.as_u64() & ((1 << shift) - 1);
+            let physical = PhysAddr::new(entry.addr().as_u64() + page_offset);
+            writeln!(out, "{:#x} -> {:#x}", address.as_u64(), physical.as_u64())?;
+            return Ok(());
+        }
+        table_address = entry.addr();
+    }
+    Ok(())
+}
+
+fn uptime(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    let uptime = crate::time::uptime();
+    writeln!(
+        out,
+        "up {}.{:03} s ({} ticks, clock source {})",
+        uptime.as_secs(),
+        uptime.subsec_millis(),
+        crate::time::ticks(),
+        crate::time::clock_source().name()
+    )?;
+    Ok(())
+}
+
+fn date(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    let now = crate::time::rtc::SystemTime::now()
+        .ok_or_else(|| CommandError::Failed("the clock hasn't been read".into()))?;
+    writeln!(
+        out,
+        "{} UTC ({} s since the epoch)",
+        now.date_time(),
+        now.unix_seconds()
+    )?;
+    Ok(())
+}
+
+fn dmesg(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    // Copy first, the terminal can't be written while the log buffer is locked
+    let mut lines = alloc::vec::Vec::new();
+    crate::log::dmesg(|line| lines.push(alloc::string::String::from(line)));
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+fn clear(out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    write!(out, "\x1b[2J\x1b[H")?;
+    Ok(())
+}
+
+fn reboot(_out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    crate::power::reboot()
+}
+
+fn shutdown(_out: &mut dyn Write, _arguments: &[&str]) -> CommandResult {
+    crate::power::shutdown()
+}
+
+#[test_case]
+fn test_parse_address() {
+    assert_eq!(parse_address("0xb8000"), Some(0xb8000));
+    assert_eq!(parse_address("0x_4444_4444_0000"), Some(0x4444_4444_0000));
+    assert_eq!(parse_address("4096"), Some(4096));
+    assert_eq!(parse_address("zz"), None);
+}
@@ -1,6 +1,7 @@
 use super::{Task, TaskId};
 
 use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use core::task::Waker;
 use core::task::{Context, Poll};
 use crossbeam_queue::ArrayQueue;
@@ -12,6 +13,45 @@ lazy_static! {
     static ref SPAWNED_TASKS: Arc<ArrayQueue<Task>> = Arc::new(ArrayQueue::new(500));
 }
 
+///
+/// Counters updated by the executor, readable from anywhere
+///
+struct Stats {
+    spawned: AtomicU64,
+    completed: AtomicU64,
+    polls: AtomicU64,
+    running: AtomicUsize,
+}
+
+static STATS: Stats = Stats {
+    spawned: AtomicU64::new(0),
+    completed: AtomicU64::new(0),
+    polls: AtomicU64::new(0),
+    running: AtomicUsize::new(0),
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutorStats {
+    pub spawned: u64,
+    pub completed: u64,
+    /// Times a task was polled
+    pub polls: u64,
+    /// Tasks that haven't finished yet
+    pub running: usize,
+    /// Tasks spawned from other tasks that the executor didn't pick up yet
+    pub pending_spawns: usize,
+}
+
+pub fn stats() -> ExecutorStats {
+    ExecutorStats {
+        spawned: STATS.spawned.load(Ordering::Relaxed),
+        completed: STATS.completed.load(Ordering::Relaxed),
+        polls: STATS.polls.load(Ordering::Relaxed),
+        running: STATS.running.load(Ordering::Relaxed),
+        pending_spawns: SPAWNED_TASKS.len(),
+    }
+}
+
 /// Proper Executor that doesn't constantly poll futures
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
@@ -36,6 +76,8 @@ impl Executor {
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with the same ID already exists");
         }
+        STATS.spawned.fetch_add(1, Ordering::Relaxed);
+        STATS.running.store(self.tasks.len(), Ordering::Relaxed);
         self.task_queue
             .push(task_id)
             .expect("queue is full. consider increasing the number of concurrent tasks");
@@ -55,21 +97,12 @@ impl Executor {
             self.sleep_if_idle();
         }
     }
-    async fn e() -> u64 {
-        3
-    }
-
-    async fn uwu() {
-        use crate::println;
-        println!("{}", Executor::e().await);
-    }
     fn sleep_if_idle(&mut self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
         if self.task_queue.is_empty() && SPAWNED_TASKS.len() == 0 {
             enable_and_hlt();
-            Executor::spawn_task().push(Task::new(Executor::uwu()));
         } else {
             interrupts::enable();
         }
@@ -93,10 +126,13 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
+            STATS.polls.fetch_add(1, Ordering::Relaxed);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    STATS.completed.fetch_add(1, Ordering::Relaxed);
+                    STATS.running.store(tasks.len(), Ordering::Relaxed);
                 }
                 Poll::Pending => {}
             }
@@ -7,7 +7,7 @@ use crossbeam_queue::ArrayQueue;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
-use crate::println;
+use crate::error;
 
 pub struct ScancodeStream {
     _private: (),
@@ -47,27 +47,148 @@ impl Stream for ScancodeStream {
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("[ERROR] keyboard input is full!");
+            error!("keyboard input is full!");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("[ERROR]: scancode queue uninitialized");
+        error!("scancode queue uninitialized");
     }
 }
 use crate::print;
+use crate::vga_buffer::BUFFER_HEIGHT;
+use crate::vt;
 use futures_util::stream::StreamExt;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1,
+};
+
+///
+/// Modifier keys we care about before the event reaches the layout
+///
+#[derive(Debug, Default)]
+struct Modifiers {
+    left_shift: bool,
+    right_shift: bool,
+    left_alt: bool,
+    right_alt: bool,
+}
+
+impl Modifiers {
+    fn update(&mut self, event: &KeyEvent) {
+        let pressed = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft => self.left_shift = pressed,
+            KeyCode::ShiftRight => self.right_shift = pressed,
+            KeyCode::AltLeft => self.left_alt = pressed,
+            KeyCode::AltRight => self.right_alt = pressed,
+            _ => {}
+        }
+    }
+
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+}
+
+///
+/// Handles the console shortcuts:
+///
+/// * Shift+PageUp/PageDown scroll through the history of the active terminal
+/// * Alt+F1..F6 switch virtual terminals
+///
+/// Returns true if the event was consumed
+///
+fn handle_console_shortcut(modifiers: &Modifiers, event: &KeyEvent) -> bool {
+    use x86_64::instructions::interrupts;
+    if event.state != KeyState::Down {
+        return false;
+    }
+    let writer = match vt::active() {
+        Some(terminal) => terminal.writer(),
+        None => &crate::vga_buffer::WRITER,
+    };
+    if modifiers.shift() {
+        match event.code {
+            KeyCode::PageUp => {
+                interrupts::without_interrupts(|| writer.lock().scroll_up(BUFFER_HEIGHT / 2));
+                return true;
+            }
+            KeyCode::PageDown => {
+                interrupts::without_interrupts(|| writer.lock().scroll_down(BUFFER_HEIGHT / 2));
+                return true;
+            }
+            _ => {}
+        }
+    }
+    if modifiers.alt() {
+        let terminal = match event.code {
+            KeyCode::F1 => Some(0),
+            KeyCode::F2 => Some(1),
+            KeyCode::F3 => Some(2),
+            KeyCode::F4 => Some(3),
+            KeyCode::F5 => Some(4),
+            KeyCode::F6 => Some(5),
+            _ => None,
+        };
+        if let Some(terminal) = terminal {
+            vt::switch_to(terminal);
+            return true;
+        }
+    }
+    false
+}
+
+///
+/// Sends a decoded key to the focused window, if there is one, or else to the active
+/// terminal, echoing it if the terminal wants to
+///
+fn dispatch_key(key: DecodedKey) {
+    if crate::gui::compositor::push_key(key) {
+        return;
+    }
+    let terminal = match vt::active() {
+        Some(terminal) => terminal,
+        // Virtual terminals are not there yet, just show it
+        None => {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => print!("{:?}", key),
+            }
+            return;
+        }
+    };
+    match key {
+        DecodedKey::Unicode(character) => {
+            terminal.push_input(character);
+            if terminal.echo() {
+                terminal.write_fmt(format_args!("{}", character));
+            }
+        }
+        DecodedKey::RawKey(key) => {
+            if terminal.echo() {
+                terminal.write_fmt(format_args!("{:?}", key));
+            }
+        }
+    }
+}
+
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut modifiers = Modifiers::default();
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            modifiers.update(&key_event);
+            if handle_console_shortcut(&modifiers, &key_event) {
+                continue;
+            }
             if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+                dispatch_key(key);
             }
         }
     }
@@ -3,7 +3,9 @@ use core::{future::Future, pin::Pin};
 
 pub mod executor;
 pub mod keyboard;
+pub mod mouse;
 pub mod simple_executor;
+pub mod sync;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct TaskId(u64);
@@ -62,3 +64,18 @@ impl Task {
         self.future.as_mut().poll(context)
     }
 }
+
+///
+/// Polls `future` until it's done, without an executor. Nothing wakes it, it's polled
+/// again right away: for tests and futures that never really wait
+///
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = simple_executor::dummy_waker();
+    let mut context = Context::from_waker(&waker);
+    futures_util::pin_mut!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//!
+//! PS/2 mouse
+//!
+//! The mouse sits on the second port of the PS/2 controller and raises IRQ 12. Like the
+//! keyboard, the interrupt handler only queues the raw bytes; `MouseEventStream` puts
+//! them together into 3 byte packets.
+//!
+use crate::error;
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+
+const COMMAND_ENABLE_AUX: u8 = 0xa8;
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_WRITE_AUX: u8 = 0xd4;
+
+const CONFIG_AUX_INTERRUPT: u8 = 0x02;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_ACK: u8 = 0xfa;
+
+/// How many times the status register is polled before giving up
+const TIMEOUT: usize = 100_000;
+
+fn status() -> u8 {
+    let mut port: Port<u8> = Port::new(COMMAND_PORT);
+    unsafe { port.read() }
+}
+
+fn wait_writable() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err("PS/2 controller is not accepting commands")
+}
+
+fn wait_readable() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+    }
+    Err("PS/2 controller didn't answer")
+}
+
+fn write_command(command: u8) -> Result<(), &'static str> {
+    wait_writable()?;
+    let mut port: Port<u8> = Port::new(COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), &'static str> {
+    wait_writable()?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.write(data) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, &'static str> {
+    wait_readable()?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    Ok(unsafe { port.read() })
+}
+
+///
+/// Sends a command to the mouse itself and checks it was acknowledged
+///
+fn write_mouse(command: u8) -> Result<(), &'static str> {
+    write_command(COMMAND_WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err("the mouse didn't acknowledge the command"),
+    }
+}
+
+///
+/// Enables the mouse port and its interrupt. Call it before enabling interrupts or
+/// with them disabled, otherwise the keyboard handler can eat the answers
+///
+pub fn init() -> Result<(), &'static str> {
+    write_command(COMMAND_ENABLE_AUX)?;
+    write_command(COMMAND_READ_CONFIG)?;
+    let config = read_data()?;
+    let config = (config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED;
+    write_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)?;
+    write_mouse(MOUSE_SET_DEFAULTS)?;
+    write_mouse(MOUSE_ENABLE_REPORTING)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Movement since the previous packet. `dy` grows upwards, like the hardware reports it
+///
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: MouseButtons,
+}
+
+const PACKET_ALWAYS_ONE: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const PACKET_X_OVERFLOW: u8 = 0x40;
+const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+///
+/// Puts the bytes sent by the mouse together into events
+///
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; 3],
+            len: 0,
+        }
+    }
+
+    ///
+    /// Returns an event when `byte` completes a packet
+    ///
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bit 3 of the first byte is always set, use it to get back in sync
+        // if a byte was lost
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.bytes.len() {
+            return None;
+        }
+        self.len = 0;
+        let [flags, x, y] = self.bytes;
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+            return None;
+        }
+        let extend = |value: u8, negative: bool| {
+            if negative {
+                value as i16 - 0x100
+            } else {
+                value as i16
+            }
+        };
+        Some(MouseEvent {
+            dx: extend(x, flags & PACKET_X_SIGN != 0),
+            dy: extend(y, flags & PACKET_Y_SIGN != 0),
+            buttons: MouseButtons {
+                left: flags & 0x01 != 0,
+                right: flags & 0x02 != 0,
+                middle: flags & 0x04 != 0,
+            },
+        })
+    }
+}
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if let Err(_) = queue.push(byte) {
+            error!("mouse input is full!");
+        } else {
+            WAKER.wake();
+        }
+    }
+}
+
+///
+/// Raw bytes sent by the mouse
+///
+pub struct MouseByteStream {
+    _private: (),
+}
+
+impl MouseByteStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(300))
+            .expect("Should be called once");
+        Self { _private: () }
+    }
+}
+
+impl Stream for MouseByteStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Ok(byte) => Poll::Ready(Some(byte)),
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+pub struct MouseEventStream {
+    bytes: MouseByteStream,
+    decoder: PacketDecoder,
+}
+
+impl MouseEventStream {
+    pub fn new() -> Self {
+        Self {
+            bytes: MouseByteStream::new(),
+            decoder: PacketDecoder::new(),
+        }
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        loop {
+            match self.bytes.poll_next_unpin(cx) {
+                Poll::Ready(Some(byte)) => {
+                    if let Some(event) = self.decoder.add_byte(byte) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                other => return other.map(|_| None),
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_mouse_packet_decoding() {
+    let mut decoder = PacketDecoder::new();
+    // A stray byte without the sync bit is skipped
+    assert_eq!(decoder.add_byte(0x00), None);
+    assert_eq!(decoder.add_byte(0x08 | 0x10 | 0x01), None);
+    assert_eq!(decoder.add_byte(0xfe), None);
+    let event = decoder.add_byte(0x05).expect("a full packet");
+    assert_eq!((event.dx, event.dy), (-2, 5));
+    assert!(event.buttons.left && !event.buttons.right);
+}
@@ -42,6 +42,6 @@ fn dummy_raw_waker() -> RawWaker {
     RawWaker::new(0 as *const (), vtable)
 }
 
-fn dummy_waker() -> Waker {
+pub(super) fn dummy_waker() -> Waker {
     unsafe { Waker::from_raw(dummy_raw_waker()) }
 }
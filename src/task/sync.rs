@@ -0,0 +1,91 @@
+//!
+//! Locks for tasks
+//!
+//! A `spin::Mutex` can't be held across an `.await`: the task holding it can be
+//! suspended and the next task that tries to take it spins forever on the only CPU.
+//! `AsyncMutex` suspends the tasks that wait instead.
+//!
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+
+pub struct AsyncMutex<T> {
+    locked: AtomicBool,
+    waiters: spin::Mutex<Vec<Waker>>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: spin::Mutex::new(Vec::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<T>> {
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(AsyncMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// Waits until the lock is free and takes it
+    ///
+    pub fn lock(&self) -> impl Future<Output = AsyncMutexGuard<T>> {
+        poll_fn(move |cx| {
+            if let Some(guard) = self.try_lock() {
+                return Poll::Ready(guard);
+            }
+            self.waiters.lock().push(cx.waker().clone());
+            // It may have been released before the waker was there
+            match self.try_lock() {
+                Some(guard) => Poll::Ready(guard),
+                None => Poll::Pending,
+            }
+        })
+    }
+}
+
+pub struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for AsyncMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for AsyncMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        // Everybody tries again, one of them gets it
+        let waiters = core::mem::take(&mut *self.mutex.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
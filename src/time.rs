@@ -0,0 +1,347 @@
+//!
+//! Timekeeping
+//!
+//! Time comes from a clock source: the PIT tick count, which is always there but only
+//! has a resolution of 55 ms, the HPET and the TSC, once `init` found and calibrated
+//! them. `select_best` switches to the one with the highest rating without letting the
+//! time go backwards.
+//!
+//! Wall clock time comes from the CMOS clock, see `rtc`.
+//!
+pub mod hpet;
+pub mod rtc;
+pub mod tsc;
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+///
+/// Frequency of the oscillator that drives the PIT (channel 0 is the one wired to IRQ 0)
+///
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+
+///
+/// Divisor the BIOS leaves programmed in the PIT, which gives us ~18.2 ticks per second
+///
+pub const PIT_DIVISOR: u64 = 65536;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+struct Sleeper {
+    id: u64,
+    /// In nanoseconds since boot
+    deadline: u64,
+    waker: Waker,
+    woken: bool,
+}
+
+///
+/// Tasks in `sleep`. The tick only wakes them, each `Sleep` takes its entry out (and
+/// drops the waker) itself, so the interrupt handler never frees memory
+///
+static SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+
+static NEXT_SLEEPER: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Called by the timer interrupt handler on every PIT tick
+///
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let now = now_nanos();
+    for sleeper in SLEEPERS.lock().iter_mut() {
+        if !sleeper.woken && sleeper.deadline <= now {
+            sleeper.woken = true;
+            sleeper.waker.wake_by_ref();
+        }
+    }
+}
+
+struct Sleep {
+    deadline: u64,
+    /// Of its entry in `SLEEPERS`, once it has one
+    id: Option<u64>,
+}
+
+impl Sleep {
+    fn unregister(&mut self) {
+        if let Some(id) = self.id.take() {
+            let removed = without_interrupts(|| {
+                let mut sleepers = SLEEPERS.lock();
+                let position = sleepers.iter().position(|sleeper| sleeper.id == id);
+                position.map(|position| sleepers.swap_remove(position))
+            });
+            // Dropped with interrupts on
+            drop(removed);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if now_nanos() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let id = *self
+            .id
+            .get_or_insert_with(|| NEXT_SLEEPER.fetch_add(1, Ordering::Relaxed));
+        // The tick takes the lock too
+        without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            match sleepers.iter_mut().find(|sleeper| sleeper.id == id) {
+                Some(sleeper) => {
+                    if !sleeper.waker.will_wake(cx.waker()) {
+                        sleeper.waker = cx.waker().clone();
+                    }
+                    sleeper.woken = false;
+                }
+                None => sleepers.push(Sleeper {
+                    id,
+                    deadline,
+                    waker: cx.waker().clone(),
+                    woken: false,
+                }),
+            }
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+///
+/// Lets other tasks run for at least `duration`. Tasks are woken on a timer tick, so
+/// it can take up to a tick longer
+///
+pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    Sleep {
+        deadline: now_nanos() + duration.as_nanos() as u64,
+        id: None,
+    }
+}
+
+///
+/// Number of timer interrupts received since they were enabled
+///
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn tick_nanos() -> u64 {
+    // u128 so `ticks * PIT_DIVISOR * 10^9` doesn't overflow after a few seconds
+    let nanos = ticks() as u128 * PIT_DIVISOR as u128 * 1_000_000_000 / PIT_FREQUENCY_HZ as u128;
+    nanos as u64
+}
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    ///
+    /// How good the source is, the highest one is used. 0 means it can't be used
+    ///
+    fn rating(&self) -> u32;
+
+    ///
+    /// Nanoseconds since some point in the past
+    ///
+    fn nanos(&self) -> u64;
+}
+
+struct PitTicks;
+
+impl ClockSource for PitTicks {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        10
+    }
+
+    fn nanos(&self) -> u64 {
+        tick_nanos()
+    }
+}
+
+impl ClockSource for hpet::HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        match (self.is_available(), self.is_64_bit()) {
+            (false, _) => 0,
+            (true, true) => 200,
+            // The counter wraps
+            (true, false) => 50,
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        hpet::HpetClock::nanos(self)
+    }
+}
+
+impl ClockSource for tsc::TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        match (self.is_available(), self.is_invariant()) {
+            (false, _) => 0,
+            (true, true) => 300,
+            // It changes speed with the power state of the CPU
+            (true, false) => 100,
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        tsc::TscClock::nanos(self)
+    }
+}
+
+static PIT_TICKS: PitTicks = PitTicks;
+
+static SOURCES: [&dyn ClockSource; 3] = [&PIT_TICKS, &hpet::HPET, &tsc::TSC];
+
+/// Index in `SOURCES` of the source in use
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Added to the source so the time continues where the previous source left it
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub fn clock_source() -> &'static dyn ClockSource {
+    SOURCES[CURRENT.load(Ordering::Relaxed)]
+}
+
+///
+/// Nanoseconds since boot
+///
+pub fn now_nanos() -> u64 {
+    clock_source()
+        .nanos()
+        .wrapping_add(OFFSET.load(Ordering::Relaxed))
+}
+
+///
+/// Time elapsed since boot
+///
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now_nanos())
+}
+
+///
+/// Spins for `duration`, for drivers that have to wait a bit for the hardware
+///
+pub fn spin_for(duration: Duration) {
+    let end = now_nanos() + duration.as_nanos() as u64;
+    while now_nanos() < end {
+        core::hint::spin_loop();
+    }
+}
+
+///
+/// Switches to the source with the highest rating
+///
+pub fn select_best() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = now_nanos();
+        let index = best_source(&SOURCES);
+        OFFSET.store(now.wrapping_sub(SOURCES[index].nanos()), Ordering::Relaxed);
+        CURRENT.store(index, Ordering::Relaxed);
+    });
+}
+
+///
+/// Index of the source with the highest rating, the first one of those if they tie
+///
+fn best_source(sources: &[&dyn ClockSource]) -> usize {
+    let mut best = 0;
+    for (index, source) in sources.iter().enumerate() {
+        if source.rating() > sources[best].rating() {
+            best = index;
+        }
+    }
+    best
+}
+
+/// How long the TSC is measured against the HPET
+const TSC_CALIBRATION_NANOS: u64 = 10_000_000;
+
+///
+/// Starts the HPET at `hpet` if there's one, calibrates the TSC against it (or against
+/// the timer ticks, which needs interrupts enabled) and picks the best clock source
+///
+pub fn init(
+    hpet: Option<PhysAddr>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    use x86_64::instructions::interrupts;
+    if let Some(address) = hpet {
+        if let Err(error) = hpet::init(address, mapper, frame_allocator) {
+            crate::warn!("time: {}", error);
+        }
+    }
+    let tsc_frequency = if hpet::HPET.is_available() {
+        Some(tsc::calibrate(
+            || hpet::HPET.nanos(),
+            TSC_CALIBRATION_NANOS,
+            core::hint::spin_loop,
+        ))
+    } else if interrupts::are_enabled() {
+        // Two ticks, the first one only lines us up with a tick
+        Some(tsc::calibrate(tick_nanos, 1, x86_64::instructions::hlt))
+    } else {
+        None
+    };
+    select_best();
+    crate::info!(
+        "time: using {}, HPET {} Hz, TSC {} Hz",
+        clock_source().name(),
+        hpet::HPET.frequency(),
+        tsc_frequency.unwrap_or(0)
+    );
+}
+
+#[test_case]
+fn test_best_source() {
+    struct Fake(u32);
+    impl ClockSource for Fake {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn rating(&self) -> u32 {
+            self.0
+        }
+
+        fn nanos(&self) -> u64 {
+            0
+        }
+    }
+    assert_eq!(
+        best_source(&[&Fake(10), &Fake(0), &Fake(300), &Fake(100)]),
+        2
+    );
+    // Sources that can't be used don't beat the PIT
+    assert_eq!(best_source(&[&Fake(10), &Fake(0)]), 0);
+    assert_eq!(best_source(&[&Fake(50), &Fake(50)]), 0);
+}
@@ -0,0 +1,150 @@
+//!
+//! High Precision Event Timer, used as a free running counter
+//!
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+
+const CAPABILITY_64_BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+const MMIO_SIZE: u64 = 0x400;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+pub struct HpetClock {
+    /// Virtual address of the registers, 0 until `init`
+    base: AtomicU64,
+    /// Length of a counter tick in femtoseconds
+    period: AtomicU64,
+    counter_64_bit: AtomicBool,
+    /// Last value read from a 32 bit counter, extended to 64 bits
+    extended: AtomicU64,
+}
+
+pub static HPET: HpetClock = HpetClock {
+    base: AtomicU64::new(0),
+    period: AtomicU64::new(0),
+    counter_64_bit: AtomicBool::new(false),
+    extended: AtomicU64::new(0),
+};
+
+impl HpetClock {
+    fn register(&self, offset: usize) -> *mut u64 {
+        (self.base.load(Ordering::Relaxed) as usize + offset) as *mut u64
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.base.load(Ordering::Relaxed) != 0
+    }
+
+    ///
+    /// Wraps after a few minutes if it's not, `counter` makes up the high half then
+    ///
+    pub fn is_64_bit(&self) -> bool {
+        self.counter_64_bit.load(Ordering::Relaxed)
+    }
+
+    pub fn frequency(&self) -> u64 {
+        match self.period.load(Ordering::Relaxed) {
+            0 => 0,
+            period => FEMTOSECONDS_PER_SECOND / period,
+        }
+    }
+
+    ///
+    /// The main counter, always 64 bits. A 32 bit counter has to be read at least once
+    /// every half wrap (2.5 minutes at 14.3 MHz) for that, the timer tick does it while
+    /// the HPET is the clock source
+    ///
+    pub fn counter(&self) -> u64 {
+        if !self.is_available() {
+            return 0;
+        }
+        let raw = unsafe { self.register(MAIN_COUNTER).read_volatile() };
+        if self.is_64_bit() {
+            return raw;
+        }
+        let mut last = self.extended.load(Ordering::Acquire);
+        loop {
+            let extended = extend(last, raw as u32);
+            if extended == last {
+                return last;
+            }
+            match self.extended.compare_exchange(
+                last,
+                extended,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return extended,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    pub fn nanos(&self) -> u64 {
+        let femtoseconds = self.counter() as u128 * self.period.load(Ordering::Relaxed) as u128;
+        (femtoseconds / 1_000_000) as u64
+    }
+}
+
+///
+/// Moves `last`, a 32 bit counter extended to 64 bits, to the `raw` value read from it.
+/// Less than half a wrap ahead is the time that passed, more than that is a value read
+/// before `last` by somebody who got interrupted, which gives `last` again so the time
+/// doesn't go backwards
+///
+fn extend(last: u64, raw: u32) -> u64 {
+    let ahead = raw.wrapping_sub(last as u32);
+    if ahead < 1 << 31 {
+        last + ahead as u64
+    } else {
+        last
+    }
+}
+
+///
+/// Maps the registers at `address` and starts the main counter
+///
+pub fn init(
+    address: PhysAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let base: VirtAddr = crate::memory::map_mmio(address, MMIO_SIZE, mapper, frame_allocator)
+        .map_err(|_| "couldn't map the HPET registers")?;
+    let registers = base.as_mut_ptr::<u8>();
+    let capabilities = unsafe { (registers.add(CAPABILITIES) as *const u64).read_volatile() };
+    let period = capabilities >> 32;
+    // The specification caps the period at 100 ns
+    if period == 0 || period > 100_000_000 {
+        return Err("the HPET has an invalid period");
+    }
+    unsafe {
+        let configuration = registers.add(CONFIGURATION) as *mut u64;
+        configuration.write_volatile(configuration.read_volatile() | CONFIGURATION_ENABLE);
+    }
+    HPET.period.store(period, Ordering::Relaxed);
+    // Extending starts from wherever the counter is now
+    let counter = unsafe { (registers.add(MAIN_COUNTER) as *const u64).read_volatile() };
+    HPET.extended
+        .store(counter as u32 as u64, Ordering::Relaxed);
+    HPET.counter_64_bit
+        .store(capabilities & CAPABILITY_64_BIT != 0, Ordering::Relaxed);
+    HPET.base.store(base.as_u64(), Ordering::SeqCst);
+    Ok(())
+}
+
+#[test_case]
+fn test_extend_32_bit_counter() {
+    assert_eq!(extend(5, 9), 9);
+    // Wrapped
+    assert_eq!(extend(0xffff_fff0, 0x10), 0x1_0000_0010);
+    // Read before the last value, the time stays
+    assert_eq!(extend(0x1_0000_0010, 0xffff_fff0), 0x1_0000_0010);
+    assert_eq!(extend(0x2_0000_0100, 0x80), 0x2_0000_0100);
+}
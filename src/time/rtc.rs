@@ -0,0 +1,324 @@
+//!
+//! CMOS real-time clock and wall clock time
+//!
+//! The RTC is read once, when its driver is probed, and the wall clock is that moment
+//! plus the uptime after it, so reading the time doesn't touch the CMOS.
+//!
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// Set in the index so NMIs stay disabled while the CMOS is half way through an access,
+/// the index is written again without it once the access is done
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 0x40;
+const HOUR_PM: u8 = 0x80;
+
+///
+/// The index/data pair has to be used by one CPU at a time, and not by an interrupt
+/// handler in the middle of another access
+///
+static CMOS: Mutex<()> = Mutex::new(());
+
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(INDEX_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        index.write(NMI_DISABLE | register);
+        let value = data.read();
+        // Same register, NMIs back on
+        index.write(register);
+        value
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    let mut index: Port<u8> = Port::new(INDEX_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        index.write(NMI_DISABLE | register);
+        data.write(value);
+        index.write(register);
+    }
+}
+
+///
+/// Calendar date and time, in UTC like the RTC is normally set
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    ///
+    /// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+    ///
+    fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+        // Count years from March so the leap day is the last one of the year
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = if year >= 0 { year } else { year - 399 } / 400;
+        let year_of_era = year - era * 400;
+        let month_from_march = (month as i64 + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    pub fn to_unix_seconds(&self) -> u64 {
+        let days = Self::days_from_civil(self.year as i64, self.month, self.day);
+        days as u64 * 86_400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        let seconds_of_day = seconds % 86_400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(century_register: u8) -> RawTime {
+    while read_register(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: match century_register {
+            0 => 0,
+            register => read_register(register),
+        },
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+///
+/// Reads the clock. `century_register` is the CMOS register with the century, from the
+/// FADT, or 0 if there's none and years are taken to be after 2000
+///
+pub fn read_clock(century_register: u8) -> DateTime {
+    let _guard = CMOS.lock();
+    without_interrupts(|| {
+        // An update can still start while reading, read until two reads agree
+        let mut raw = read_raw(century_register);
+        loop {
+            let again = read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let status = read_register(STATUS_B);
+        let pm = raw.hour & HOUR_PM != 0;
+        let mut hour = raw.hour & !HOUR_PM;
+        let decode = |value: u8| {
+            if status & STATUS_B_BINARY != 0 {
+                value
+            } else {
+                from_bcd(value)
+            }
+        };
+        hour = decode(hour);
+        if status & STATUS_B_24_HOUR == 0 {
+            // 12 AM is 0 and 12 PM is 12
+            hour = hour % 12 + if pm { 12 } else { 0 };
+        }
+        let century = match century_register {
+            0 => 20,
+            _ => decode(raw.century),
+        };
+        DateTime {
+            year: century as u16 * 100 + decode(raw.year) as u16,
+            month: decode(raw.month),
+            day: decode(raw.day),
+            hour,
+            minute: decode(raw.minute),
+            second: decode(raw.second),
+        }
+    })
+}
+
+///
+/// Seconds since the UNIX epoch at boot, in nanoseconds so `now` stays precise. 0 until
+/// `init`
+///
+static BOOT_TIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Reads the RTC and from then on knows the wall clock time
+///
+pub fn init(century_register: u8) -> DateTime {
+    let now = read_clock(century_register);
+    let uptime = crate::time::now_nanos();
+    let boot = (now.to_unix_seconds() * 1_000_000_000).saturating_sub(uptime);
+    BOOT_TIME_NANOS.store(boot, Ordering::Relaxed);
+    now
+}
+
+///
+/// A point in wall clock time
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SystemTime(Duration);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+    ///
+    /// `None` until the RTC was read
+    ///
+    pub fn now() -> Option<Self> {
+        match BOOT_TIME_NANOS.load(Ordering::Relaxed) {
+            0 => None,
+            boot => Some(SystemTime(Duration::from_nanos(
+                boot + crate::time::now_nanos(),
+            ))),
+        }
+    }
+
+    pub fn duration_since_epoch(&self) -> Duration {
+        self.0
+    }
+
+    pub fn unix_seconds(&self) -> u64 {
+        self.0.as_secs()
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_seconds(self.unix_seconds())
+    }
+}
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Interrupts of the RTC are only raised again once register C is read
+///
+pub(crate) fn acknowledge_interrupt() {
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    read_register(STATUS_C);
+}
+
+///
+/// Programs the periodic interrupt at `32768 >> (rate - 1)` Hz, `rate` from 3 (8 kHz)
+/// to 15 (2 Hz). The IRQ 8 handler has to call `acknowledge_interrupt`
+///
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), &'static str> {
+    if !(3..=15).contains(&rate) {
+        return Err("the RTC rate goes from 3 to 15");
+    }
+    let _guard = CMOS.lock();
+    without_interrupts(|| {
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, status_a & 0xf0 | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        read_register(STATUS_C);
+    });
+    Ok(())
+}
+
+pub fn disable_periodic_interrupt() {
+    let _guard = CMOS.lock();
+    without_interrupts(|| {
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+    });
+}
+
+///
+/// Periodic interrupts received
+///
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_unix_seconds() {
+    let date = DateTime {
+        year: 2000,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 58,
+    };
+    assert_eq!(date.to_unix_seconds(), 951_868_798);
+    assert_eq!(DateTime::from_unix_seconds(951_868_798), date);
+    assert_eq!(DateTime::from_unix_seconds(0).year, 1970);
+}
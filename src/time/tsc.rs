@@ -0,0 +1,97 @@
+//!
+//! Time stamp counter of the CPU, the cheapest clock to read
+//!
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub struct TscClock {
+    /// Ticks per second, 0 until calibrated
+    frequency: AtomicU64,
+    invariant: AtomicBool,
+}
+
+pub static TSC: TscClock = TscClock {
+    frequency: AtomicU64::new(0),
+    invariant: AtomicBool::new(false),
+};
+
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+///
+/// Whether the counter keeps the same rate in every power state
+///
+fn is_invariant() -> bool {
+    use core::arch::x86_64::__cpuid;
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 }
+}
+
+impl TscClock {
+    pub fn is_available(&self) -> bool {
+        self.frequency.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn is_invariant(&self) -> bool {
+        self.invariant.load(Ordering::Relaxed)
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency.load(Ordering::Relaxed)
+    }
+
+    pub fn nanos(&self) -> u64 {
+        match self.frequency() {
+            0 => 0,
+            frequency => (read() as u128 * 1_000_000_000 / frequency as u128) as u64,
+        }
+    }
+}
+
+///
+/// Measures the TSC against `reference`, a clock in nanoseconds, for about `window`
+/// nanoseconds. `wait` is called while waiting
+///
+pub fn calibrate(reference: impl Fn() -> u64, window: u64, wait: impl Fn()) -> u64 {
+    let frequency = measure(reference, read, window, wait);
+    TSC.invariant.store(is_invariant(), Ordering::Relaxed);
+    TSC.frequency.store(frequency, Ordering::SeqCst);
+    frequency
+}
+
+///
+/// Ticks per second of `counter` measured against `reference`
+///
+fn measure(
+    reference: impl Fn() -> u64,
+    counter: impl Fn() -> u64,
+    window: u64,
+    wait: impl Fn(),
+) -> u64 {
+    // Start right after the reference changed, it may be coarse
+    let first = reference();
+    while reference() == first {
+        wait();
+    }
+    let start = reference();
+    let start_counter = counter();
+    while reference() - start < window {
+        wait();
+    }
+    let elapsed = reference() - start;
+    let elapsed_counter = counter() - start_counter;
+    (elapsed_counter as u128 * 1_000_000_000 / elapsed.max(1) as u128) as u64
+}
+
+#[test_case]
+fn test_measure_frequency() {
+    use core::cell::Cell;
+    // A reference that moves 1 us every time it's read and a counter at 3 GHz
+    let now = Cell::new(0u64);
+    let reference = || {
+        let time = now.get();
+        now.set(time + 1000);
+        time
+    };
+    let counter = || now.get() * 3;
+    assert_eq!(measure(reference, counter, 50_000, || {}), 3_000_000_000);
+}
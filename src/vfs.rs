@@ -0,0 +1,432 @@
+//!
+//! Virtual filesystem
+//!
+//! Filesystems implement `FileSystem` and `Inode`, and get mounted on a directory of the
+//! tree (the first one on `/`). Everything else goes through the functions here, which
+//! take paths: they are resolved from `/` or from the working directory, follow the
+//! symbolic links on the way and step into the filesystems mounted on the directories
+//! they cross.
+//!
+//! `open` hands out descriptors for the open files, see `file`. All operations are
+//! async since filesystems may have to wait for their disk.
+//!
+pub mod file;
+pub mod initrd;
+pub mod path;
+pub mod ramfs;
+
+pub use file::{close, fstat, open, read, seek, write, Fd, File, OpenFlags, SeekFrom};
+pub use path::{current_dir, set_current_dir};
+
+use crate::block::BlockError;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+///
+/// What the operations of `Inode` and `File` return, boxed so the traits stay object safe
+///
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// Empty path, or `.` or `..` where a name is needed
+    InvalidPath,
+    /// A seek before the start of the file, or flags that don't go together
+    InvalidArgument,
+    /// Too many symbolic links while resolving a path, probably a loop
+    TooManyLinks,
+    /// The operation would cross from one filesystem to another
+    CrossDevice,
+    /// A filesystem is mounted there
+    Busy,
+    ReadOnly,
+    /// The descriptor isn't open, or not for this
+    BadDescriptor,
+    /// The filesystem can't do that
+    Unsupported,
+    NoSpace,
+    Io(BlockError),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::DirectoryNotEmpty => write!(f, "directory not empty"),
+            FsError::InvalidPath => write!(f, "invalid path"),
+            FsError::InvalidArgument => write!(f, "invalid argument"),
+            FsError::TooManyLinks => write!(f, "too many levels of symbolic links"),
+            FsError::CrossDevice => write!(f, "cross-device link"),
+            FsError::Busy => write!(f, "device or resource busy"),
+            FsError::ReadOnly => write!(f, "read-only file system"),
+            FsError::BadDescriptor => write!(f, "bad file descriptor"),
+            FsError::Unsupported => write!(f, "operation not supported"),
+            FsError::NoSpace => write!(f, "no space left on device"),
+            FsError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Number of the inode in its filesystem
+    pub inode: u64,
+    pub kind: FileType,
+    pub size: u64,
+    /// Permission bits, as in `0o755`
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub links: u32,
+    /// Last modification, in seconds since the UNIX epoch
+    pub modified: u64,
+}
+
+///
+/// Changes for `Inode::set_attributes`, the fields left as `None` stay as they are
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub modified: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+///
+/// A file, directory or symbolic link of a filesystem. The defaults are for
+/// filesystems that can't do the operation
+///
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> FsFuture<'_, Metadata>;
+
+    fn set_attributes(&self, _attributes: Attributes) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::Unsupported) })
+    }
+
+    ///
+    /// Reads from `offset` on, returns how many bytes, 0 at the end of the file
+    ///
+    fn read_at<'a>(&'a self, _offset: u64, _buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    ///
+    /// Writes at `offset`, growing the file if it goes past the end
+    ///
+    fn write_at<'a>(&'a self, _offset: u64, _data: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    ///
+    /// Entry `name` of a directory. `.` and `..` are handled by the VFS
+    ///
+    fn lookup<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    ///
+    /// Makes an empty file or directory called `name` in this directory
+    ///
+    fn create<'a>(
+        &'a self,
+        _name: &'a str,
+        _kind: FileType,
+        _mode: u16,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async { Err(FsError::Unsupported) })
+    }
+
+    fn symlink<'a>(&'a self, _name: &'a str, _target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async { Err(FsError::Unsupported) })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async { Err(FsError::InvalidPath) })
+    }
+
+    ///
+    /// Removes entry `name`, directories only when they are empty
+    ///
+    fn unlink<'a>(&'a self, _name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async { Err(FsError::Unsupported) })
+    }
+
+    ///
+    /// Moves entry `name` to `new_name` in `new_parent`, a directory of the same
+    /// filesystem, replacing what was there
+    ///
+    fn rename<'a>(
+        &'a self,
+        _name: &'a str,
+        _new_parent: &'a Arc<dyn Inode>,
+        _new_name: &'a str,
+    ) -> FsFuture<'a, ()> {
+        Box::pin(async { Err(FsError::Unsupported) })
+    }
+
+    ///
+    /// For filesystems to get their own inodes back from a `dyn Inode`
+    ///
+    fn as_any(&self) -> &dyn Any;
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    ///
+    /// Writes whatever the filesystem keeps in memory to its device
+    ///
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+lazy_static! {
+    ///
+    /// Filesystems by the path they are mounted on
+    ///
+    static ref MOUNTS: Mutex<BTreeMap<String, Arc<dyn FileSystem>>> = Mutex::new(BTreeMap::new());
+}
+
+fn mounted(path: &str) -> Option<Arc<dyn FileSystem>> {
+    MOUNTS.lock().get(path).cloned()
+}
+
+///
+/// Mount point of the filesystem `path` is in, the longest one that contains it
+///
+fn mount_of(path: &str) -> String {
+    let mounts = MOUNTS.lock();
+    let mut best = String::from("/");
+    for mount in mounts.keys() {
+        if path::contains(mount, path) && mount.len() > best.len() {
+            best = mount.clone();
+        }
+    }
+    best
+}
+
+///
+/// Mounts `filesystem` on the directory `path`, or as the root if `path` is `/` and
+/// there is no root yet
+///
+pub async fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    if path == "/" && mounted("/").is_none() {
+        return mount_root(filesystem);
+    }
+    let resolved = path::resolve(path, true).await?;
+    if resolved.inode.metadata().await?.kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    insert_mount(resolved.path, filesystem)
+}
+
+///
+/// Mounts `filesystem` as the root, which needs no path resolved
+///
+pub fn mount_root(filesystem: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    insert_mount(String::from("/"), filesystem)
+}
+
+fn insert_mount(path: String, filesystem: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let mut mounts = MOUNTS.lock();
+    if mounts.contains_key(&path) {
+        return Err(FsError::Busy);
+    }
+    crate::info!("vfs: {} mounted on {}", filesystem.name(), path);
+    mounts.insert(path, filesystem);
+    Ok(())
+}
+
+///
+/// Mounts an empty ramfs as the root
+///
+pub fn init() {
+    if let Err(error) = mount_root(ramfs::RamFs::new()) {
+        crate::warn!("vfs: no root filesystem: {}", error);
+    }
+}
+
+///
+/// Unmounts the filesystem mounted on `path` after syncing it. Filesystems with other
+/// filesystems mounted inside are busy
+///
+pub async fn unmount(path: &str) -> Result<(), FsError> {
+    let canonical = path::resolve(path, true).await?.path;
+    let filesystem = mounted(&canonical).ok_or(FsError::InvalidPath)?;
+    let nested = MOUNTS
+        .lock()
+        .keys()
+        .any(|mount| mount != &canonical && path::contains(&canonical, mount));
+    if nested {
+        return Err(FsError::Busy);
+    }
+    filesystem.sync().await?;
+    MOUNTS.lock().remove(&canonical);
+    Ok(())
+}
+
+///
+/// Mount points and the name of their filesystem
+///
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|(path, filesystem)| (path.clone(), filesystem.name()))
+        .collect()
+}
+
+///
+/// Syncs every mounted filesystem
+///
+pub async fn sync() -> Result<(), FsError> {
+    let filesystems: Vec<Arc<dyn FileSystem>> = MOUNTS.lock().values().cloned().collect();
+    for filesystem in filesystems {
+        filesystem.sync().await?;
+    }
+    Ok(())
+}
+
+///
+/// The inode at `path`, following symbolic links
+///
+pub async fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    Ok(path::resolve(path, true).await?.inode)
+}
+
+///
+/// Metadata of `path`, following symbolic links
+///
+pub async fn stat(path: &str) -> Result<Metadata, FsError> {
+    lookup(path).await?.metadata().await
+}
+
+///
+/// Metadata of `path` itself, even if it's a symbolic link
+///
+pub async fn lstat(path: &str) -> Result<Metadata, FsError> {
+    path::resolve(path, false).await?.inode.metadata().await
+}
+
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path).await?.read_dir().await
+}
+
+pub async fn create_dir(path: &str, mode: u16) -> Result<Arc<dyn Inode>, FsError> {
+    let (parent, name) = path::resolve_parent(path).await?;
+    parent.inode.create(&name, FileType::Directory, mode).await
+}
+
+///
+/// Creates every missing directory in `path`
+///
+pub async fn create_dir_all(path: &str, mode: u16) -> Result<(), FsError> {
+    let absolute = path::absolute(path);
+    let mut partial = String::new();
+    for component in path::components(&absolute) {
+        partial.push('/');
+        partial.push_str(component);
+        match create_dir(&partial, mode).await {
+            Ok(_) | Err(FsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+pub async fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::resolve_parent(path).await?;
+    parent.inode.symlink(&name, target).await.map(|_| ())
+}
+
+pub async fn read_link(path: &str) -> Result<String, FsError> {
+    path::resolve(path, false).await?.inode.read_link().await
+}
+
+pub async fn set_attributes(path: &str, attributes: Attributes) -> Result<(), FsError> {
+    lookup(path).await?.set_attributes(attributes).await
+}
+
+pub async fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    lookup(path).await?.truncate(size).await
+}
+
+///
+/// Removes a file, a symbolic link or an empty directory
+///
+pub async fn remove(path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::resolve_parent(path).await?;
+    if mounted(&path::join(&parent.path, &name)).is_some() {
+        return Err(FsError::Busy);
+    }
+    parent.inode.unlink(&name).await
+}
+
+pub async fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = path::resolve_parent(from).await?;
+    let (new_parent, new_name) = path::resolve_parent(to).await?;
+    let old_path = path::join(&old_parent.path, &old_name);
+    if mounted(&old_path).is_some() || mounted(&path::join(&new_parent.path, &new_name)).is_some() {
+        return Err(FsError::Busy);
+    }
+    if mount_of(&old_parent.path) != mount_of(&new_parent.path) {
+        return Err(FsError::CrossDevice);
+    }
+    // A directory can't go inside itself
+    if path::contains(&old_path, &new_parent.path) {
+        return Err(FsError::InvalidPath);
+    }
+    old_parent
+        .inode
+        .rename(&old_name, &new_parent.inode, &new_name)
+        .await
+}
@@ -0,0 +1,248 @@
+//!
+//! Open files and their descriptors
+//!
+//! `open` resolves a path once and keeps the inode in an `OpenFile`, with the offset the
+//! next read or write starts at. Descriptors are indexes in one table for the whole
+//! kernel, the lowest free one is handed out first.
+//!
+use super::path;
+use super::{DirEntry, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::task::sync::AsyncMutex;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use spin::Mutex;
+
+///
+/// How a file is opened, combined with `|`
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(pub u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it doesn't exist
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Empty the file when opening it for writing
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes to the end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+    /// With `CREATE`, fail if the file exists
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 5);
+    /// Fail unless it's a directory
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 6);
+
+    pub fn contains(self, flags: OpenFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+///
+/// An open file. Reads and writes start at its offset and move it
+///
+pub trait File: Send + Sync {
+    fn read<'a>(&'a self, buffer: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    fn write<'a>(&'a self, data: &'a [u8]) -> FsFuture<'a, usize>;
+
+    ///
+    /// Moves the offset, returns the new one
+    ///
+    fn seek(&self, position: SeekFrom) -> FsFuture<'_, u64>;
+
+    fn stat(&self) -> FsFuture<'_, Metadata>;
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>>;
+}
+
+///
+/// A file of a filesystem opened with `open`
+///
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    /// Held for the whole read or write, so they don't overlap
+    offset: AsyncMutex<u64>,
+}
+
+impl OpenFile {
+    pub fn new(inode: Arc<dyn Inode>, flags: OpenFlags) -> Self {
+        OpenFile {
+            inode,
+            flags,
+            offset: AsyncMutex::new(0),
+        }
+    }
+}
+
+impl File for OpenFile {
+    fn read<'a>(&'a self, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.flags.contains(OpenFlags::READ) {
+                return Err(FsError::BadDescriptor);
+            }
+            let mut offset = self.offset.lock().await;
+            let read = self.inode.read_at(*offset, buffer).await?;
+            *offset += read as u64;
+            Ok(read)
+        })
+    }
+
+    fn write<'a>(&'a self, data: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if !self.flags.contains(OpenFlags::WRITE) {
+                return Err(FsError::BadDescriptor);
+            }
+            let mut offset = self.offset.lock().await;
+            if self.flags.contains(OpenFlags::APPEND) {
+                *offset = self.inode.metadata().await?.size;
+            }
+            let written = self.inode.write_at(*offset, data).await?;
+            *offset += written as u64;
+            Ok(written)
+        })
+    }
+
+    fn seek(&self, position: SeekFrom) -> FsFuture<'_, u64> {
+        Box::pin(async move {
+            let mut offset = self.offset.lock().await;
+            let (base, delta) = match position {
+                SeekFrom::Start(position) => (position, 0),
+                SeekFrom::End(delta) => (self.inode.metadata().await?.size, delta),
+                SeekFrom::Current(delta) => (*offset, delta),
+            };
+            let position = if delta < 0 {
+                base.checked_sub(delta.wrapping_neg() as u64)
+            } else {
+                base.checked_add(delta as u64)
+            };
+            *offset = position.ok_or(FsError::InvalidArgument)?;
+            Ok(*offset)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Metadata> {
+        self.inode.metadata()
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        self.inode.read_dir()
+    }
+}
+
+///
+/// A descriptor of an open file
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(pub usize);
+
+static FILES: Mutex<Vec<Option<Arc<dyn File>>>> = Mutex::new(Vec::new());
+
+///
+/// Puts `file` in the descriptor table
+///
+pub fn install(file: Arc<dyn File>) -> Fd {
+    let mut files = FILES.lock();
+    match files.iter().position(Option::is_none) {
+        Some(index) => {
+            files[index] = Some(file);
+            Fd(index)
+        }
+        None => {
+            files.push(Some(file));
+            Fd(files.len() - 1)
+        }
+    }
+}
+
+///
+/// The file open as `fd`
+///
+pub fn file(fd: Fd) -> Result<Arc<dyn File>, FsError> {
+    FILES
+        .lock()
+        .get(fd.0)
+        .cloned()
+        .flatten()
+        .ok_or(FsError::BadDescriptor)
+}
+
+pub async fn open(path: &str, flags: OpenFlags) -> Result<Fd, FsError> {
+    let inode = match path::resolve(path, true).await {
+        Ok(resolved) => {
+            if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) {
+                return Err(FsError::AlreadyExists);
+            }
+            resolved.inode
+        }
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = path::resolve_parent(path).await?;
+            parent.inode.create(&name, FileType::File, 0o644).await?
+        }
+        Err(error) => return Err(error),
+    };
+    let kind = inode.metadata().await?.kind;
+    if kind == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::IsADirectory);
+    }
+    if kind != FileType::Directory && flags.contains(OpenFlags::DIRECTORY) {
+        return Err(FsError::NotADirectory);
+    }
+    if flags.contains(OpenFlags::TRUNCATE | OpenFlags::WRITE) {
+        inode.truncate(0).await?;
+    }
+    Ok(install(Arc::new(OpenFile::new(inode, flags))))
+}
+
+///
+/// Frees the descriptor. The file stays open while somebody still uses it
+///
+pub fn close(fd: Fd) -> Result<(), FsError> {
+    FILES
+        .lock()
+        .get_mut(fd.0)
+        .and_then(Option::take)
+        .map(|_| ())
+        .ok_or(FsError::BadDescriptor)
+}
+
+pub async fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize, FsError> {
+    file(fd)?.read(buffer).await
+}
+
+pub async fn write(fd: Fd, data: &[u8]) -> Result<usize, FsError> {
+    file(fd)?.write(data).await
+}
+
+pub async fn seek(fd: Fd, position: SeekFrom) -> Result<u64, FsError> {
+    file(fd)?.seek(position).await
+}
+
+pub async fn fstat(fd: Fd) -> Result<Metadata, FsError> {
+    file(fd)?.stat().await
+}
+
+#[test_case]
+fn test_open_flags() {
+    let flags = OpenFlags::READ | OpenFlags::WRITE;
+    assert!(flags.contains(OpenFlags::READ));
+    assert!(flags.contains(OpenFlags::READ | OpenFlags::WRITE));
+    assert!(!flags.contains(OpenFlags::WRITE | OpenFlags::CREATE));
+}
@@ -0,0 +1,324 @@
+//!
+//! Initial ramdisk
+//!
+//! A ustar archive built into the kernel with `include_bytes!` and unpacked into the root
+//! ramfs at boot, so there are files before any disk driver is up. It's made from the
+//! `initrd` directory at the top of the repository, see the README to rebuild it.
+//!
+//! Files and directories keep their mode, owner and modification time, symbolic links
+//! are made as they are. Hard links and special files are skipped.
+//!
+use super::{path, Attributes, FsError, OpenFlags};
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str;
+
+///
+/// The archive built into the kernel
+///
+pub static ARCHIVE: &[u8] = include_bytes!("initrd.tar");
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// The archive ends in the middle of an entry
+    Truncated,
+    /// The checksum of the header at this offset is wrong
+    Checksum(usize),
+    /// A field of a header that can't be read
+    BadField(&'static str),
+    Fs(FsError),
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TarError::Truncated => write!(f, "the archive is truncated"),
+            TarError::Checksum(offset) => write!(f, "bad header checksum at {:#x}", offset),
+            TarError::BadField(field) => write!(f, "bad {} in a header", field),
+            TarError::Fs(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<FsError> for TarError {
+    fn from(error: FsError) -> Self {
+        TarError::Fs(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Hard links, devices, FIFOs and extended headers, holds the type flag
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// Put in front of `name` with a `/`, for long paths
+    pub prefix: &'a str,
+    pub name: &'a str,
+    pub kind: EntryKind,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: u64,
+    /// Target of a symbolic link
+    pub link: &'a str,
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    pub fn path(&self) -> String {
+        let mut path = String::from(self.prefix);
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(self.name);
+        path
+    }
+}
+
+///
+/// A string field, which ends at the first NUL if it doesn't fill the field
+///
+fn text<'a>(field: &'a [u8], name: &'static str) -> Result<&'a str, TarError> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    str::from_utf8(&field[..end]).map_err(|_| TarError::BadField(name))
+}
+
+///
+/// A number field, in octal with spaces or NULs around it
+///
+fn octal(field: &[u8], name: &'static str) -> Result<u64, TarError> {
+    let mut value: u64 = 0;
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != b' ' && byte != 0);
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return Err(TarError::BadField(name));
+        }
+        value = value.checked_mul(8).ok_or(TarError::BadField(name))? + (digit - b'0') as u64;
+    }
+    Ok(value)
+}
+
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| match i {
+            // The checksum field counts as spaces
+            148..=155 => b' ' as u64,
+            _ => byte as u64,
+        })
+        .sum()
+}
+
+///
+/// Goes through the entries of an archive
+///
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, TarError> {
+        let header = self
+            .archive
+            .get(self.offset..self.offset + BLOCK_SIZE)
+            .ok_or(TarError::Truncated)?;
+        // The archive ends with empty blocks
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        if octal(&header[148..156], "checksum")? != checksum(header) {
+            return Err(TarError::Checksum(self.offset));
+        }
+        let size = octal(&header[124..136], "size")? as usize;
+        let start = self.offset + BLOCK_SIZE;
+        let data = self
+            .archive
+            .get(start..start + size)
+            .ok_or(TarError::Truncated)?;
+        let kind = match header[156] {
+            b'0' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'2' => EntryKind::Symlink,
+            flag => EntryKind::Other(flag),
+        };
+        // Only ustar has a prefix, old archives have other things there
+        let prefix = if &header[257..262] == b"ustar" {
+            text(&header[345..500], "prefix")?
+        } else {
+            ""
+        };
+        let entry = Entry {
+            prefix,
+            name: text(&header[..100], "name")?,
+            kind,
+            mode: octal(&header[100..108], "mode")? as u16,
+            uid: octal(&header[108..116], "uid")? as u32,
+            gid: octal(&header[116..124], "gid")? as u32,
+            modified: octal(&header[136..148], "modification time")?,
+            link: text(&header[157..257], "link name")?,
+            data,
+        };
+        self.offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        Ok(Some(entry))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.parse().transpose();
+        // Nothing after the end or an error makes sense
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+fn attributes(entry: &Entry) -> Attributes {
+    Attributes {
+        mode: Some(entry.mode),
+        uid: Some(entry.uid),
+        gid: Some(entry.gid),
+        modified: Some(entry.modified),
+    }
+}
+
+///
+/// Whether `relative` is or goes through one of the symbolic links in `links`, all
+/// relative to the destination of `unpack`
+///
+fn through_link(links: &BTreeSet<String>, relative: &str) -> bool {
+    let mut prefix = String::new();
+    path::components(relative)
+        .filter(|&name| name != ".")
+        .any(|name| {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(name);
+            links.contains(&prefix)
+        })
+}
+
+///
+/// Unpacks `archive` in the directory `destination`, creating it if it's missing.
+/// Returns how many entries were unpacked
+///
+pub async fn unpack(archive: &[u8], destination: &str) -> Result<usize, TarError> {
+    super::create_dir_all(destination, 0o755).await?;
+    let destination = path::absolute(destination);
+    // Their modification time is set last, creating what's inside changes it
+    let mut directories = Vec::new();
+    // The links made so far, the entries after them must not go through them: a link to
+    // `/` and then a file below it would be written anywhere
+    let mut links = BTreeSet::new();
+    let mut count = 0;
+    for entry in entries(archive) {
+        let entry = entry?;
+        let name = entry.path();
+        let relative = name.trim_start_matches("./").trim_matches('/');
+        if relative.is_empty() || path::components(relative).any(|name| name == "..") {
+            continue;
+        }
+        if through_link(&links, relative) {
+            crate::warn!("initrd: {} skipped, it goes through a link", name);
+            continue;
+        }
+        let target = path::join(&destination, relative);
+        if let Some((parent, _)) = path::split_last(&target) {
+            super::create_dir_all(parent, 0o755).await?;
+        }
+        match entry.kind {
+            EntryKind::File => {
+                let fd = super::open(
+                    &target,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                )
+                .await?;
+                let written = super::write(fd, entry.data).await;
+                super::close(fd)?;
+                written?;
+                super::set_attributes(&target, attributes(&entry)).await?;
+            }
+            EntryKind::Directory => {
+                match super::create_dir(&target, entry.mode).await {
+                    Ok(_) | Err(FsError::AlreadyExists) => {}
+                    Err(error) => return Err(error.into()),
+                }
+                directories.push((target, attributes(&entry)));
+            }
+            EntryKind::Symlink => {
+                super::symlink(entry.link, &target).await?;
+                let key: Vec<&str> = path::components(relative)
+                    .filter(|&name| name != ".")
+                    .collect();
+                links.insert(key.join("/"));
+            }
+            EntryKind::Other(flag) => {
+                crate::warn!("initrd: {} skipped, type {:?}", name, flag as char);
+                continue;
+            }
+        }
+        count += 1;
+    }
+    for (directory, attributes) in directories.iter().rev() {
+        super::set_attributes(directory, *attributes).await?;
+    }
+    Ok(count)
+}
+
+///
+/// Unpacks the archive built into the kernel in `/`
+///
+pub async fn load() {
+    match unpack(ARCHIVE, "/").await {
+        Ok(count) => crate::info!("initrd: {} entries unpacked", count),
+        Err(error) => crate::warn!("initrd: {}", error),
+    }
+}
+
+#[test_case]
+fn test_builtin_archive() {
+    let mut files = 0;
+    for entry in entries(ARCHIVE) {
+        let entry = entry.expect("the built-in initrd is damaged");
+        if entry.name == "etc/hostname" {
+            assert_eq!(entry.kind, EntryKind::File);
+            assert_eq!(entry.mode, 0o644);
+        }
+        if entry.kind == EntryKind::File {
+            files += 1;
+        }
+    }
+    assert!(files > 0);
+}
@@ -0,0 +1,181 @@
+//!
+//! Paths and how they are resolved
+//!
+//! Resolving walks the path one component at a time from the root, keeping the
+//! directories it went through: `..` goes back to the previous one, so it also leaves
+//! a mounted filesystem through the directory it's mounted on. A symbolic link puts
+//! the components of its target in front of the ones left.
+//!
+use super::{FileType, FsError, Inode};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+///
+/// Symbolic links followed at most while resolving a path
+///
+pub const MAX_LINKS: usize = 40;
+
+///
+/// Working directory relative paths start from, empty for `/`
+///
+static CURRENT_DIR: Mutex<String> = Mutex::new(String::new());
+
+///
+/// A resolved path: the inode and its absolute path, without `.`, `..` or links
+///
+pub struct Resolved {
+    pub path: String,
+    pub inode: Arc<dyn Inode>,
+}
+
+///
+/// The names in `path`, without the empty ones of repeated or trailing slashes
+///
+pub fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+///
+/// Whether `path` is `directory` or inside it, both absolute and resolved
+///
+pub fn contains(directory: &str, path: &str) -> bool {
+    let directory = directory.trim_end_matches('/');
+    path.starts_with(directory)
+        && (path.len() == directory.len() || path[directory.len()..].starts_with('/'))
+}
+
+pub fn join(directory: &str, name: &str) -> String {
+    let mut path = String::from(directory.trim_end_matches('/'));
+    path.push('/');
+    path.push_str(name);
+    path
+}
+
+///
+/// Splits the last component off an absolute path. `None` for `/`
+///
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let slash = path.rfind('/')?;
+    let parent = match &path[..slash] {
+        "" => "/",
+        parent => parent,
+    };
+    Some((parent, &path[slash + 1..]))
+}
+
+pub fn current_dir() -> String {
+    match CURRENT_DIR.lock().as_str() {
+        "" => String::from("/"),
+        directory => String::from(directory),
+    }
+}
+
+pub async fn set_current_dir(path: &str) -> Result<(), FsError> {
+    let resolved = resolve(path, true).await?;
+    if resolved.inode.metadata().await?.kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    *CURRENT_DIR.lock() = resolved.path;
+    Ok(())
+}
+
+///
+/// `path` from the root, relative ones put after the working directory
+///
+pub fn absolute(path: &str) -> String {
+    if path.starts_with('/') {
+        String::from(path)
+    } else {
+        join(&current_dir(), path)
+    }
+}
+
+fn path_of(stack: &[(String, Arc<dyn Inode>)]) -> String {
+    if stack.is_empty() {
+        return String::from("/");
+    }
+    let mut path = String::new();
+    for (name, _) in stack {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
+
+///
+/// Walks `path`. Symbolic links in the middle are always followed, the last component
+/// only if `follow`
+///
+pub async fn resolve(path: &str, follow: bool) -> Result<Resolved, FsError> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let root = super::mounted("/").ok_or(FsError::NotFound)?.root();
+    let absolute = absolute(path);
+    // Reversed, the next component is the last one
+    let mut pending: Vec<String> = components(&absolute).rev().map(String::from).collect();
+    let mut stack: Vec<(String, Arc<dyn Inode>)> = Vec::new();
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "." => continue,
+            ".." => {
+                stack.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let parent = stack.last().map_or(&root, |(_, inode)| inode).clone();
+        let mut child = parent.lookup(&component).await?;
+        if let Some(filesystem) = super::mounted(&join(&path_of(&stack), &component)) {
+            child = filesystem.root();
+        }
+        let last = pending.is_empty();
+        if (follow || !last) && child.metadata().await?.kind == FileType::Symlink {
+            links += 1;
+            if links > MAX_LINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            let target = child.read_link().await?;
+            if target.starts_with('/') {
+                stack.clear();
+            }
+            pending.extend(components(&target).rev().map(String::from));
+            continue;
+        }
+        stack.push((component, child));
+    }
+    let path = path_of(&stack);
+    let inode = stack.pop().map_or(root, |(_, inode)| inode);
+    Ok(Resolved { path, inode })
+}
+
+///
+/// Resolves the directory `path` is in and returns it with the last name of `path`,
+/// for creating or removing it
+///
+pub async fn resolve_parent(path: &str) -> Result<(Resolved, String), FsError> {
+    let absolute = absolute(path);
+    let (parent, name) = split_last(&absolute).ok_or(FsError::InvalidPath)?;
+    if name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    let name = String::from(name);
+    let parent = resolve(parent, true).await?;
+    Ok((parent, name))
+}
+
+#[test_case]
+fn test_split_and_contains() {
+    assert_eq!(split_last("/a/b/"), Some(("/a", "b")));
+    assert_eq!(split_last("/a"), Some(("/", "a")));
+    assert_eq!(split_last("/"), None);
+    assert!(contains("/", "/a"));
+    assert!(contains("/a", "/a/b"));
+    assert!(contains("/a", "/a"));
+    assert!(!contains("/a", "/ab"));
+    assert_eq!(components("//a/./b/").count(), 3);
+}
@@ -0,0 +1,440 @@
+//!
+//! Filesystem in memory
+//!
+//! Everything lives on the heap and is gone at reboot. File contents are kept in pages
+//! that are only allocated when something is written to them, so growing a file with
+//! `truncate` or writing past its end leaves holes that read as zeros and take no memory.
+//!
+use super::{Attributes, DirEntry, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::time::rtc::SystemTime;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+///
+/// Size of the pieces file contents are allocated in
+///
+pub const PAGE_SIZE: usize = 4096;
+
+type Page = Box<[u8; PAGE_SIZE]>;
+
+enum Content {
+    File {
+        size: u64,
+        /// By index, the missing ones are holes
+        pages: BTreeMap<u64, Page>,
+    },
+    Directory(BTreeMap<String, Arc<RamInode>>),
+    Symlink(String),
+}
+
+struct Info {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    modified: u64,
+}
+
+pub struct RamInode {
+    number: u64,
+    info: Mutex<Info>,
+    content: Mutex<Content>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().map_or(0, |time| time.unix_seconds())
+}
+
+impl RamInode {
+    fn new(number: u64, content: Content, mode: u16) -> Arc<Self> {
+        Arc::new(RamInode {
+            number,
+            info: Mutex::new(Info {
+                mode,
+                uid: 0,
+                gid: 0,
+                modified: now(),
+            }),
+            content: Mutex::new(content),
+        })
+    }
+
+    fn kind(&self) -> FileType {
+        match &*self.content.lock() {
+            Content::File { .. } => FileType::File,
+            Content::Directory(_) => FileType::Directory,
+            Content::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn touch(&self) {
+        self.info.lock().modified = now();
+    }
+
+    fn metadata_now(&self) -> Metadata {
+        let (kind, size, links) = match &*self.content.lock() {
+            Content::File { size, .. } => (FileType::File, *size, 1),
+            Content::Directory(entries) => {
+                let directories = entries
+                    .values()
+                    .filter(|entry| entry.kind() == FileType::Directory)
+                    .count();
+                (
+                    FileType::Directory,
+                    entries.len() as u64,
+                    2 + directories as u32,
+                )
+            }
+            Content::Symlink(target) => (FileType::Symlink, target.len() as u64, 1),
+        };
+        let info = self.info.lock();
+        Metadata {
+            inode: self.number,
+            kind,
+            size,
+            mode: info.mode,
+            uid: info.uid,
+            gid: info.gid,
+            links,
+            modified: info.modified,
+        }
+    }
+
+    fn read_now(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let content = self.content.lock();
+        let (size, pages) = match &*content {
+            Content::File { size, pages } => (*size, pages),
+            Content::Directory(_) => return Err(FsError::IsADirectory),
+            Content::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let index = position / PAGE_SIZE as u64;
+            let start = (position % PAGE_SIZE as u64) as usize;
+            let count = (PAGE_SIZE - start).min(len - done);
+            let target = &mut buffer[done..done + count];
+            match pages.get(&index) {
+                Some(page) => target.copy_from_slice(&page[start..start + count]),
+                None => target.iter_mut().for_each(|byte| *byte = 0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_now(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut content = self.content.lock();
+        let (size, pages) = match &mut *content {
+            Content::File { size, pages } => (size, pages),
+            Content::Directory(_) => return Err(FsError::IsADirectory),
+            Content::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        // Nothing written past the end doesn't make the file bigger
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(FsError::NoSpace)?;
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let index = position / PAGE_SIZE as u64;
+            let start = (position % PAGE_SIZE as u64) as usize;
+            let count = (PAGE_SIZE - start).min(data.len() - done);
+            let page = pages
+                .entry(index)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            page[start..start + count].copy_from_slice(&data[done..done + count]);
+            done += count;
+        }
+        *size = (*size).max(end);
+        drop(content);
+        self.touch();
+        Ok(data.len())
+    }
+
+    fn truncate_now(&self, new_size: u64) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let (size, pages) = match &mut *content {
+            Content::File { size, pages } => (size, pages),
+            Content::Directory(_) => return Err(FsError::IsADirectory),
+            Content::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        if new_size < *size {
+            let page_size = PAGE_SIZE as u64;
+            // Whole pages past the end go, the one the end is in loses its tail
+            let first_gone = (new_size + page_size - 1) / page_size;
+            let _ = pages.split_off(&first_gone);
+            if new_size % page_size != 0 {
+                if let Some(page) = pages.get_mut(&(new_size / page_size)) {
+                    page[(new_size % page_size) as usize..]
+                        .iter_mut()
+                        .for_each(|byte| *byte = 0);
+                }
+            }
+        }
+        *size = new_size;
+        drop(content);
+        self.touch();
+        Ok(())
+    }
+
+    fn insert(
+        &self,
+        name: &str,
+        make: impl FnOnce() -> Arc<RamInode>,
+    ) -> Result<Arc<dyn Inode>, FsError> {
+        check_name(name)?;
+        let mut content = self.content.lock();
+        let entries = match &mut *content {
+            Content::Directory(entries) => entries,
+            _ => return Err(FsError::NotADirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = make();
+        entries.insert(String::from(name), inode.clone());
+        drop(content);
+        self.touch();
+        Ok(inode)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+///
+/// Whether `inode` can go where `existing` is in a rename
+///
+fn check_replace(inode: &RamInode, existing: &RamInode) -> Result<(), FsError> {
+    match (inode.kind(), &*existing.content.lock()) {
+        (FileType::Directory, Content::Directory(entries)) if !entries.is_empty() => {
+            Err(FsError::DirectoryNotEmpty)
+        }
+        (FileType::Directory, Content::Directory(_)) => Ok(()),
+        (FileType::Directory, _) => Err(FsError::NotADirectory),
+        (_, Content::Directory(_)) => Err(FsError::IsADirectory),
+        _ => Ok(()),
+    }
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> FsFuture<'_, Metadata> {
+        Box::pin(async move { Ok(self.metadata_now()) })
+    }
+
+    fn set_attributes(&self, attributes: Attributes) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let mut info = self.info.lock();
+            if let Some(mode) = attributes.mode {
+                info.mode = mode & 0o7777;
+            }
+            if let Some(uid) = attributes.uid {
+                info.uid = uid;
+            }
+            if let Some(gid) = attributes.gid {
+                info.gid = gid;
+            }
+            if let Some(modified) = attributes.modified {
+                info.modified = modified;
+            }
+            Ok(())
+        })
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move { self.read_now(offset, buffer) })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, data: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move { self.write_now(offset, data) })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move { self.truncate_now(size) })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            match &*self.content.lock() {
+                Content::Directory(entries) => entries
+                    .get(name)
+                    .map(|inode| inode.clone() as Arc<dyn Inode>)
+                    .ok_or(FsError::NotFound),
+                _ => Err(FsError::NotADirectory),
+            }
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            match &*self.content.lock() {
+                Content::Directory(entries) => Ok(entries
+                    .iter()
+                    .map(|(name, inode)| DirEntry {
+                        name: name.clone(),
+                        inode: inode.number,
+                        kind: inode.kind(),
+                    })
+                    .collect()),
+                _ => Err(FsError::NotADirectory),
+            }
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        kind: FileType,
+        mode: u16,
+    ) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let content = match kind {
+                FileType::File => Content::File {
+                    size: 0,
+                    pages: BTreeMap::new(),
+                },
+                FileType::Directory => Content::Directory(BTreeMap::new()),
+                FileType::Symlink => return Err(FsError::InvalidArgument),
+            };
+            self.insert(name, || {
+                RamInode::new(next_number(), content, mode & 0o7777)
+            })
+        })
+    }
+
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let content = Content::Symlink(String::from(target));
+            self.insert(name, || RamInode::new(next_number(), content, 0o777))
+        })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            match &*self.content.lock() {
+                Content::Symlink(target) => Ok(target.clone()),
+                _ => Err(FsError::InvalidArgument),
+            }
+        })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let mut content = self.content.lock();
+            let entries = match &mut *content {
+                Content::Directory(entries) => entries,
+                _ => return Err(FsError::NotADirectory),
+            };
+            let inode = entries.get(name).ok_or(FsError::NotFound)?;
+            if let Content::Directory(children) = &*inode.content.lock() {
+                if !children.is_empty() {
+                    return Err(FsError::DirectoryNotEmpty);
+                }
+            }
+            // Open files keep their inode until they are closed
+            entries.remove(name);
+            drop(content);
+            self.touch();
+            Ok(())
+        })
+    }
+
+    fn rename<'a>(
+        &'a self,
+        name: &'a str,
+        new_parent: &'a Arc<dyn Inode>,
+        new_name: &'a str,
+    ) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            check_name(new_name)?;
+            let new_parent = new_parent
+                .as_any()
+                .downcast_ref::<RamInode>()
+                .ok_or(FsError::CrossDevice)?;
+            let same = core::ptr::eq(self, new_parent);
+            let inode = match &*self.content.lock() {
+                Content::Directory(entries) => {
+                    entries.get(name).cloned().ok_or(FsError::NotFound)?
+                }
+                _ => return Err(FsError::NotADirectory),
+            };
+            if same && name == new_name {
+                return Ok(());
+            }
+            {
+                let mut content = new_parent.content.lock();
+                let entries = match &mut *content {
+                    Content::Directory(entries) => entries,
+                    _ => return Err(FsError::NotADirectory),
+                };
+                if let Some(existing) = entries.get(new_name) {
+                    check_replace(&inode, existing)?;
+                }
+                entries.insert(String::from(new_name), inode);
+            }
+            if let Content::Directory(entries) = &mut *self.content.lock() {
+                entries.remove(name);
+            }
+            self.touch();
+            if !same {
+                new_parent.touch();
+            }
+            Ok(())
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+///
+/// Inode numbers, from 1 on. They are unique across every ramfs
+///
+static NEXT_NUMBER: AtomicU64 = AtomicU64::new(1);
+
+fn next_number() -> u64 {
+    NEXT_NUMBER.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    ///
+    /// An empty filesystem, the root owned by root with mode `0o755`
+    ///
+    pub fn new() -> Arc<Self> {
+        Arc::new(RamFs {
+            root: RamInode::new(next_number(), Content::Directory(BTreeMap::new()), 0o755),
+        })
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
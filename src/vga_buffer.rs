@@ -36,6 +36,29 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: Color) -> ColorCode {
+        ColorCode(self.0 & 0xf0 | foreground as u8)
+    }
+
+    fn with_background(self, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | self.0 & 0x0f)
+    }
+
+    /// Sets the bright bit of the foreground
+    fn brightened(self) -> ColorCode {
+        ColorCode(self.0 | 0x08)
+    }
+
+    /// Foreground of `other`, background of `self`
+    fn with_foreground_of(self, other: ColorCode) -> ColorCode {
+        ColorCode(self.0 & 0xf0 | other.0 & 0x0f)
+    }
+
+    /// Swaps foreground and background
+    fn reversed(self) -> ColorCode {
+        ColorCode(self.0 << 4 | self.0 >> 4)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -58,51 +81,441 @@ impl ScreenChar {
     }
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub mod ansi;
+pub mod cp437;
+pub mod cursor;
+pub mod scrollback;
+
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
+use ansi::Action;
+use cursor::CursorShape;
+use scrollback::Scrollback;
 use volatile::Volatile;
 
 #[repr(transparent)]
-struct Buffer {
+///
+/// Grid of characters. Either the memory mapped VGA buffer at 0xb8000 or an
+/// off-screen copy owned by a virtual terminal
+///
+pub struct Buffer {
     // We don't want rust to optimize this
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+///
+/// Allocates a blank buffer in the heap that lives forever
+///
+pub fn new_off_screen_buffer() -> &'static mut Buffer {
+    use alloc::boxed::Box;
+    // All zeroes is a black space on black background
+    Box::leak(Box::new(unsafe { core::mem::zeroed::<Buffer>() }))
+}
+
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
     color: ColorCode,
+    /// Color restored by `ESC [ 0 m`
+    default_color: ColorCode,
+    /// Bold (`ESC [ 1 m`) is rendered as a bright foreground
+    bold: bool,
+    /// Foreground from before bold, put back by `ESC [ 22 m`
+    unbold_color: ColorCode,
+    saved_position: (usize, usize),
+    ansi: ansi::Parser,
+    cursor_shape: Option<CursorShape>,
+    /// `None` until the heap is ready, see `set_scrollback_lines`
+    scrollback: Option<Scrollback>,
+    /// Whether `buffer` is the one at 0xb8000, only that writer moves the hardware cursor
+    on_screen: bool,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
+    ///
+    /// Creates a writer that draws into an off-screen buffer, it can be put
+    /// on screen later with `swap_buffer`
+    ///
+    pub fn new_off_screen(buffer: &'static mut Buffer) -> Self {
+        let color = ColorCode::new(Color::LightGray, Color::Black);
+        Writer {
+            column_position: 0,
+            row_position: 0,
+            color,
+            default_color: color,
+            bold: false,
+            unbold_color: color,
+            saved_position: (0, 0),
+            ansi: ansi::Parser::new(),
+            cursor_shape: Some(CursorShape::Underline),
+            scrollback: None,
+            on_screen: false,
+            buffer,
+        }
+    }
+
+    ///
+    /// Makes the writer draw into `buffer` from now on, copying the current contents
+    /// into it. Returns the buffer used until now.
+    ///
+    /// `on_screen` tells whether `buffer` is the VGA one, in which case the cursor
+    /// is moved to this writer's position
+    ///
+    pub fn swap_buffer(
+        &mut self,
+        buffer: &'static mut Buffer,
+        on_screen: bool,
+    ) -> &'static mut Buffer {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            for column in 0..BUFFER_WIDTH {
+                buffer.chars[row][column].write(self.buffer.chars[row][column].read());
+            }
+        }
+        self.on_screen = on_screen;
+        let old = core::mem::replace(&mut self.buffer, buffer);
+        if on_screen {
+            match self.cursor_shape {
+                Some(shape) => cursor::enable(shape),
+                None => cursor::disable(),
+            }
+            self.update_cursor();
+        }
+        old
+    }
+
+    ///
+    /// Color that will be used for the next characters
+    ///
+    pub fn color(&self) -> ColorCode {
+        self.color
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color = ColorCode::new(foreground, background);
+    }
+
+    pub fn set_color_code(&mut self, color: ColorCode) {
+        self.color = color;
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
         // self.new_line();
         match byte {
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+            byte => self.write_glyph(byte),
+        }
+    }
+
+    ///
+    /// Draws the glyph `byte` even if it's a control character, `0x0a` is `◙` here
+    ///
+    fn write_glyph(&mut self, byte: u8) {
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        // Needs 2 bytes, first byte is the character,
+        //  second byte is the color foreground and background
+        self.buffer.chars[self.row_position][self.column_position]
+            .write(ScreenChar::new(byte, self.color));
+        self.column_position += 1;
+    }
+
+    ///
+    /// Writes a string interpreting the ANSI escape sequences in it (see `ansi`).
+    ///
+    /// Non ASCII characters are translated to code page 437 (see `cp437`)
+    ///
+    pub fn write_string(&mut self, string: &str) {
+        self.scroll_to_bottom();
+        for character in string.chars() {
+            if character.is_ascii() {
+                if let Some(action) = self.ansi.advance(character as u8) {
+                    self.apply(action);
                 }
-                let end = BUFFER_HEIGHT - 1;
-                // Needs 2 bytes, first byte is the character,
-                //  second byte is the color foreground and background
-                self.buffer.chars[end][self.column_position]
-                    .write(ScreenChar::new(byte, self.color));
-                self.column_position += 1;
+            } else {
+                self.write_glyph(cp437::from_char_or_placeholder(character));
             }
         }
+        self.update_cursor();
     }
 
-    pub fn write_string(&mut self, string: &str) {
-        for byte in string.bytes() {
-            match byte {
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Print(byte) => match byte {
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+                b'\r' => self.column_position = 0,
+                // backspace
+                0x08 => self.column_position = self.column_position.saturating_sub(1),
+                b'\t' => {
+                    let next_stop = (self.column_position / 8 + 1) * 8;
+                    while self.column_position < next_stop.min(BUFFER_WIDTH) {
+                        self.write_byte(b' ');
+                    }
+                }
+                _ => self.write_byte(cp437::PLACEHOLDER),
+            },
+            Action::CursorUp(n) => {
+                self.row_position = self.row_position.saturating_sub(n as usize);
+            }
+            Action::CursorDown(n) => {
+                self.row_position = (self.row_position + n as usize).min(BUFFER_HEIGHT - 1);
+            }
+            Action::CursorForward(n) => {
+                self.column_position = (self.column_position + n as usize).min(BUFFER_WIDTH - 1);
+            }
+            Action::CursorBack(n) => {
+                self.column_position = self.column_position.saturating_sub(n as usize);
+            }
+            Action::CursorPosition(row, column) => {
+                self.row_position = (row as usize).min(BUFFER_HEIGHT - 1);
+                self.column_position = (column as usize).min(BUFFER_WIDTH - 1);
+            }
+            Action::EraseDisplay(mode) => self.erase_display(mode),
+            Action::EraseLine(mode) => self.erase_line(mode),
+            Action::SelectGraphicRendition(params) => self.select_graphic_rendition(params),
+            Action::SaveCursor => self.saved_position = (self.row_position, self.column_position),
+            Action::RestoreCursor => {
+                let (row, column) = self.saved_position;
+                self.row_position = row;
+                self.column_position = column;
+            }
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let (row, column) = (self.row_position, self.column_position);
+        match mode {
+            0 => {
+                self.clear_region(row, column, 1, BUFFER_WIDTH);
+                self.clear_region(row + 1, 0, BUFFER_HEIGHT, BUFFER_WIDTH);
+            }
+            1 => {
+                self.clear_region(0, 0, row, BUFFER_WIDTH);
+                self.clear_region(row, 0, 1, column + 1);
+            }
+            _ => self.clear_region(0, 0, BUFFER_HEIGHT, BUFFER_WIDTH),
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let (row, column) = (self.row_position, self.column_position);
+        match mode {
+            0 => self.clear_region(row, column, 1, BUFFER_WIDTH),
+            1 => self.clear_region(row, 0, 1, column + 1),
+            _ => self.clear_region(row, 0, 1, BUFFER_WIDTH),
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: ansi::Params) {
+        // `ESC [ m` is the same as `ESC [ 0 m`
+        if params.as_slice().is_empty() {
+            self.reset_graphic_rendition();
+        }
+        for &param in params.as_slice() {
+            match param {
+                0 => self.reset_graphic_rendition(),
+                1 => {
+                    if !self.bold {
+                        self.unbold_color = self.color;
+                    }
+                    self.bold = true;
+                    self.color = self.color.brightened();
+                }
+                22 => {
+                    if self.bold {
+                        self.color = self.color.with_foreground_of(self.unbold_color);
+                    }
+                    self.bold = false;
+                }
+                7 => self.color = self.color.reversed(),
+                30..=37 => {
+                    let color = ansi::ansi_color(param - 30, self.bold);
+                    self.color = self.color.with_foreground(color);
+                    let dim = ansi::ansi_color(param - 30, false);
+                    self.unbold_color = self.unbold_color.with_foreground(dim);
+                }
+                90..=97 => {
+                    let color = ansi::ansi_color(param - 90, true);
+                    self.color = self.color.with_foreground(color);
+                    self.unbold_color = self.unbold_color.with_foreground(color);
+                }
+                39 => {
+                    self.color = self.color.with_foreground_of(self.default_color);
+                    self.unbold_color = self.default_color;
+                }
+                40..=47 => {
+                    let color = ansi::ansi_color(param - 40, false);
+                    self.color = self.color.with_background(color);
+                }
+                100..=107 => {
+                    let color = ansi::ansi_color(param - 100, true);
+                    self.color = self.color.with_background(color);
+                }
+                49 => self.color = ColorCode(self.default_color.0 & 0xf0 | self.color.0 & 0x0f),
+                // Underline, blink, italics... can't be shown in text mode
+                _ => {}
+            }
+        }
+    }
+
+    fn reset_graphic_rendition(&mut self) {
+        self.color = self.default_color;
+        self.bold = false;
+    }
+
+    ///
+    /// Writes a character at the given cell with the given color.
+    ///
+    /// Doesn't move the writing position nor the cursor. Out of bounds cells are ignored
+    ///
+    pub fn write_at(&mut self, row: usize, column: usize, byte: u8, color: ColorCode) {
+        self.scroll_to_bottom();
+        if row < BUFFER_HEIGHT && column < BUFFER_WIDTH {
+            self.buffer.chars[row][column].write(ScreenChar::new(byte, color));
+        }
+    }
+
+    ///
+    /// Writes a string starting at the given cell using the current color, clipping
+    /// it at the end of the row
+    ///
+    pub fn write_string_at(&mut self, row: usize, column: usize, string: &str) {
+        for (i, character) in string.chars().enumerate() {
+            let byte = cp437::from_char_or_placeholder(character);
+            self.write_at(row, column + i, byte, self.color);
+        }
+    }
+
+    ///
+    /// Returns the `(row, column)` where the next character will be written
+    ///
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    ///
+    /// Returns the character and color shown at the given cell
+    ///
+    pub fn read_at(&self, row: usize, column: usize) -> (u8, ColorCode) {
+        let screen_char = self.buffer.chars[row][column].read();
+        (screen_char.ascii_character, screen_char.color_code)
+    }
+
+    ///
+    /// Moves the writing position (and the cursor) to the given cell, clamping it to the screen
+    ///
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = column.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    ///
+    /// Blanks the whole screen and moves the writing position to the top left corner
+    ///
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_position(0, 0);
+    }
+
+    ///
+    /// Blanks a rectangle of the screen with the current background color
+    ///
+    pub fn clear_region(&mut self, row: usize, column: usize, height: usize, width: usize) {
+        self.scroll_to_bottom();
+        let blank = self.blank();
+        let row_end = (row + height).min(BUFFER_HEIGHT);
+        let column_end = (column + width).min(BUFFER_WIDTH);
+        for i in row..row_end {
+            for j in column..column_end {
+                self.buffer.chars[i][j].write(blank);
             }
         }
     }
 
+    ///
+    /// Shows the blinking cursor with the given shape, or hides it with `None`
+    ///
+    pub fn set_cursor_shape(&mut self, shape: Option<CursorShape>) {
+        self.cursor_shape = shape;
+        if !self.on_screen {
+            return;
+        }
+        match shape {
+            Some(shape) => cursor::enable(shape),
+            None => cursor::disable(),
+        }
+        self.update_cursor();
+    }
+
+    ///
+    /// Sets how many rows scrolled off the top of the screen are kept, 0 disables
+    /// the scrollback.
+    ///
+    /// Needs the heap, so the history is disabled until this is called
+    ///
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.scroll_to_bottom();
+        let mut scrollback = match lines {
+            0 => None,
+            lines => Some(Scrollback::new(lines)),
+        };
+        if let (Some(old), Some(new)) = (self.scrollback.take(), scrollback.as_mut()) {
+            old.move_into(new);
+        }
+        self.scrollback = scrollback;
+    }
+
+    pub fn scrollback_lines(&self) -> usize {
+        self.scrollback
+            .as_ref()
+            .map(|scrollback| scrollback.capacity())
+            .unwrap_or(0)
+    }
+
+    ///
+    /// Shows rows from the history, `lines` further up than what is shown now
+    ///
+    pub fn scroll_up(&mut self, lines: usize) {
+        if let Some(scrollback) = &mut self.scrollback {
+            scrollback.scroll_up(self.buffer, lines);
+        }
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        if let Some(scrollback) = &mut self.scrollback {
+            scrollback.scroll_down(self.buffer, lines);
+        }
+    }
+
+    ///
+    /// Goes back to the live screen if we are looking at the history
+    ///
+    pub fn scroll_to_bottom(&mut self) {
+        if let Some(scrollback) = &mut self.scrollback {
+            if scrollback.is_scrolled() {
+                scrollback.scroll_to_bottom(self.buffer);
+            }
+        }
+    }
+
+    fn update_cursor(&self) {
+        if self.on_screen && self.cursor_shape.is_some() {
+            let column = self.column_position.min(BUFFER_WIDTH - 1);
+            cursor::set_position(self.row_position, column);
+        }
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar::new(b' ', self.color)
+    }
+
     fn clear_row(&mut self, row: usize) {
         for i in 0..BUFFER_WIDTH {
             self.buffer.chars[row][i].write(ScreenChar::new(
@@ -113,20 +526,36 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        if let Some(scrollback) = &mut self.scrollback {
+            scrollback.push(scrollback::read_row(self.buffer, 0));
+        }
         for i in 1..BUFFER_HEIGHT {
             for j in 0..BUFFER_WIDTH {
                 self.buffer.chars[i - 1][j].write(self.buffer.chars[i][j].read());
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 }
 
 pub fn print_something() {
     let mut writer = Writer {
         column_position: 0,
+        row_position: BUFFER_HEIGHT - 1,
         color: ColorCode::new(Color::Yellow, Color::Black),
+        default_color: ColorCode::new(Color::Yellow, Color::Black),
+        bold: false,
+        unbold_color: ColorCode::new(Color::Yellow, Color::Black),
+        saved_position: (0, 0),
+        ansi: ansi::Parser::new(),
+        cursor_shape: None,
+        scrollback: None,
+        on_screen: true,
         // the VGA buffer is in the memory address 0xb8000
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     };
@@ -156,7 +585,17 @@ use spin::Mutex;
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
+        // Start writing at the bottom, older lines scroll up
+        row_position: BUFFER_HEIGHT - 1,
         color: ColorCode::new(Color::Yellow, Color::Black),
+        default_color: ColorCode::new(Color::Yellow, Color::Black),
+        bold: false,
+        unbold_color: ColorCode::new(Color::Yellow, Color::Black),
+        saved_position: (0, 0),
+        ansi: ansi::Parser::new(),
+        cursor_shape: Some(CursorShape::Underline),
+        scrollback: None,
+        on_screen: true,
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     });
 }
@@ -179,6 +618,9 @@ macro_rules! println {
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
+    if crate::framebuffer::console::is_active() {
+        return crate::framebuffer::console::_print(args);
+    }
     // We know that no interrupts are being called in this context
     interrupts::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
@@ -203,6 +645,69 @@ pub fn test_println_output() {
     });
 }
 
+#[test_case]
+pub fn test_write_at_position() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.position();
+        writer.set_position(3, 10);
+        writer.write_string("abc");
+        assert_eq!(writer.position(), (3, 13));
+        assert_eq!(writer.buffer.chars[3][11].read().ascii_character, b'b');
+        writer.write_string_at(4, BUFFER_WIDTH - 1, "xy");
+        assert_eq!(
+            writer.buffer.chars[4][BUFFER_WIDTH - 1]
+                .read()
+                .ascii_character,
+            b'x'
+        );
+        writer.set_position(previous.0, previous.1);
+    });
+}
+
+#[test_case]
+pub fn test_ansi_colors_and_erase() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.position();
+        writer.write_string("\x1b[6;1H\x1b[2K\x1b[1;31mA\x1b[0mB\x1b[1;32m\x1b[22mC\x1b[0m");
+        writer.write_string("\x1b[1mD\x1b[22mE");
+        let red = writer.buffer.chars[5][0].read();
+        assert_eq!(red.ascii_character, b'A');
+        assert_eq!(
+            red.color_code,
+            ColorCode::new(Color::LightRed, Color::Black)
+        );
+        let normal = writer.buffer.chars[5][1].read();
+        assert_eq!(normal.color_code, writer.default_color);
+        let green = writer.buffer.chars[5][2].read();
+        assert_eq!(green.color_code.0 & 0x0f, Color::Green as u8);
+        // Back to the default, even a bright one
+        let after_bold = writer.buffer.chars[5][4].read();
+        assert_eq!(after_bold.color_code, writer.default_color);
+        writer.set_position(previous.0, previous.1);
+    });
+}
+
+#[test_case]
+pub fn test_println_code_page_437() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nWörld ═◙");
+        let row = writer.position().0;
+        let screen_char = writer.buffer.chars[row][1].read();
+        assert_eq!(screen_char.ascii_character, 0x94);
+        let screen_char = writer.buffer.chars[row][6].read();
+        assert_eq!(screen_char.ascii_character, 0xcd);
+        // A glyph, not a new line
+        let screen_char = writer.buffer.chars[row][7].read();
+        assert_eq!(screen_char.ascii_character, 0x0a);
+    });
+}
+
 #[test_case]
 pub fn test_println_many() {
     for _ in 0..200 {
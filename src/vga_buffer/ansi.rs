@@ -0,0 +1,234 @@
+//!
+//! Parser for the subset of ANSI/VT100 escape sequences understood by the VGA console
+//!
+//! Supported sequences (`n`/`m` default to 1 unless stated otherwise):
+//!
+//! * `ESC [ n A/B/C/D`: cursor up/down/forward/back
+//! * `ESC [ n ; m H` and `ESC [ n ; m f`: cursor position, 1-based
+//! * `ESC [ n J`: erase display (0 to the end, 1 to the start, 2 everything), default 0
+//! * `ESC [ n K`: erase line, same modes as `J`, default 0
+//! * `ESC [ ... m`: SGR (colors)
+//! * `ESC [ s` / `ESC [ u`: save/restore cursor position
+//!
+//! Any other sequence is swallowed so it doesn't show up as garbage on the screen.
+//!
+use super::Color;
+
+const MAX_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Numeric parameters of a CSI sequence. Missing parameters are stored as `0`
+///
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Self {
+        Self {
+            values: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.values[..self.len]
+    }
+
+    ///
+    /// Returns parameter `index` or `default` when it is missing or 0
+    ///
+    pub fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.as_slice().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// What the writer has to do after feeding a byte to the parser
+///
+pub enum Action {
+    /// A byte that must be drawn (or handled as a plain control character)
+    Print(u8),
+    CursorUp(u16),
+    CursorDown(u16),
+    CursorForward(u16),
+    CursorBack(u16),
+    /// Zero-based `(row, column)`
+    CursorPosition(u16, u16),
+    EraseDisplay(u16),
+    EraseLine(u16),
+    SelectGraphicRendition(Params),
+    SaveCursor,
+    RestoreCursor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+const ESCAPE: u8 = 0x1b;
+
+pub struct Parser {
+    state: State,
+    params: Params,
+    /// Whether a digit was seen for the parameter being parsed
+    current_started: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: Params::new(),
+            current_started: false,
+        }
+    }
+
+    ///
+    /// Feeds a byte to the state machine, returning an action once a character
+    /// or a full sequence has been read
+    ///
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => {
+                if byte == ESCAPE {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Action::Print(byte))
+                }
+            }
+            State::Escape => {
+                if byte == b'[' {
+                    self.state = State::Csi;
+                    self.params = Params::new();
+                    self.current_started = false;
+                } else {
+                    // Two byte sequences (ESC 7, ESC c...) are not supported, drop them
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => self.advance_csi(byte),
+        }
+    }
+
+    fn advance_csi(&mut self, byte: u8) -> Option<Action> {
+        match byte {
+            b'0'..=b'9' => {
+                if !self.current_started {
+                    self.push_param();
+                    self.current_started = true;
+                }
+                if self.params.len > 0 {
+                    let value = &mut self.params.values[self.params.len - 1];
+                    *value = value
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+                None
+            }
+            b';' => {
+                if !self.current_started {
+                    // Empty parameter, like the first one of `ESC [ ; 5 H`
+                    self.push_param();
+                }
+                self.current_started = false;
+                None
+            }
+            // Intermediate and private marker bytes (`?`, ` `...), ignored
+            0x20..=0x2f | b'<'..=b'?' => None,
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                self.dispatch(byte)
+            }
+            // Anything else aborts the sequence
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    fn push_param(&mut self) {
+        if self.params.len < MAX_PARAMS {
+            self.params.values[self.params.len] = 0;
+            self.params.len += 1;
+        }
+    }
+
+    fn dispatch(&mut self, final_byte: u8) -> Option<Action> {
+        let params = self.params;
+        let action = match final_byte {
+            b'A' => Action::CursorUp(params.get_or(0, 1)),
+            b'B' => Action::CursorDown(params.get_or(0, 1)),
+            b'C' => Action::CursorForward(params.get_or(0, 1)),
+            b'D' => Action::CursorBack(params.get_or(0, 1)),
+            b'H' | b'f' => Action::CursorPosition(params.get_or(0, 1) - 1, params.get_or(1, 1) - 1),
+            b'J' => Action::EraseDisplay(params.get_or(0, 0)),
+            b'K' => Action::EraseLine(params.get_or(0, 0)),
+            b'm' => Action::SelectGraphicRendition(params),
+            b's' => Action::SaveCursor,
+            b'u' => Action::RestoreCursor,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+///
+/// Maps the 8 ANSI colors (`30..=37` minus 30) to the VGA palette
+///
+pub fn ansi_color(index: u16, bright: bool) -> Color {
+    match (index, bright) {
+        (0, false) => Color::Black,
+        (1, false) => Color::Red,
+        (2, false) => Color::Green,
+        (3, false) => Color::Brown,
+        (4, false) => Color::Blue,
+        (5, false) => Color::Magenta,
+        (6, false) => Color::Cyan,
+        (7, false) => Color::LightGray,
+        (0, true) => Color::DarkGray,
+        (1, true) => Color::LightRed,
+        (2, true) => Color::LightGreen,
+        (3, true) => Color::Yellow,
+        (4, true) => Color::LightBlue,
+        (5, true) => Color::Pink,
+        (6, true) => Color::LightCyan,
+        _ => Color::White,
+    }
+}
+
+#[test_case]
+fn test_ansi_parses_sgr() {
+    let mut parser = Parser::new();
+    let mut last = None;
+    for &byte in b"\x1b[1;31m" {
+        last = parser.advance(byte);
+    }
+    match last {
+        Some(Action::SelectGraphicRendition(params)) => assert_eq!(params.as_slice(), &[1, 31]),
+        other => panic!("unexpected action {:?}", other),
+    }
+    assert_eq!(parser.advance(b'x'), Some(Action::Print(b'x')));
+}
+
+#[test_case]
+fn test_ansi_parses_cursor_position_defaults() {
+    let mut parser = Parser::new();
+    let mut last = None;
+    for &byte in b"\x1b[;5H" {
+        last = parser.advance(byte);
+    }
+    assert_eq!(last, Some(Action::CursorPosition(0, 4)));
+}
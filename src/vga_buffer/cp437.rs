@@ -0,0 +1,89 @@
+//!
+//! Translation from Unicode to code page 437, the character set of the VGA text mode font
+//!
+
+///
+/// Glyph shown when a character can't be represented (`■`)
+///
+pub const PLACEHOLDER: u8 = 0xfe;
+
+///
+/// Glyphs of the control character range `0x00..=0x1f`. `0x00` is left empty
+///
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+///
+/// Glyphs of `0x80..=0xff`
+///
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+///
+/// Characters without their own glyph that look close enough to one of the table
+///
+const ALIASES: [(char, u8); 7] = [
+    ('β', 0xe1), // drawn like ß
+    ('μ', 0xe6), // Greek mu, the table has the micro sign
+    ('∈', 0xee), // drawn like ε
+    ('Π', 0xe3), // close to π
+    ('∑', 0xe4), // n-ary summation, drawn like Σ
+    ('Ø', 0xed), // drawn like φ
+    ('⌂', 0x7f),
+];
+
+///
+/// Returns the code page 437 byte that renders `character`, if there is one.
+///
+/// Printable ASCII maps to itself
+///
+pub fn from_char(character: char) -> Option<u8> {
+    match character {
+        ' '..='~' => Some(character as u8),
+        _ => LOW
+            .iter()
+            .skip(1)
+            .position(|&glyph| glyph == character)
+            .map(|index| index as u8 + 1)
+            .or_else(|| {
+                HIGH.iter()
+                    .position(|&glyph| glyph == character)
+                    .map(|index| index as u8 + 0x80)
+            })
+            .or_else(|| {
+                ALIASES
+                    .iter()
+                    .find(|&&(alias, _)| alias == character)
+                    .map(|&(_, byte)| byte)
+            }),
+    }
+}
+
+///
+/// Like `from_char` but falls back to `PLACEHOLDER`
+///
+pub fn from_char_or_placeholder(character: char) -> u8 {
+    from_char(character).unwrap_or(PLACEHOLDER)
+}
+
+#[test_case]
+fn test_cp437_mapping() {
+    assert_eq!(from_char('a'), Some(b'a'));
+    assert_eq!(from_char('ö'), Some(0x94));
+    assert_eq!(from_char('═'), Some(0xcd));
+    assert_eq!(from_char('█'), Some(0xdb));
+    assert_eq!(from_char('Ω'), Some(0xea));
+    assert_eq!(from_char('→'), Some(0x1a));
+    assert_eq!(from_char('漢'), None);
+    assert_eq!(from_char_or_placeholder('漢'), PLACEHOLDER);
+}
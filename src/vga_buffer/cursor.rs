@@ -0,0 +1,82 @@
+//!
+//! Hardware text-mode cursor, driven through the CRT controller registers
+//!
+use x86_64::instructions::port::Port;
+
+const CRTC_ADDRESS_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+
+const CURSOR_START_REGISTER: u8 = 0x0A;
+const CURSOR_END_REGISTER: u8 = 0x0B;
+const CURSOR_LOCATION_HIGH_REGISTER: u8 = 0x0E;
+const CURSOR_LOCATION_LOW_REGISTER: u8 = 0x0F;
+
+/// Bit 5 of the cursor start register hides the cursor
+const CURSOR_DISABLE: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Scanlines (0 top, 15 bottom) covered by the blinking cursor
+///
+pub enum CursorShape {
+    Underline,
+    HalfBlock,
+    Block,
+    Custom { start: u8, end: u8 },
+}
+
+impl CursorShape {
+    fn scanlines(self) -> (u8, u8) {
+        match self {
+            CursorShape::Underline => (13, 14),
+            CursorShape::HalfBlock => (8, 15),
+            CursorShape::Block => (0, 15),
+            CursorShape::Custom { start, end } => (start & 0x1f, end & 0x1f),
+        }
+    }
+}
+
+unsafe fn read_register(index: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    address.write(index);
+    data.read()
+}
+
+unsafe fn write_register(index: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    address.write(index);
+    data.write(value);
+}
+
+///
+/// Moves the blinking cursor to the given cell
+///
+pub fn set_position(row: usize, column: usize) {
+    let position = (row * super::BUFFER_WIDTH + column) as u16;
+    unsafe {
+        write_register(CURSOR_LOCATION_LOW_REGISTER, (position & 0xff) as u8);
+        write_register(CURSOR_LOCATION_HIGH_REGISTER, (position >> 8) as u8);
+    }
+}
+
+///
+/// Shows the cursor with the given shape
+///
+pub fn enable(shape: CursorShape) {
+    let (start, end) = shape.scanlines();
+    unsafe {
+        // Keep the reserved bits of both registers as they are
+        let start_register = read_register(CURSOR_START_REGISTER) & 0xc0;
+        write_register(CURSOR_START_REGISTER, start_register | start);
+        let end_register = read_register(CURSOR_END_REGISTER) & 0xe0;
+        write_register(CURSOR_END_REGISTER, end_register | end);
+    }
+}
+
+pub fn disable() {
+    unsafe {
+        write_register(CURSOR_START_REGISTER, CURSOR_DISABLE);
+    }
+}
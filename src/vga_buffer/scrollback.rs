@@ -0,0 +1,129 @@
+//!
+//! History of the rows that scrolled off the top of the screen
+//!
+use super::{Buffer, ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+///
+/// Lines kept by default, each one takes `2 * BUFFER_WIDTH` bytes of heap
+///
+pub const DEFAULT_HISTORY_LINES: usize = 100;
+
+pub(super) type Row = [ScreenChar; BUFFER_WIDTH];
+
+pub struct Scrollback {
+    history: VecDeque<Row>,
+    capacity: usize,
+    /// How many rows above the live screen we are looking at, 0 means the live screen
+    offset: usize,
+    /// Copy of the live screen while the history is shown
+    saved_screen: Vec<Row>,
+}
+
+impl Scrollback {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+            offset: 0,
+            saved_screen: Vec::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.offset > 0
+    }
+
+    ///
+    /// Moves the most recent rows that fit into `other`
+    ///
+    pub(super) fn move_into(self, other: &mut Scrollback) {
+        let skip = self.history.len().saturating_sub(other.capacity);
+        for row in self.history.into_iter().skip(skip) {
+            other.push(row);
+        }
+    }
+
+    ///
+    /// Stores a row that is about to be scrolled off the screen
+    ///
+    pub(super) fn push(&mut self, row: Row) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(row);
+    }
+
+    ///
+    /// Moves the view `lines` rows back in the history and redraws the screen
+    ///
+    pub(super) fn scroll_up(&mut self, buffer: &mut Buffer, lines: usize) {
+        if self.history.is_empty() {
+            return;
+        }
+        if self.offset == 0 {
+            self.saved_screen = (0..BUFFER_HEIGHT)
+                .map(|row| read_row(buffer, row))
+                .collect();
+        }
+        self.offset = (self.offset + lines).min(self.history.len());
+        self.render(buffer);
+    }
+
+    ///
+    /// Moves the view `lines` rows forward, restoring the live screen when reaching it
+    ///
+    pub(super) fn scroll_down(&mut self, buffer: &mut Buffer, lines: usize) {
+        if self.offset == 0 {
+            return;
+        }
+        self.offset = self.offset.saturating_sub(lines);
+        self.render(buffer);
+        if self.offset == 0 {
+            self.saved_screen = Vec::new();
+        }
+    }
+
+    pub(super) fn scroll_to_bottom(&mut self, buffer: &mut Buffer) {
+        self.scroll_down(buffer, self.offset);
+    }
+
+    fn render(&self, buffer: &mut Buffer) {
+        let top = self.history.len() - self.offset;
+        for screen_row in 0..BUFFER_HEIGHT {
+            let line = top + screen_row;
+            let row = if line < self.history.len() {
+                &self.history[line]
+            } else {
+                &self.saved_screen[line - self.history.len()]
+            };
+            for (column, &screen_char) in row.iter().enumerate() {
+                buffer.chars[screen_row][column].write(screen_char);
+            }
+        }
+    }
+}
+
+pub(super) fn read_row(buffer: &Buffer, row: usize) -> Row {
+    let mut copy = [buffer.chars[row][0].read(); BUFFER_WIDTH];
+    for (column, screen_char) in copy.iter_mut().enumerate() {
+        *screen_char = buffer.chars[row][column].read();
+    }
+    copy
+}
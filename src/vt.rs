@@ -0,0 +1,198 @@
+//!
+//! Virtual terminals
+//!
+//! There are `TERMINAL_COUNT` consoles, each one with its own `Writer` drawing into an
+//! off-screen `Buffer`, its own cursor and its own input queue. Only the active one
+//! draws into the VGA memory; switching (Alt+F1..F6) copies the screen back into the
+//! old terminal buffer and the new terminal buffer onto the screen.
+//!
+//! Terminal 0 is `vga_buffer::WRITER`, so `print!` and the kernel logs end up there. The
+//! shell runs on terminal 1.
+//!
+use crate::vga_buffer::{self, scrollback::DEFAULT_HISTORY_LINES, Buffer, Writer, WRITER};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+pub const TERMINAL_COUNT: usize = 6;
+
+const INPUT_QUEUE_SIZE: usize = 128;
+
+pub struct Terminal {
+    writer: &'static Mutex<Writer>,
+    /// The buffer the writer is not using: the off-screen one while the terminal is active
+    spare_buffer: Mutex<Option<&'static mut Buffer>>,
+    input: ArrayQueue<char>,
+    input_waker: AtomicWaker,
+    /// Whether the keyboard task prints the keys it sends to this terminal
+    echo: AtomicBool,
+}
+
+impl Terminal {
+    fn new(writer: &'static Mutex<Writer>, spare_buffer: Option<&'static mut Buffer>) -> Self {
+        Self {
+            writer,
+            spare_buffer: Mutex::new(spare_buffer),
+            input: ArrayQueue::new(INPUT_QUEUE_SIZE),
+            input_waker: AtomicWaker::new(),
+            echo: AtomicBool::new(true),
+        }
+    }
+
+    pub fn writer(&self) -> &'static Mutex<Writer> {
+        self.writer
+    }
+
+    pub fn set_echo(&self, echo: bool) {
+        self.echo.store(echo, Ordering::Relaxed);
+    }
+
+    pub fn echo(&self) -> bool {
+        self.echo.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Queues a key for whoever reads this terminal. Keys are dropped if nobody does
+    ///
+    pub fn push_input(&self, character: char) {
+        if self.input.push(character).is_ok() {
+            self.input_waker.wake();
+        }
+    }
+
+    ///
+    /// Stream of the keys typed while this terminal was active
+    ///
+    pub fn input(&'static self) -> InputStream {
+        InputStream { terminal: self }
+    }
+
+    pub fn write_fmt(&self, args: fmt::Arguments) {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+        // The text buffers are not visible under a graphics console, show the active one there
+        let is_active = active().map_or(false, |active| core::ptr::eq(active, self));
+        if is_active && crate::framebuffer::console::is_active() {
+            return crate::framebuffer::console::_print(args);
+        }
+        interrupts::without_interrupts(|| {
+            let _ = self.writer.lock().write_fmt(args);
+        });
+    }
+}
+
+pub struct InputStream {
+    terminal: &'static Terminal,
+}
+
+impl Stream for InputStream {
+    type Item = char;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<char>> {
+        let input = &self.terminal.input;
+        if let Ok(character) = input.pop() {
+            return Poll::Ready(Some(character));
+        }
+        self.terminal.input_waker.register(&cx.waker());
+        match input.pop() {
+            Ok(character) => Poll::Ready(Some(character)),
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+static TERMINALS: OnceCell<Vec<Terminal>> = OnceCell::uninit();
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Creates the virtual terminals. Needs the heap
+///
+pub fn init() {
+    TERMINALS
+        .try_init_once(|| {
+            let mut terminals = Vec::with_capacity(TERMINAL_COUNT);
+            terminals.push(Terminal::new(
+                &WRITER,
+                Some(vga_buffer::new_off_screen_buffer()),
+            ));
+            for _ in 1..TERMINAL_COUNT {
+                let mut writer = Writer::new_off_screen(vga_buffer::new_off_screen_buffer());
+                writer.set_scrollback_lines(DEFAULT_HISTORY_LINES);
+                let writer: &'static Mutex<Writer> = Box::leak(Box::new(Mutex::new(writer)));
+                terminals.push(Terminal::new(writer, None));
+            }
+            terminals
+        })
+        .expect("virtual terminals should be initialized once");
+}
+
+///
+/// Returns terminal `index` (0 based, Alt+F1 is terminal 0), `None` if it doesn't
+/// exist or `init` wasn't called yet
+///
+pub fn terminal(index: usize) -> Option<&'static Terminal> {
+    TERMINALS.get()?.get(index)
+}
+
+pub fn active_index() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn active() -> Option<&'static Terminal> {
+    terminal(active_index())
+}
+
+///
+/// Puts terminal `index` on the screen
+///
+pub fn switch_to(index: usize) {
+    use x86_64::instructions::interrupts;
+    let terminals = match TERMINALS.get() {
+        Some(terminals) if index < terminals.len() => terminals,
+        _ => return,
+    };
+    interrupts::without_interrupts(|| {
+        let current = ACTIVE.load(Ordering::Relaxed);
+        if current == index {
+            return;
+        }
+        let (old, new) = (&terminals[current], &terminals[index]);
+        let off_screen = old
+            .spare_buffer
+            .lock()
+            .take()
+            .expect("the active terminal has a spare buffer");
+        let screen = old.writer.lock().swap_buffer(off_screen, false);
+        let off_screen = new.writer.lock().swap_buffer(screen, true);
+        *new.spare_buffer.lock() = Some(off_screen);
+        ACTIVE.store(index, Ordering::Relaxed);
+    });
+}
+
+#[doc(hidden)]
+pub fn _print(index: usize, args: fmt::Arguments) {
+    if let Some(terminal) = terminal(index) {
+        terminal.write_fmt(args);
+    }
+}
+
+/// Prints to the given virtual terminal
+#[macro_export]
+macro_rules! vt_print {
+    ($terminal:expr, $($arg:tt)*) => ($crate::vt::_print($terminal, format_args!($($arg)*)));
+}
+
+/// Prints to the given virtual terminal, appending a newline
+#[macro_export]
+macro_rules! vt_println {
+    ($terminal:expr) => ($crate::vt_print!($terminal, "\n"));
+    ($terminal:expr, $($arg:tt)*) => ($crate::vt_print!($terminal, "{}\n", format_args!($($arg)*)));
+}
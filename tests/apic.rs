@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::apic::io::Trigger;
+use rust_os::apic::{InterruptOverride, Topology};
+use rust_os::memory;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn legacy_topology() {
+    let topology = Topology::legacy();
+    assert_eq!(topology.io_apics.len(), 1);
+    assert_eq!(topology.io_apics[0].address, PhysAddr::new(0xfec0_0000));
+    assert_eq!(topology.io_apics[0].gsi_base, 0);
+    assert_eq!(topology.local_apic.as_u64() & 0xfff, 0);
+    // The PIT moves to pin 2, where the cascade was
+    assert_eq!(topology.gsi_of(0), Some((2, Trigger::EDGE_HIGH)));
+    assert_eq!(topology.gsi_of(2), None);
+    assert_eq!(topology.gsi_of(1), Some((1, Trigger::EDGE_HIGH)));
+    assert_eq!(topology.gsi_of(20), Some((20, Trigger::LEVEL_LOW)));
+}
+
+#[test_case]
+fn overrides_move_isa_lines() {
+    let mut topology = Topology::legacy();
+    topology.overrides = vec![
+        InterruptOverride {
+            source: 0,
+            gsi: 2,
+            trigger: Trigger::EDGE_HIGH,
+        },
+        // Like the ACPI interrupt on most boards
+        InterruptOverride {
+            source: 9,
+            gsi: 9,
+            trigger: Trigger::LEVEL_LOW,
+        },
+        InterruptOverride {
+            source: 11,
+            gsi: 20,
+            trigger: Trigger::LEVEL_LOW,
+        },
+    ];
+    assert_eq!(topology.gsi_of(9), Some((9, Trigger::LEVEL_LOW)));
+    assert_eq!(topology.gsi_of(11), Some((20, Trigger::LEVEL_LOW)));
+    // Line 20 would be on the pin line 11 took
+    assert_eq!(topology.gsi_of(20), None);
+    assert_eq!(topology.gsi_of(10), Some((10, Trigger::EDGE_HIGH)));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,237 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::allocator;
+use rust_os::block::partition::{self, PartitionKind};
+use rust_os::block::{self, cache, BlockDevice, BlockError, BlockFuture};
+use rust_os::memory;
+use rust_os::task::block_on;
+use spin::Mutex;
+
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    test_main();
+    loop {}
+}
+
+const BLOCK_SIZE: usize = 512;
+
+///
+/// Disk in memory that counts the requests it gets
+///
+struct MemoryDisk {
+    data: Mutex<Vec<u8>>,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl MemoryDisk {
+    fn new(blocks: usize) -> Arc<Self> {
+        Arc::new(MemoryDisk {
+            data: Mutex::new(vec![0; blocks * BLOCK_SIZE]),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a mut [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            block::check_range(self, start, buffer.len())?;
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let offset = start as usize * BLOCK_SIZE;
+            buffer.copy_from_slice(&self.data.lock()[offset..offset + buffer.len()]);
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buffer: &'a [u8],
+    ) -> BlockFuture<'a, Result<(), BlockError>> {
+        Box::pin(async move {
+            block::check_range(self, start, buffer.len())?;
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            let offset = start as usize * BLOCK_SIZE;
+            self.data.lock()[offset..offset + buffer.len()].copy_from_slice(buffer);
+            Ok(())
+        })
+    }
+}
+
+#[test_case]
+fn out_of_range_requests_are_refused() {
+    let disk = MemoryDisk::new(4);
+    let mut buffer = [0; BLOCK_SIZE];
+    assert_eq!(
+        block_on(disk.read_blocks(4, &mut buffer)),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        block_on(disk.read_blocks(0, &mut buffer[..100])),
+        Err(BlockError::BadBuffer)
+    );
+}
+
+#[test_case]
+fn cached_blocks_are_read_once() {
+    let disk = MemoryDisk::new(16);
+    disk.data.lock()[BLOCK_SIZE * 5] = 42;
+    let device: Arc<dyn BlockDevice> = disk.clone();
+    let mut byte = [0];
+    block_on(cache::read(&device, 5, 0, &mut byte)).unwrap();
+    assert_eq!(byte[0], 42);
+    block_on(cache::read(&device, 5, 0, &mut byte)).unwrap();
+    assert_eq!(disk.reads.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn sequential_reads_read_ahead() {
+    let disk = MemoryDisk::new(32);
+    let device: Arc<dyn BlockDevice> = disk.clone();
+    let mut buffer = [0; BLOCK_SIZE];
+    for block in 0..(2 + cache::READ_AHEAD) {
+        block_on(cache::read(&device, block, 0, &mut buffer)).unwrap();
+    }
+    // The first two blocks and then the rest in one go
+    assert_eq!(disk.reads.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn writes_reach_the_disk_when_flushed() {
+    let disk = MemoryDisk::new(8);
+    let device: Arc<dyn BlockDevice> = disk.clone();
+    block_on(cache::write(&device, 3, 10, &[1, 2, 3])).unwrap();
+    block_on(cache::write(&device, 4, 0, &[7; BLOCK_SIZE])).unwrap();
+    assert_eq!(disk.data.lock()[BLOCK_SIZE * 3 + 10], 0);
+    let mut bytes = [0; 3];
+    block_on(cache::read(&device, 3, 10, &mut bytes)).unwrap();
+    assert_eq!(bytes, [1, 2, 3]);
+    block_on(cache::flush(&device)).unwrap();
+    assert_eq!(disk.data.lock()[BLOCK_SIZE * 3 + 10..][..3], [1, 2, 3]);
+    assert_eq!(disk.data.lock()[BLOCK_SIZE * 4], 7);
+    // Blocks 3 and 4 are next to each other, one request
+    assert_eq!(disk.writes.load(Ordering::SeqCst), 1);
+    block_on(cache::release(&device)).unwrap();
+}
+
+fn mbr_entry(sector: &mut [u8], index: usize, kind: u8, start: u32, blocks: u32) {
+    let entry = &mut sector[446 + index * 16..][..16];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+}
+
+#[test_case]
+fn mbr_partitions_are_found() {
+    let disk = MemoryDisk::new(64);
+    {
+        let mut data = disk.data.lock();
+        mbr_entry(&mut data, 0, 0x83, 2, 10);
+        mbr_entry(&mut data, 1, 0x05, 20, 40);
+        // One logical partition in the extended one
+        mbr_entry(&mut data[20 * BLOCK_SIZE..], 0, 0x0b, 1, 8);
+        data[2 * BLOCK_SIZE] = 0x11;
+    }
+    let device: Arc<dyn BlockDevice> = disk;
+    let partitions = block_on(partition::scan(&device)).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].name(), "memory1");
+    assert_eq!(partitions[0].kind, PartitionKind::Mbr(0x83));
+    assert_eq!(partitions[1].number, 5);
+    assert_eq!(partitions[1].start, 21);
+    assert_eq!(partitions[1].blocks, 8);
+
+    let mut buffer = [0; BLOCK_SIZE];
+    block_on(partitions[0].read_blocks(0, &mut buffer)).unwrap();
+    assert_eq!(buffer[0], 0x11);
+    assert_eq!(
+        block_on(partitions[0].read_blocks(10, &mut buffer)),
+        Err(BlockError::OutOfRange)
+    );
+}
+
+#[test_case]
+fn gpt_partitions_are_found() {
+    let disk = MemoryDisk::new(64);
+    {
+        let mut data = disk.data.lock();
+        mbr_entry(&mut data, 0, 0xee, 1, 63);
+        // One entry of 128 bytes in LBA 2
+        let entry = &mut data[2 * BLOCK_SIZE..][..128];
+        entry[0] = 0xaf;
+        entry[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&43u64.to_le_bytes());
+        for (i, c) in "root".encode_utf16().enumerate() {
+            entry[56 + i * 2..][..2].copy_from_slice(&c.to_le_bytes());
+        }
+        let entries_crc = partition::crc32(&data[2 * BLOCK_SIZE..][..128]);
+        let header = &mut data[BLOCK_SIZE..][..92];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&1u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = partition::crc32(header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    }
+    let device: Arc<dyn BlockDevice> = disk.clone();
+    let partitions = block_on(partition::scan(&device)).unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].start, 34);
+    assert_eq!(partitions[0].blocks, 10);
+    match &partitions[0].kind {
+        PartitionKind::Gpt { name, .. } => assert_eq!(name, "root"),
+        kind => panic!("not a GPT partition: {:?}", kind),
+    }
+
+    // A damaged header and no backup, no partitions
+    disk.data.lock()[BLOCK_SIZE + 80] = 2;
+    assert!(block_on(partition::scan(&device)).unwrap().is_empty());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::allocator;
+use rust_os::driver::{self, Bus, Device, Driver, DriverError, Resource};
+use rust_os::memory;
+
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    test_main();
+    loop {}
+}
+
+///
+/// Takes the devices whose name starts with `prefix`, refusing the ones without
+/// resources. Each test has its own, the counts start at zero whatever ran before
+///
+struct FakeDriver {
+    prefix: &'static str,
+    probed: AtomicUsize,
+    removed: AtomicUsize,
+}
+
+impl FakeDriver {
+    const fn new(prefix: &'static str) -> Self {
+        FakeDriver {
+            prefix,
+            probed: AtomicUsize::new(0),
+            removed: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Driver for FakeDriver {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        device.name.starts_with(self.prefix)
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        if device.resources.is_empty() {
+            return Err(DriverError::Unsupported);
+        }
+        self.probed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Device) {
+        self.removed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn devices_added_before_the_driver_are_probed() {
+    static DRIVER: FakeDriver = FakeDriver::new("early");
+    driver::add_device("early0", None, Bus::Isa, vec![Resource::Irq(9)]);
+    driver::add_device("early-empty", None, Bus::Isa, Vec::new());
+    driver::register_driver(&DRIVER);
+    assert_eq!(DRIVER.probed.load(Ordering::SeqCst), 1);
+    let bound = driver::find_device("early0").unwrap();
+    assert_eq!(bound.driver_name(), Some("fake"));
+    assert_eq!(bound.irq(), Some(9));
+    assert_eq!(
+        driver::find_device("early-empty").unwrap().driver_name(),
+        None
+    );
+}
+
+#[test_case]
+fn removing_a_bus_removes_its_devices() {
+    static DRIVER: FakeDriver = FakeDriver::new("child");
+    driver::register_driver(&DRIVER);
+    let bus = driver::add_device("bus", None, Bus::Root, Vec::new());
+    let io = Resource::Io { base: 0x80, len: 1 };
+    driver::add_device("child1", Some(bus), Bus::Isa, vec![io]);
+    driver::add_device("child2", Some(bus), Bus::Isa, vec![io]);
+    assert_eq!(DRIVER.probed.load(Ordering::SeqCst), 2);
+    driver::remove_device(bus);
+    assert_eq!(DRIVER.removed.load(Ordering::SeqCst), 2);
+    assert!(driver::find_device("child1").is_none());
+    assert!(driver::find_device("bus").is_none());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::framebuffer::{self, Framebuffer, PixelFormat, Rgb};
+use rust_os::gui::{DoubleBuffer, Painter, Point, Rect, Surface};
+use rust_os::memory;
+
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    test_main();
+    loop {}
+}
+
+const RED: Rgb = Rgb::new(0xff, 0, 0);
+
+#[test_case]
+fn lines_include_both_ends() {
+    let mut surface = Surface::new(16, 16, Rgb::BLACK);
+    surface
+        .painter()
+        .draw_line(Point::new(1, 2), Point::new(12, 7), RED);
+    assert_eq!(surface.pixel(1, 2), RED);
+    assert_eq!(surface.pixel(12, 7), RED);
+    assert_eq!(surface.pixel(12, 2), Rgb::BLACK);
+}
+
+#[test_case]
+fn drawing_is_clipped() {
+    let mut surface = Surface::new(16, 16, Rgb::BLACK);
+    let mut painter = Painter::with_area(&mut surface, Rect::new(4, 4, 8, 8));
+    // Way bigger than the area, only the area gets painted
+    painter.fill_rect(Rect::new(-10, -10, 100, 100), RED);
+    painter.fill_circle(Point::new(0, 0), 20, RED);
+    assert_eq!(surface.pixel(4, 4), RED);
+    assert_eq!(surface.pixel(11, 11), RED);
+    assert_eq!(surface.pixel(3, 4), Rgb::BLACK);
+    assert_eq!(surface.pixel(12, 12), Rgb::BLACK);
+}
+
+#[test_case]
+fn blit_copies_the_visible_part() {
+    let mut source = Surface::new(4, 4, RED);
+    source.painter().draw_pixel(3, 3, Rgb::WHITE);
+    let mut target = Surface::new(8, 8, Rgb::BLACK);
+    target.painter().blit(&source, Point::new(6, 6));
+    assert_eq!(target.pixel(6, 6), RED);
+    assert_eq!(target.pixel(7, 7), RED);
+    target.painter().blit(&source, Point::new(-3, -3));
+    assert_eq!(target.pixel(0, 0), Rgb::WHITE);
+    assert_eq!(target.pixel(1, 1), Rgb::BLACK);
+}
+
+#[test_case]
+fn double_buffer_fits_the_default_mode() {
+    let (width, height) = (framebuffer::DEFAULT_WIDTH, framebuffer::DEFAULT_HEIGHT);
+    // Stands in for the video memory, same size as the real one
+    let mut video = vec![0u8; width * height * 4];
+    let front = unsafe {
+        Framebuffer::new(
+            x86_64::VirtAddr::from_ptr(video.as_mut_ptr()),
+            width,
+            height,
+            width * 4,
+            PixelFormat::Bgr32,
+        )
+    };
+    let mut screen = DoubleBuffer::new(front);
+    let bounds = screen.bounds();
+    assert_eq!(bounds, Rect::new(0, 0, width as i32, height as i32));
+    screen.painter().fill_rect(bounds, RED);
+    screen.present();
+    let last = (width * height - 1) * 4;
+    assert_eq!(video[last + 2], 0xff);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
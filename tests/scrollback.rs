@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::memory;
+use rust_os::println;
+use rust_os::vga_buffer::{BUFFER_HEIGHT, WRITER};
+
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn scrolled_off_lines_are_kept() {
+    WRITER.lock().set_scrollback_lines(50);
+    for i in 0..BUFFER_HEIGHT + 10 {
+        println!("line {}", i);
+    }
+    let mut writer = WRITER.lock();
+    writer.scroll_up(10);
+    // The bottom row is now the one that was 10 lines above the last printed one
+    let (character, _) = writer.read_at(BUFFER_HEIGHT - 1, 5);
+    assert_eq!(character, b'2');
+    writer.scroll_down(10);
+    let (character, _) = writer.read_at(BUFFER_HEIGHT - 2, 5);
+    assert_eq!(character, b'3');
+}
+
+#[test_case]
+fn history_is_bounded() {
+    let mut writer = WRITER.lock();
+    writer.set_scrollback_lines(5);
+    assert_eq!(writer.scrollback_lines(), 5);
+    drop(writer);
+    for _ in 0..20 {
+        println!("more output");
+    }
+    let mut writer = WRITER.lock();
+    writer.scroll_up(100);
+    writer.scroll_to_bottom();
+    writer.set_scrollback_lines(0);
+    assert_eq!(writer.scrollback_lines(), 0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
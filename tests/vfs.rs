@@ -0,0 +1,223 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::memory;
+use rust_os::task::block_on;
+use rust_os::vfs::{self, initrd, FileType, FsError, OpenFlags, SeekFrom};
+
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    vfs::init();
+    test_main();
+    loop {}
+}
+
+const CREATE: OpenFlags = OpenFlags(OpenFlags::READ.0 | OpenFlags::WRITE.0 | OpenFlags::CREATE.0);
+
+#[test_case]
+fn files_keep_what_is_written() {
+    let fd = block_on(vfs::open("/hello", CREATE)).unwrap();
+    assert_eq!(block_on(vfs::write(fd, b"hello world")), Ok(11));
+    assert_eq!(block_on(vfs::seek(fd, SeekFrom::Start(6))), Ok(6));
+    let mut buffer = [0; 16];
+    assert_eq!(block_on(vfs::read(fd, &mut buffer)), Ok(5));
+    assert_eq!(&buffer[..5], b"world");
+    assert_eq!(block_on(vfs::read(fd, &mut buffer)), Ok(0));
+    vfs::close(fd).unwrap();
+    assert_eq!(vfs::close(fd), Err(FsError::BadDescriptor));
+    assert_eq!(block_on(vfs::stat("/hello")).unwrap().size, 11);
+}
+
+#[test_case]
+fn paths_are_resolved() {
+    block_on(vfs::create_dir_all("/a/b/c", 0o755)).unwrap();
+    block_on(vfs::symlink("/a/b", "/link")).unwrap();
+    assert_eq!(
+        block_on(vfs::stat("/link/c")).unwrap().kind,
+        FileType::Directory
+    );
+    assert_eq!(
+        block_on(vfs::lstat("/link")).unwrap().kind,
+        FileType::Symlink
+    );
+    block_on(vfs::set_current_dir("/a/b")).unwrap();
+    assert_eq!(vfs::current_dir(), "/a/b");
+    assert!(block_on(vfs::stat("c/../../b/./c")).is_ok());
+    block_on(vfs::set_current_dir("/")).unwrap();
+    assert_eq!(block_on(vfs::stat("/a/missing")), Err(FsError::NotFound));
+
+    // A link to itself
+    block_on(vfs::symlink("/loop", "/loop")).unwrap();
+    assert_eq!(block_on(vfs::stat("/loop")), Err(FsError::TooManyLinks));
+}
+
+#[test_case]
+fn directories_are_listed_and_removed() {
+    block_on(vfs::create_dir("/list", 0o755)).unwrap();
+    for name in &["/list/one", "/list/two"] {
+        let fd = block_on(vfs::open(name, CREATE)).unwrap();
+        vfs::close(fd).unwrap();
+    }
+    let entries = block_on(vfs::read_dir("/list")).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, "one");
+    assert_eq!(
+        block_on(vfs::remove("/list")),
+        Err(FsError::DirectoryNotEmpty)
+    );
+    block_on(vfs::remove("/list/one")).unwrap();
+    block_on(vfs::remove("/list/two")).unwrap();
+    block_on(vfs::remove("/list")).unwrap();
+    assert_eq!(block_on(vfs::stat("/list")), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn files_are_renamed() {
+    block_on(vfs::create_dir("/from", 0o755)).unwrap();
+    block_on(vfs::create_dir("/to", 0o755)).unwrap();
+    let fd = block_on(vfs::open("/from/file", CREATE)).unwrap();
+    block_on(vfs::write(fd, b"data")).unwrap();
+    vfs::close(fd).unwrap();
+    block_on(vfs::rename("/from/file", "/to/moved")).unwrap();
+    assert_eq!(block_on(vfs::stat("/from/file")), Err(FsError::NotFound));
+    assert_eq!(block_on(vfs::stat("/to/moved")).unwrap().size, 4);
+    // Not inside itself
+    assert_eq!(
+        block_on(vfs::rename("/from", "/from/inner")),
+        Err(FsError::InvalidPath)
+    );
+}
+
+#[test_case]
+fn files_grow_sparse_and_shrink() {
+    let fd = block_on(vfs::open("/sparse", CREATE)).unwrap();
+    block_on(vfs::seek(fd, SeekFrom::Start(100_000))).unwrap();
+    block_on(vfs::write(fd, &[7])).unwrap();
+    assert_eq!(block_on(vfs::fstat(fd)).unwrap().size, 100_001);
+    block_on(vfs::seek(fd, SeekFrom::Start(150_000))).unwrap();
+    assert_eq!(block_on(vfs::write(fd, &[])), Ok(0));
+    assert_eq!(block_on(vfs::fstat(fd)).unwrap().size, 100_001);
+    block_on(vfs::seek(fd, SeekFrom::Start(50_000))).unwrap();
+    let mut buffer = vec![1; 10];
+    block_on(vfs::read(fd, &mut buffer)).unwrap();
+    assert!(buffer.iter().all(|&byte| byte == 0));
+
+    block_on(vfs::truncate("/sparse", 10)).unwrap();
+    block_on(vfs::truncate("/sparse", 200_000)).unwrap();
+    block_on(vfs::seek(fd, SeekFrom::Start(100_000))).unwrap();
+    block_on(vfs::read(fd, &mut buffer[..1])).unwrap();
+    assert_eq!(buffer[0], 0);
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn open_flags_are_checked() {
+    let fd = block_on(vfs::open("/flags", CREATE)).unwrap();
+    block_on(vfs::write(fd, b"abc")).unwrap();
+    vfs::close(fd).unwrap();
+    assert_eq!(
+        block_on(vfs::open("/flags", CREATE | OpenFlags::EXCLUSIVE)),
+        Err(FsError::AlreadyExists)
+    );
+    assert_eq!(
+        block_on(vfs::open("/", OpenFlags::WRITE)),
+        Err(FsError::IsADirectory)
+    );
+    let fd = block_on(vfs::open("/flags", OpenFlags::WRITE | OpenFlags::APPEND)).unwrap();
+    block_on(vfs::write(fd, b"def")).unwrap();
+    assert_eq!(
+        block_on(vfs::read(fd, &mut [0; 3])),
+        Err(FsError::BadDescriptor)
+    );
+    vfs::close(fd).unwrap();
+    let fd = block_on(vfs::open("/flags", OpenFlags::WRITE | OpenFlags::TRUNCATE)).unwrap();
+    assert_eq!(block_on(vfs::fstat(fd)).unwrap().size, 0);
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn initrd_is_unpacked_with_its_permissions() {
+    assert!(block_on(initrd::unpack(initrd::ARCHIVE, "/initrd")).unwrap() > 0);
+    let root = block_on(vfs::stat("/initrd/root")).unwrap();
+    assert_eq!(root.kind, FileType::Directory);
+    assert_eq!(root.mode, 0o700);
+    let hostname = block_on(vfs::stat("/initrd/etc/hostname")).unwrap();
+    assert_eq!(hostname.mode, 0o644);
+    assert_eq!(hostname.size, 8);
+    assert_eq!(
+        block_on(vfs::read_link("/initrd/etc/issue")).unwrap(),
+        "motd"
+    );
+    let fd = block_on(vfs::open("/initrd/etc/issue", OpenFlags::READ)).unwrap();
+    let mut buffer = [0; 7];
+    block_on(vfs::read(fd, &mut buffer)).unwrap();
+    assert_eq!(&buffer, b"Welcome");
+    vfs::close(fd).unwrap();
+}
+
+fn put(header: &mut [u8], start: usize, text: &str) {
+    header[start..start + text.len()].copy_from_slice(text.as_bytes());
+}
+
+///
+/// A ustar entry: its header and `data`, padded to whole blocks
+///
+fn tar_entry(name: &str, kind: u8, link: &str, data: &[u8]) -> Vec<u8> {
+    let mut header = vec![0; 512];
+    put(&mut header, 0, name);
+    put(&mut header, 100, "0000644");
+    put(&mut header, 108, "0000000");
+    put(&mut header, 116, "0000000");
+    put(&mut header, 124, &format!("{:011o}", data.len()));
+    put(&mut header, 136, "00000000000");
+    header[156] = kind;
+    put(&mut header, 157, link);
+    put(&mut header, 257, "ustar\0");
+    put(&mut header, 263, "00");
+    put(&mut header, 148, "        ");
+    let sum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    put(&mut header, 148, &format!("{:06o}\0 ", sum));
+    header.extend_from_slice(data);
+    header.resize((header.len() + 511) / 512 * 512, 0);
+    header
+}
+
+#[test_case]
+fn initrd_links_cant_take_entries_out() {
+    let mut archive = tar_entry("x", b'2', "/", &[]);
+    archive.extend(tar_entry("x/escaped", b'0', "", b"bad"));
+    archive.extend(tar_entry("./x/./other", b'0', "", b"bad"));
+    archive.extend(tar_entry("x", b'0', "", b"bad"));
+    archive.resize(archive.len() + 1024, 0);
+    assert_eq!(block_on(initrd::unpack(&archive, "/unpacked")), Ok(1));
+    assert_eq!(
+        block_on(vfs::lstat("/unpacked/x")).unwrap().kind,
+        FileType::Symlink
+    );
+    assert_eq!(block_on(vfs::stat("/escaped")), Err(FsError::NotFound));
+    assert_eq!(block_on(vfs::stat("/other")), Err(FsError::NotFound));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,242 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::driver::virtio::queue::{Buffer, Virtqueue};
+use rust_os::memory;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+use bootloader::{entry_point, BootInfo};
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[CRASH] Heap allocator failed");
+    test_main();
+    loop {}
+}
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+fn physical<T>(address: PhysAddr) -> *mut T {
+    let offset = memory::physical_memory_offset().unwrap();
+    (offset + address.as_u64()).as_mut_ptr()
+}
+
+///
+/// Does what a device does with the rings, through physical memory. Copies what the
+/// readable buffers of a chain hold to its writable ones
+///
+struct FakeDevice {
+    descriptors: *mut Descriptor,
+    available: *mut u16,
+    used: *mut u16,
+    size: u16,
+    seen: u16,
+}
+
+impl FakeDevice {
+    fn new(queue: &Virtqueue) -> Self {
+        FakeDevice {
+            descriptors: physical(queue.descriptors_address()),
+            available: physical(queue.available_address()),
+            used: physical(queue.used_address()),
+            size: queue.size(),
+            seen: 0,
+        }
+    }
+
+    ///
+    /// Handles every chain in the available ring, returns their descriptors
+    ///
+    fn handle(&mut self) -> Vec<Vec<Descriptor>> {
+        let mut chains = Vec::new();
+        let available = unsafe { self.available.add(1).read_volatile() };
+        while self.seen != available {
+            let position = 2 + (self.seen % self.size) as usize;
+            let head = unsafe { self.available.add(position).read_volatile() };
+            let mut chain = Vec::new();
+            let mut id = head;
+            loop {
+                let descriptor = unsafe { self.descriptors.add(id as usize).read_volatile() };
+                chain.push(descriptor);
+                if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                    break;
+                }
+                id = descriptor.next;
+            }
+            let mut input = Vec::new();
+            for descriptor in chain.iter().filter(|d| d.flags & DESCRIPTOR_WRITE == 0) {
+                let start: *const u8 = physical(PhysAddr::new(descriptor.address));
+                for i in 0..descriptor.len as usize {
+                    input.push(unsafe { start.add(i).read_volatile() });
+                }
+            }
+            let mut written = 0;
+            for descriptor in chain.iter().filter(|d| d.flags & DESCRIPTOR_WRITE != 0) {
+                let start: *mut u8 = physical(PhysAddr::new(descriptor.address));
+                let len = (descriptor.len as usize).min(input.len() - written);
+                for i in 0..len {
+                    unsafe { start.add(i).write_volatile(input[written + i]) };
+                }
+                written += len;
+            }
+            unsafe {
+                let index = self.used.add(1).read_volatile();
+                let element = UsedElement {
+                    id: head as u32,
+                    len: written as u32,
+                };
+                (self.used.add(2) as *mut UsedElement)
+                    .add((index % self.size) as usize)
+                    .write_volatile(element);
+                self.used.add(1).write_volatile(index.wrapping_add(1));
+            }
+            self.seen = self.seen.wrapping_add(1);
+            chains.push(chain);
+        }
+        chains
+    }
+}
+
+///
+/// The buffers of a chain that sends `input` and gets as much back in `output`
+///
+fn echo(input: &[u8], output: &mut [u8]) -> Vec<Buffer> {
+    let mut buffers = Vec::new();
+    Buffer::add_range(
+        &mut buffers,
+        VirtAddr::from_ptr(input.as_ptr()),
+        input.len(),
+        false,
+    )
+    .unwrap();
+    Buffer::add_range(
+        &mut buffers,
+        VirtAddr::from_ptr(output.as_ptr()),
+        output.len(),
+        true,
+    )
+    .unwrap();
+    buffers
+}
+
+#[test_case]
+fn chains_are_handed_back() {
+    let mut queue = Virtqueue::new(0, 8, 0).unwrap();
+    let mut device = FakeDevice::new(&queue);
+    let input = vec![1, 2, 3, 4, 5];
+    let mut output = vec![0; 5];
+    let buffers = echo(&input, &mut output);
+    let head = queue.push(&buffers, Box::new((input, output))).unwrap();
+    assert!(queue.take(head).is_none());
+
+    let chains = device.handle();
+    assert_eq!(chains.len(), 1);
+    let chain = &chains[0];
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[0].len, 5);
+    assert_eq!(chain[0].flags, DESCRIPTOR_NEXT);
+    assert_eq!(chain[1].flags, DESCRIPTOR_WRITE);
+
+    queue.collect();
+    let (written, owned) = queue.take(head).unwrap();
+    assert_eq!(written, 5);
+    let (_, output) = *owned.downcast::<(Vec<u8>, Vec<u8>)>().unwrap();
+    assert_eq!(output, [1, 2, 3, 4, 5]);
+    // Only once
+    assert!(queue.take(head).is_none());
+}
+
+#[test_case]
+fn ring_indexes_wrap_around() {
+    // Two chains of two fill it
+    let mut queue = Virtqueue::new(0, 4, 0).unwrap();
+    let mut device = FakeDevice::new(&queue);
+    // The indexes are `u16`, they wrap after 65536 chains
+    for round in 0..40_000u32 {
+        let mut heads = [0; 2];
+        for (i, head) in heads.iter_mut().enumerate() {
+            let input = vec![round as u8, i as u8];
+            let mut output = vec![0; 2];
+            let buffers = echo(&input, &mut output);
+            *head = queue.push(&buffers, Box::new((input, output))).unwrap();
+        }
+        // Full until the device hands them back
+        let buffers = echo(&[0], &mut [0]);
+        assert!(queue.push(&buffers, Box::new(())).is_err());
+
+        assert_eq!(device.handle().len(), 2);
+        queue.collect();
+        for (i, &head) in heads.iter().enumerate() {
+            let (written, owned) = queue.take(head).unwrap();
+            assert_eq!(written, 2);
+            let (_, output) = *owned.downcast::<(Vec<u8>, Vec<u8>)>().unwrap();
+            assert_eq!(output, [round as u8, i as u8]);
+        }
+    }
+}
+
+#[test_case]
+fn abandoned_chains_are_freed_later() {
+    let mut queue = Virtqueue::new(0, 2, 0).unwrap();
+    let mut device = FakeDevice::new(&queue);
+    let alive = Arc::new(());
+    let input = vec![7; 4];
+    let mut output = vec![0; 4];
+    let buffers = echo(&input, &mut output);
+    let head = queue
+        .push(&buffers, Box::new((input, output, alive.clone())))
+        .unwrap();
+    queue.abandon(head);
+    // The device still has the buffers
+    assert_eq!(Arc::strong_count(&alive), 2);
+
+    device.handle();
+    // Not from the interrupt handler
+    queue.collect();
+    assert_eq!(Arc::strong_count(&alive), 2);
+    assert!(queue.take(head).is_none());
+
+    // The next chain needs its descriptors
+    let input = vec![1; 4];
+    let mut output = vec![0; 4];
+    let buffers = echo(&input, &mut output);
+    assert!(queue.push(&buffers, Box::new((input, output))).is_ok());
+    assert_eq!(Arc::strong_count(&alive), 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}